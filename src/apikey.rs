@@ -1,8 +1,6 @@
-use rocket::{
-    http::Status,
-    request::{self, FromRequest, Outcome},
-};
-use rocket_okapi::okapi;
+use std::path::Path;
+
+use rocket::request::{self, FromRequest, Outcome};
 use rocket_okapi::okapi::openapi3::{
     Object, Responses, SecurityRequirement, SecurityScheme, SecuritySchemeData,
 };
@@ -11,34 +9,123 @@ use rocket_okapi::{
     request::{OpenApiFromRequest, RequestHeaderInput},
 };
 
-pub struct ApiKey(String);
+use crate::error::ErrorCode;
+
+/// An api key and the name it shows up with in the logs.
+#[derive(Debug, PartialEq)]
+pub struct NamedKey {
+    pub name: String,
+    pub key: String,
+}
+
+impl NamedKey {
+    /// A line of the api key file, which is the key as a whole, even if it
+    /// contains a `:`.
+    pub fn unnamed(line: &str) -> Self {
+        let key = line.trim();
+        // Part of the hash, so the logs tell keys apart without revealing
+        // them.
+        let hash = crate::reconcile::sha256(key.as_bytes());
+        NamedKey {
+            name: format!("key-{}", &hash[..8]),
+            key: key.to_owned(),
+        }
+    }
+
+    /// A line of the named api key file, `<name>:<key>`. The name ends at
+    /// the first `:`, so the key may contain more of them.
+    pub fn named(line: &str) -> Result<Self, String> {
+        match line.split_once(':') {
+            Some((name, key))
+                if !name.trim().is_empty() && !key.trim().is_empty() =>
+            {
+                Ok(NamedKey {
+                    name: name.trim().to_owned(),
+                    key: key.trim().to_owned(),
+                })
+            }
+            _ => Err(format!(
+                "\"{}\" is not written as <name>:<key>",
+                line.trim()
+            )),
+        }
+    }
+}
+
+/// Reads the keys of the api key file, if it exists, and of the named api
+/// key file, if one is given.
+pub fn load(
+    keys: &Path,
+    named_keys: Option<&Path>,
+) -> Result<Vec<NamedKey>, String> {
+    fn lines(text: &str) -> impl Iterator<Item = &str> {
+        text.lines().filter(|line| !line.trim().is_empty())
+    }
+
+    let text = std::fs::read_to_string(keys).unwrap_or_default();
+    let mut loaded: Vec<_> = lines(&text).map(NamedKey::unnamed).collect();
+    if let Some(path) = named_keys {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let key = NamedKey::named(line).map_err(|e| {
+                format!("{} line {}: {}", path.display(), i + 1, e)
+            })?;
+            loaded.push(key);
+        }
+    }
+    Ok(loaded)
+}
+
+/// Why the api key of a request was rejected, for the error catchers.
+pub struct AuthFailure(pub Option<ErrorCode>);
+
+/// The name of the api key a request was sent with.
+pub struct ApiKey(pub String);
+
+fn fail(
+    request: &request::Request<'_>,
+    code: ErrorCode,
+) -> request::Outcome<ApiKey, ErrorCode> {
+    request.local_cache(|| AuthFailure(Some(code)));
+    Outcome::Failure((code.status(), code))
+}
 
 // Implement the actual checks for the authentication
 #[rocket::async_trait]
 impl<'a> FromRequest<'a> for ApiKey {
-    type Error = &'static str;
+    type Error = ErrorCode;
     async fn from_request(
         request: &'a request::Request<'_>,
     ) -> request::Outcome<Self, Self::Error> {
         if crate::CONFIG.dev {
-            Outcome::Success(ApiKey(format!("")))
+            crate::logging::record_api_key("dev");
+            Outcome::Success(ApiKey("dev".to_owned()))
         } else {
             // Get the key from the http header
             match request.headers().get_one("x-api-key") {
                 Some(key) => {
-                    if crate::CONFIG.apikeys.iter().any(|k| k == key) {
-                        Outcome::Success(ApiKey(key.to_owned()))
+                    let found =
+                        crate::CONFIG.apikeys.iter().find(|k| k.key == key);
+                    if let Some(found) = found {
+                        crate::logging::record_api_key(&found.name);
+                        Outcome::Success(ApiKey(found.name.clone()))
                     } else {
-                        Outcome::Failure((
-                            Status::Unauthorized,
-                            "Api key is invalid.",
-                        ))
+                        crate::metrics::AUTH_FAILURES
+                            .with_label_values(&["invalid_key"])
+                            .inc();
+                        fail(request, ErrorCode::InvalidApiKey)
                     }
                 }
-                None => Outcome::Failure((
-                    Status::BadRequest,
-                    "Missing `x-api-key` header.",
-                )),
+                None => {
+                    crate::metrics::AUTH_FAILURES
+                        .with_label_values(&["missing_header"])
+                        .inc();
+                    fail(request, ErrorCode::MissingApiKey)
+                }
             }
         }
     }
@@ -72,68 +159,63 @@ impl<'a> OpenApiFromRequest<'a> for ApiKey {
         ))
     }
 
-    // Optionally add responses
-    // Also see `main.rs` part of this.
     fn get_responses(
-        gen: &mut OpenApiGenerator,
+        _gen: &mut OpenApiGenerator,
     ) -> rocket_okapi::Result<Responses> {
-        use rocket_okapi::okapi::openapi3::RefOr;
-        // Can switch between to the but both are checked if they compile correctly
-        let use_method = "recommended";
-        // It can return a "400 BadRequest" and a "401 Unauthorized"
-        // In both cases we just return a what we have set in the catches (if any).
-        // In our cases this is: `crate::MyError`
-        // This depends on you catcher return type.
-
-        // Below are 3 examples, all are similar, first 2 are recommended.
-        match use_method {
-            "recommended" => Ok(Responses {
-                // Recommended and most strait forward.
-                // And easy to add or remove new responses.
-                responses: okapi::map! {
-                    "400".to_owned() => RefOr::Object(crate::common::bad_request_response(gen)),
-                    "401".to_owned() => RefOr::Object(crate::common::unauthorized_response(gen)),
-                },
-                ..Default::default()
-            }),
-            "1st alternative" => {
-                // This is same as macro above does, so just depends on what you like more.
-                let mut responses = Responses::default();
-                responses.responses.insert(
-                    "400".to_owned(),
-                    RefOr::Object(crate::common::bad_request_response(gen)),
-                );
-                responses.responses.insert(
-                    "401".to_owned(),
-                    RefOr::Object(crate::common::unauthorized_response(gen)),
-                );
-                Ok(responses)
-            }
-            "2nd alternative" => {
-                // This not advised because of issue #57.
-                // But this does work.
-                // https://github.com/GREsau/okapi/issues/57
-                // Note: this one does not add the `description` field to the responses.
-                // So it is slightly different in output.
-                let mut responses = Responses::default();
-                let schema = gen.json_schema::<crate::common::MyError>();
-                // Add "400 BadRequest"
-                rocket_okapi::util::add_schema_response(
-                    &mut responses,
-                    400,
-                    "application/json",
-                    schema.clone(),
-                )?;
-                // Add "401 Unauthorized"
-                rocket_okapi::util::add_schema_response(
-                    &mut responses,
-                    401,
-                    "application/json",
-                    schema,
-                )?;
-                Ok(responses)
+        // Documented by `RouteError`, which every route returns.
+        Ok(Responses::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_colons_of_unnamed_keys() {
+        let key = NamedKey::unnamed(" team:s3cr3t ");
+        assert_eq!(key.key, "team:s3cr3t");
+        assert!(key.name.starts_with("key-"));
+        assert_eq!(key.name.len(), 12);
+    }
+
+    #[test]
+    fn splits_named_keys_at_the_first_colon() {
+        assert_eq!(
+            NamedKey::named("bot: a:b").unwrap(),
+            NamedKey {
+                name: "bot".to_owned(),
+                key: "a:b".to_owned(),
             }
-            _ => Ok(Responses::default()),
-        }
+        );
+        assert!(NamedKey::named("no name").is_err());
+        assert!(NamedKey::named(":key").is_err());
+        assert!(NamedKey::named("bot:").is_err());
+    }
+
+    #[test]
+    fn loads_both_files() {
+        let dir = std::env::temp_dir()
+            .join(format!("mapmaster-apikeys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let keys = dir.join("apikeys");
+        let named = dir.join("named");
+        std::fs::write(&keys, "legacy:key\n\n").unwrap();
+        std::fs::write(&named, "bot:other\n").unwrap();
+
+        let loaded = load(&keys, Some(&named)).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].key, "legacy:key");
+        assert_eq!(loaded[1].name, "bot");
+
+        // A missing key file is no keys, a missing named one is a mistake.
+        assert!(load(&dir.join("none"), None).unwrap().is_empty());
+        assert!(load(&keys, Some(&dir.join("none"))).is_err());
+
+        std::fs::write(&named, "bot:other\nforgot the name\n").unwrap();
+        let e = load(&keys, Some(&named)).unwrap_err();
+        assert!(e.contains("line 2"), "{}", e);
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
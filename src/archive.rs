@@ -0,0 +1,455 @@
+//! Zip and tar.gz archives with several maps, which `create` unpacks.
+//!
+//! Every `.map` entry becomes a map named after the entry. The difficulty of
+//! a map can be set in an optional `mapmaster.json` in the archive:
+//!
+//! ```json
+//! {"maps": {"maps/foo.map": {"difficulty": "hard"}}}
+//! ```
+//!
+//! Maps not listed there get the difficulty of the request. A `foo.map.cfg`
+//! next to `foo.map` becomes the config of the map. Nothing is
+//! unpacked to disk, and entries are only read up to the size limits, as the
+//! sizes an archive claims can't be trusted.
+
+use flate2::read::GzDecoder;
+use rocket::serde::Deserialize;
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+    path::{Component, Path},
+};
+
+use crate::{
+    check_map_name,
+    compat::Compatibility,
+    error::{ApiError, ErrorCode},
+    is_map_file,
+    lint::Finding,
+    map_config, normalize_map_name, parse_difficulty,
+    stats::MapStats,
+    Difficulty, CONFIG,
+};
+
+pub const MANIFEST: &str = "mapmaster.json";
+
+/// The most entries an archive may have.
+const MAX_ENTRIES: usize = 256;
+
+const MIB: u64 = 1024 * 1024;
+
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct Manifest {
+    #[serde(default)]
+    maps: HashMap<String, ManifestEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ManifestEntry {
+    difficulty: Option<String>,
+}
+
+/// A map found in an archive.
+pub struct ArchiveMap {
+    pub name: String,
+    pub difficulty: Difficulty,
+    pub data: Vec<u8>,
+    pub config: Option<String>,
+    /// The findings of the lint, filled in before the map is stored.
+    pub lint: Vec<Finding>,
+    /// Filled in with the lint, if the map file can be read.
+    pub stats: Option<MapStats>,
+    /// Filled in like the statistics.
+    pub compatibility: Option<Compatibility>,
+}
+
+/// The largest an entry and all entries together may get, in MiB.
+struct Limits {
+    map: u64,
+    archive: u64,
+}
+
+/// A file read from an archive, with its path below the archive root.
+struct Entry {
+    path: String,
+    data: Vec<u8>,
+}
+
+fn invalid(msg: impl Into<String>) -> ApiError {
+    ApiError::new(ErrorCode::InvalidArchive, msg)
+}
+
+/// Joins the parts of a path that stays inside the archive, or `None` for
+/// absolute paths and ones that leave it.
+fn safe_path(path: &Path) -> Option<String> {
+    let parts = path
+        .components()
+        .map(|c| match c {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(parts.join("/")).filter(|p| !p.is_empty())
+}
+
+/// Whether an entry is read at all. Hidden files are skipped, which also
+/// skips the `._` files macOS adds to archives.
+fn is_wanted(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    !name.starts_with('.')
+        && !path.starts_with("__MACOSX/")
+        && (name.to_lowercase().ends_with(".map")
+            || name.to_lowercase().ends_with(".map.cfg")
+            || path == MANIFEST)
+}
+
+/// Reads an entry, failing once it gets larger than the limits.
+fn read_entry(
+    reader: impl Read,
+    path: &str,
+    total: &mut u64,
+    limits: &Limits,
+) -> Result<Vec<u8>, ApiError> {
+    let max_entry = limits.map * MIB;
+    let mut data = Vec::new();
+    reader
+        .take(max_entry + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| invalid(format!("\"{}\" can't be read: {}", path, e)))?;
+    if data.len() as u64 > max_entry {
+        return Err(ApiError::new(
+            ErrorCode::MapTooLarge,
+            format!("\"{}\" is larger than {} MiB.", path, limits.map),
+        ));
+    }
+    *total += data.len() as u64;
+    if *total > limits.archive * MIB {
+        return Err(ApiError::new(
+            ErrorCode::MapTooLarge,
+            format!("The archive unpacks to more than {} MiB.", limits.archive),
+        ));
+    }
+    Ok(data)
+}
+
+fn zip_entries(file: &[u8], limits: &Limits) -> Result<Vec<Entry>, ApiError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(file))
+        .map_err(|e| invalid(format!("The zip archive is broken: {}", e)))?;
+    if archive.len() > MAX_ENTRIES {
+        return Err(invalid(format!(
+            "The archive has more than {} entries.",
+            MAX_ENTRIES
+        )));
+    }
+
+    let mut entries = Vec::new();
+    let mut total = 0;
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i).map_err(|e| {
+            invalid(format!("The zip archive is broken: {}", e))
+        })?;
+        if entry.is_dir() {
+            continue;
+        }
+        let path = safe_path(Path::new(entry.name())).ok_or_else(|| {
+            invalid(format!("\"{}\" is outside of the archive.", entry.name()))
+        })?;
+        if is_wanted(&path) {
+            let data = read_entry(entry, &path, &mut total, limits)?;
+            entries.push(Entry { path, data });
+        }
+    }
+    Ok(entries)
+}
+
+fn tar_gz_entries(
+    file: &[u8],
+    limits: &Limits,
+) -> Result<Vec<Entry>, ApiError> {
+    // The tar stream has to be unpacked to get from one entry to the next,
+    // so all of it counts against the limit, not only the entries read.
+    let limit = limits.archive * MIB;
+    let mut archive = tar::Archive::new(GzDecoder::new(file).take(limit));
+    let broken = |e: std::io::Error| {
+        invalid(format!(
+            "The archive is broken or unpacks to more than {} MiB: {}",
+            limits.archive, e
+        ))
+    };
+
+    let mut entries = Vec::new();
+    let mut total = 0;
+    for (i, entry) in archive.entries().map_err(broken)?.enumerate() {
+        if i >= MAX_ENTRIES {
+            return Err(invalid(format!(
+                "The archive has more than {} entries.",
+                MAX_ENTRIES
+            )));
+        }
+        let entry = entry.map_err(broken)?;
+        let raw_path = entry.path().map_err(broken)?.into_owned();
+        let path = safe_path(&raw_path).ok_or_else(|| {
+            invalid(format!(
+                "\"{}\" is outside of the archive.",
+                raw_path.display()
+            ))
+        })?;
+        // Links and devices are never read, only their names are checked.
+        if entry.header().entry_type().is_file() && is_wanted(&path) {
+            let data = read_entry(entry, &path, &mut total, limits)?;
+            entries.push(Entry { path, data });
+        }
+    }
+    Ok(entries)
+}
+
+/// The maps of an archive, or `None` if the file is no archive.
+pub fn unpack(
+    file: &[u8],
+    difficulty: Difficulty,
+) -> Result<Option<Vec<ArchiveMap>>, ApiError> {
+    let limits = Limits {
+        map: CONFIG.max_map_size,
+        archive: CONFIG.max_archive_size,
+    };
+    let entries =
+        if file.starts_with(b"PK\x03\x04") || file.starts_with(b"PK\x05\x06") {
+            zip_entries(file, &limits)?
+        } else if file.starts_with(&[0x1f, 0x8b]) {
+            tar_gz_entries(file, &limits)?
+        } else {
+            return Ok(None);
+        };
+
+    let mut manifest = match entries.iter().find(|e| e.path == MANIFEST) {
+        Some(entry) => serde_json::from_slice::<Manifest>(&entry.data)
+            .map_err(|e| {
+                invalid(format!("{} is not valid: {}", MANIFEST, e))
+            })?,
+        None => Manifest::default(),
+    };
+
+    let (mut configs, entries): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .filter(|e| e.path != MANIFEST)
+        .partition(|e| e.path.to_lowercase().ends_with(".cfg"));
+
+    let mut maps: Vec<ArchiveMap> = Vec::new();
+    for entry in entries {
+        let file_name = entry.path.rsplit('/').next().unwrap_or(&entry.path);
+        let name = normalize_map_name(file_name);
+        check_map_name(&name, file_name)?;
+        if maps.iter().any(|m| m.name == name) {
+            return Err(invalid(format!(
+                "The archive has more than one map named \"{}\".",
+                name
+            )));
+        }
+        if !is_map_file(&entry.data) {
+            return Err(ApiError::new(
+                ErrorCode::InvalidMapFile,
+                format!("\"{}\" is not a teeworlds map!", entry.path),
+            ));
+        }
+        let difficulty = match manifest
+            .maps
+            .remove(&entry.path)
+            .and_then(|m| m.difficulty)
+        {
+            Some(d) => parse_difficulty(&d)?,
+            None => difficulty,
+        };
+        let config_path = format!("{}.cfg", entry.path);
+        let config = match configs.iter().position(|c| c.path == config_path) {
+            Some(i) => {
+                let config = String::from_utf8(configs.remove(i).data)
+                    .map_err(|_| {
+                        invalid(format!("\"{}\" is not text.", config_path))
+                    })?;
+                map_config::validate(&config).map_err(|e| {
+                    ApiError::new(
+                        ErrorCode::InvalidConfig,
+                        format!("{}: {}", config_path, e),
+                    )
+                })?;
+                Some(config).filter(|c| !c.trim().is_empty())
+            }
+            None => None,
+        };
+        maps.push(ArchiveMap {
+            name,
+            difficulty,
+            data: entry.data,
+            config,
+            lint: Vec::new(),
+            stats: None,
+            compatibility: None,
+        });
+    }
+
+    if let Some(config) = configs.first() {
+        return Err(invalid(format!(
+            "\"{}\" has no map next to it.",
+            config.path
+        )));
+    }
+
+    if let Some(path) = manifest.maps.keys().next() {
+        return Err(invalid(format!(
+            "{} lists \"{}\", which is not in the archive.",
+            MANIFEST, path
+        )));
+    }
+    if maps.is_empty() {
+        return Err(invalid("The archive has no maps."));
+    }
+    Ok(Some(maps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    const LIMITS: Limits = Limits { map: 1, archive: 2 };
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (path, data) in entries {
+            zip.start_file(*path, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// A tar.gz archive of entries with the size their headers claim. The
+    /// paths are written as they are, which `tar` would refuse.
+    fn tar_gz(entries: &[(&str, u64, &[u8])]) -> Vec<u8> {
+        let mut tar =
+            tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        for (path, size, data) in entries {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..path.len()]
+                .copy_from_slice(path.as_bytes());
+            header.set_size(*size);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append(&header, *data).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn error(entries: Result<Vec<Entry>, ApiError>) -> ApiError {
+        match entries {
+            Ok(_) => panic!("the archive was read"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn keeps_paths_inside_the_archive() {
+        assert_eq!(safe_path(Path::new("maps/a.map")).unwrap(), "maps/a.map");
+        for path in ["../x.map", "maps/../../x.map", "/x.map", "./x.map", ""] {
+            assert_eq!(safe_path(Path::new(path)), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn reads_the_wanted_entries() {
+        let entries = vec![
+            ("maps/a.map", &b"DATA"[..]),
+            ("maps/a.map.cfg", b"sv_gravity 1"),
+            ("readme.txt", b"hi"),
+            ("__MACOSX/maps/._a.map", b"junk"),
+        ];
+        let paths = |entries: Vec<Entry>| {
+            entries.into_iter().map(|e| e.path).collect::<Vec<_>>()
+        };
+        let read = paths(zip_entries(&zip(&entries), &LIMITS).unwrap());
+        assert_eq!(read, ["maps/a.map", "maps/a.map.cfg"]);
+
+        let entries = entries
+            .iter()
+            .map(|(p, d)| (*p, d.len() as u64, *d))
+            .collect::<Vec<_>>();
+        let read = paths(tar_gz_entries(&tar_gz(&entries), &LIMITS).unwrap());
+        assert_eq!(read, ["maps/a.map", "maps/a.map.cfg"]);
+    }
+
+    #[test]
+    fn refuses_paths_outside_of_the_archive() {
+        for path in ["../x.map", "/x.map"] {
+            let e = error(zip_entries(&zip(&[(path, b"DATA")]), &LIMITS));
+            assert_eq!(e.code, ErrorCode::InvalidArchive);
+            assert!(e.msg.contains("outside"));
+
+            let tar = tar_gz(&[(path, 4, b"DATA")]);
+            let e = error(tar_gz_entries(&tar, &LIMITS));
+            assert!(e.msg.contains("outside"), "{}", e.msg);
+        }
+    }
+
+    #[test]
+    fn refuses_too_many_entries() {
+        let names = (0..=MAX_ENTRIES)
+            .map(|i| format!("{}.txt", i))
+            .collect::<Vec<_>>();
+        let zip_files = names
+            .iter()
+            .map(|n| (n.as_str(), &b""[..]))
+            .collect::<Vec<_>>();
+        let e = error(zip_entries(&zip(&zip_files), &LIMITS));
+        assert!(e.msg.contains("entries"));
+
+        let tar_files = names
+            .iter()
+            .map(|n| (n.as_str(), 0, &b""[..]))
+            .collect::<Vec<_>>();
+        let e = error(tar_gz_entries(&tar_gz(&tar_files), &LIMITS));
+        assert!(e.msg.contains("entries"));
+    }
+
+    #[test]
+    fn refuses_entries_and_archives_over_the_limits() {
+        let large = vec![b'x'; MIB as usize + 1];
+        let e = error(zip_entries(&zip(&[("a.map", &large)]), &LIMITS));
+        assert_eq!(e.code, ErrorCode::MapTooLarge);
+
+        let half = vec![b'x'; MIB as usize / 2 + 1];
+        let many = vec![
+            ("a.map", &half[..]),
+            ("b.map", &half),
+            ("c.map", &half),
+            ("d.map", &half),
+        ];
+        let e = error(zip_entries(&zip(&many), &LIMITS));
+        assert_eq!(e.code, ErrorCode::MapTooLarge);
+        assert!(e.msg.contains("archive"));
+
+        // Entries that aren't read still count for tar.gz archives.
+        let huge = vec![b'x'; 3 * MIB as usize];
+        let tar = tar_gz(&[("huge.txt", huge.len() as u64, &huge)]);
+        assert!(tar_gz_entries(&tar, &LIMITS).is_err());
+    }
+
+    #[test]
+    fn refuses_entries_larger_than_they_claim() {
+        let large = vec![b'x'; MIB as usize + 1];
+        let mut file = zip(&[("a.map", &large)]);
+        // Claim 4 bytes in the local and the central header.
+        let local = 22;
+        file[local..local + 4].copy_from_slice(&4u32.to_le_bytes());
+        let central =
+            file.windows(4).position(|w| w == b"PK\x01\x02").unwrap() + 24;
+        file[central..central + 4].copy_from_slice(&4u32.to_le_bytes());
+        let e = error(zip_entries(&file, &LIMITS));
+        assert_eq!(e.code, ErrorCode::MapTooLarge, "{}", e.msg);
+
+        let tar = tar_gz(&[("a.map", 4, &large)]);
+        assert!(tar_gz_entries(&tar, &LIMITS).is_err());
+    }
+}
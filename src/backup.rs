@@ -0,0 +1,376 @@
+//! Periodic snapshots of the database and the published maps.
+//!
+//! A backup is a zstd compressed tar archive with the database as exported by
+//! `export`, the published map files below `maps/<difficulty>/` and a
+//! manifest with the hashes of all of them, so a restore can check the
+//! archive before it replaces anything.
+
+use rocket::{
+    fairing::AdHoc,
+    serde::{Deserialize, Serialize},
+};
+use std::{
+    collections::HashSet,
+    error::Error,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use strum::IntoEnumIterator;
+
+use crate::{
+    export, get_current_time, map_file_path, reconcile, repository,
+    repository::MapRepository, CustomState, Difficulty, MapState, CONFIG,
+};
+
+const PREFIX: &str = "mapmaster-backup-";
+const SUFFIX: &str = ".tar.zst";
+const MANIFEST: &str = "manifest.json";
+const DATABASE: &str = "database.json";
+const MAPS: &str = "maps";
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct ManifestFile {
+    path: String,
+    sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct Manifest {
+    created_at: u64,
+    files: Vec<ManifestFile>,
+}
+
+/// The time a backup was created at, taken from its file name.
+fn created_at(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix(PREFIX)?
+        .strip_suffix(SUFFIX)?
+        .parse()
+        .ok()
+}
+
+/// All backups in `dir`, newest first.
+fn list_backups(dir: &Path) -> Result<Vec<(u64, PathBuf)>, std::io::Error> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(time) = created_at(&path) {
+            backups.push((time, path));
+        }
+    }
+    backups.sort_by(|a, b| b.cmp(a));
+    Ok(backups)
+}
+
+fn append(
+    archive: &mut tar::Builder<impl std::io::Write>,
+    path: &str,
+    data: &[u8],
+    mtime: u64,
+) -> Result<(), std::io::Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    archive.append_data(&mut header, path, data)
+}
+
+/// Adds a file to the archive and its hash to the manifest.
+fn append_hashed(
+    archive: &mut tar::Builder<impl std::io::Write>,
+    manifest: &mut Manifest,
+    path: String,
+    data: &[u8],
+) -> Result<(), std::io::Error> {
+    append(archive, &path, data, manifest.created_at)?;
+    manifest.files.push(ManifestFile {
+        path,
+        sha256: reconcile::sha256(data),
+    });
+    Ok(())
+}
+
+/// Writes a new backup to `dir` and returns its path.
+pub fn create_backup(
+    db: &dyn MapRepository,
+    dir: &Path,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let now = get_current_time().unwrap_or_default();
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}{}{}", PREFIX, now, SUFFIX));
+    // Only complete archives get the real name, so an interrupted backup is
+    // never mistaken for one that can be restored.
+    let partial = path.with_extension("zst.partial");
+
+    let export = export::export(db)?;
+    let mut manifest = Manifest {
+        created_at: now,
+        files: Vec::new(),
+    };
+
+    let file = std::fs::File::create(&partial)?;
+    let mut archive =
+        tar::Builder::new(zstd::Encoder::new(file, 0)?.auto_finish());
+    append_hashed(
+        &mut archive,
+        &mut manifest,
+        DATABASE.to_string(),
+        &serde_json::to_vec_pretty(&export)?,
+    )?;
+    for map in export
+        .maps
+        .iter()
+        .filter(|m| m.state == MapState::Published)
+    {
+        // The map may have been changed since the export, in which case the
+        // export doesn't know about its new file either.
+        let data = match std::fs::read(map_file_path(map)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let path = Path::new(MAPS)
+            .join(map.difficulty)
+            .join(format!("{}.map", map.name));
+        append_hashed(
+            &mut archive,
+            &mut manifest,
+            path.to_string_lossy().into_owned(),
+            &data,
+        )?;
+    }
+    let json = serde_json::to_vec_pretty(&manifest)?;
+    append(&mut archive, MANIFEST, &json, now)?;
+    archive.into_inner()?;
+
+    std::fs::rename(&partial, &path)?;
+    Ok(path)
+}
+
+/// Deletes all backups except the newest of each of the last `keep_daily`
+/// days and `keep_weekly` weeks that have one. The newest backup is always
+/// kept, even if both are 0. Returns the deleted backups.
+pub fn prune(
+    dir: &Path,
+    keep_daily: usize,
+    keep_weekly: usize,
+) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut days = HashSet::new();
+    let mut weeks = HashSet::new();
+    let mut deleted = Vec::new();
+    for (i, (time, path)) in list_backups(dir)?.into_iter().enumerate() {
+        let day = time / DAY;
+        // The epoch was a thursday, this makes weeks start on monday.
+        let week = (day + 3) / 7;
+        let mut keep = i == 0;
+        if days.len() < keep_daily && days.insert(day) {
+            keep = true;
+        }
+        if weeks.len() < keep_weekly && weeks.insert(week) {
+            keep = true;
+        }
+        if !keep {
+            std::fs::remove_file(&path)?;
+            deleted.push(path);
+        }
+    }
+    Ok(deleted)
+}
+
+/// Creates a backup and prunes the old ones according to the configuration.
+pub fn run(
+    db: &dyn MapRepository,
+    dir: &Path,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let path = create_backup(db, dir)?;
+    prune(dir, CONFIG.keep_daily_backups, CONFIG.keep_weekly_backups)?;
+    Ok(path)
+}
+
+/// Starts taking backups once the server is running, if a backup directory
+/// is configured. The first backup is taken when the newest existing one is
+/// older than the backup interval.
+pub fn scheduler() -> AdHoc {
+    AdHoc::on_liftoff("Backups", |rocket| {
+        Box::pin(async move {
+            let dir = match &CONFIG.backup_dir {
+                Some(dir) => dir.clone(),
+                None => return,
+            };
+            let db = match rocket.state::<CustomState>() {
+                Some(state) => state.db.clone(),
+                None => return,
+            };
+            let interval = CONFIG.backup_interval_hours.max(1) * 60 * 60;
+
+            rocket::tokio::spawn(async move {
+                loop {
+                    let now = get_current_time().unwrap_or_default();
+                    let last = list_backups(&dir)
+                        .ok()
+                        .and_then(|backups| backups.first().map(|b| b.0))
+                        .unwrap_or_default();
+                    let wait = (last + interval).saturating_sub(now);
+                    rocket::tokio::time::sleep(Duration::from_secs(wait)).await;
+
+                    let (db, dir) = (db.clone(), dir.clone());
+                    let result =
+                        rocket::tokio::task::spawn_blocking(move || {
+                            run(db.as_ref(), &dir).map_err(|e| e.to_string())
+                        })
+                        .await
+                        .unwrap_or_else(|e| Err(e.to_string()));
+                    match result {
+                        Ok(path) => {
+                            tracing::info!(path = %path.display(), "Wrote backup")
+                        }
+                        Err(e) => {
+                            tracing::error!(error = %e, "Backup failed");
+                            // Try again in one interval instead of right away.
+                            rocket::tokio::time::sleep(Duration::from_secs(
+                                interval,
+                            ))
+                            .await;
+                        }
+                    }
+                }
+            });
+        })
+    })
+}
+
+/// Unpacks an archive into `dir` and checks every file against the manifest.
+fn unpack(archive: &Path, dir: &Path) -> Result<Manifest, Box<dyn Error>> {
+    let file = std::fs::File::open(archive)?;
+    tar::Archive::new(zstd::Decoder::new(file)?).unpack(dir)?;
+
+    let manifest: Manifest =
+        serde_json::from_slice(&std::fs::read(dir.join(MANIFEST))?)?;
+    for file in &manifest.files {
+        let data = std::fs::read(dir.join(&file.path))
+            .map_err(|e| format!("{}: {}", file.path, e))?;
+        if reconcile::sha256(&data) != file.sha256 {
+            return Err(format!("{} doesn't match its hash!", file.path).into());
+        }
+    }
+    if !manifest.files.iter().any(|f| f.path == DATABASE) {
+        return Err("The backup doesn't contain a database!".into());
+    }
+    Ok(manifest)
+}
+
+/// Replaces the database and the published maps with the contents of a
+/// backup. The current database and map folders are kept next to the new
+/// ones. Returns the number of restored maps.
+pub fn restore(archive: &Path) -> Result<usize, Box<dyn Error>> {
+    let now = get_current_time().unwrap_or_default();
+    let public = &CONFIG.public_map_folder;
+    let staging = public.join(format!(".restore-{}", now));
+
+    let result = unpack(archive, &staging).and_then(|_| {
+        let export: export::Export =
+            serde_json::from_slice(&std::fs::read(staging.join(DATABASE))?)?;
+        export::validate(&export)?;
+        Ok(export)
+    });
+    let export = match result {
+        Ok(export) => export,
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+    };
+
+    // The server holds the lock while it runs, whatever the backend.
+    let database = &CONFIG.database_path;
+    let _lock = repository::lock(database).map_err(|e| {
+        format!("Could not lock the database, is mapmaster running? {}", e)
+    })?;
+
+    let old_database =
+        PathBuf::from(format!("{}.bak-{}", database.display(), now));
+    if database.exists() {
+        std::fs::rename(database, &old_database)?;
+    }
+    let db = crate::open_database();
+    if let Err(e) = export::restore(db.as_ref(), &export) {
+        drop(db);
+        std::fs::remove_file(database)?;
+        if old_database.exists() {
+            std::fs::rename(&old_database, database)?;
+        }
+        let _ = std::fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+
+    let old_maps = public.join(format!(".bak-{}", now));
+    for difficulty in Difficulty::iter() {
+        let current = public.join(difficulty);
+        if current.exists() {
+            std::fs::create_dir_all(&old_maps)?;
+            std::fs::rename(current, old_maps.join(difficulty))?;
+        }
+        let restored = staging.join(MAPS).join(difficulty);
+        if restored.exists() {
+            std::fs::rename(restored, public.join(difficulty))?;
+        }
+    }
+    std::fs::remove_dir_all(&staging)?;
+
+    crate::update_votes(db.as_ref())?;
+
+    println!(
+        "The previous database was moved to {} and the previous maps to {}.",
+        old_database.display(),
+        old_maps.display()
+    );
+    Ok(export.maps.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prunes_all_but_the_newest_backups() {
+        let dir = std::env::temp_dir()
+            .join(format!("mapmaster-backups-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Two backups on each of three days, a week apart.
+        let times = [0, 1, 7 * DAY, 7 * DAY + 1, 14 * DAY, 14 * DAY + 1];
+        let write = || {
+            for time in times {
+                let name = format!("{}{}{}", PREFIX, time, SUFFIX);
+                std::fs::write(dir.join(name), b"").unwrap();
+            }
+        };
+        let kept = || {
+            let backups = list_backups(&dir).unwrap();
+            backups.into_iter().map(|b| b.0).collect::<Vec<_>>()
+        };
+
+        write();
+        prune(&dir, 2, 0).unwrap();
+        assert_eq!(kept(), [14 * DAY + 1, 7 * DAY + 1]);
+
+        write();
+        prune(&dir, 1, 3).unwrap();
+        assert_eq!(kept(), [14 * DAY + 1, 7 * DAY + 1, 1]);
+
+        // Even without any days or weeks, the newest backup stays.
+        write();
+        assert_eq!(prune(&dir, 0, 0).unwrap().len(), 5);
+        assert_eq!(kept(), [14 * DAY + 1]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
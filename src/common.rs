@@ -1,117 +0,0 @@
-use rocket::Request;
-use rocket::{catch, response, response::Responder, Response};
-use rocket_okapi::gen::OpenApiGenerator;
-use rocket_okapi::okapi;
-use rocket_okapi::okapi::openapi3::{MediaType, Responses};
-use rocket_okapi::okapi::schemars;
-use rocket_okapi::response::OpenApiResponderInner;
-use rocket_okapi::OpenApiError;
-
-// ----- Catchers -------
-
-/// Error messages returned to user
-#[derive(Debug, serde::Serialize, schemars::JsonSchema)]
-pub struct MyError {
-    /// The title of the error message
-    pub err: String,
-    /// The description of the error
-    pub msg: Option<String>,
-    // HTTP Status Code returned
-    #[serde(skip)]
-    pub http_status_code: u16,
-}
-
-#[catch(400)]
-pub fn bad_request() -> MyError {
-    MyError {
-        err: "Bad Request".to_owned(),
-        msg: Some(
-            "The request given is wrongly formatted or data was missing."
-                .to_owned(),
-        ),
-        http_status_code: 400,
-    }
-}
-
-#[catch(401)]
-pub fn unauthorized() -> MyError {
-    MyError {
-        err: "Unauthorized".to_owned(),
-        msg: Some(
-            "The authentication given was incorrect or insufficient."
-                .to_owned(),
-        ),
-        http_status_code: 401,
-    }
-}
-
-/// Create my custom response
-///
-/// Putting this in a separate function somewhere will resolve issues like
-/// <https://github.com/GREsau/okapi/issues/57>
-pub fn bad_request_response(
-    gen: &mut OpenApiGenerator,
-) -> okapi::openapi3::Response {
-    let schema = gen.json_schema::<MyError>();
-    okapi::openapi3::Response {
-        description: "\
-        # 400 Bad Request\n\
-        The request given is wrongly formatted or data was missing. \
-        "
-        .to_owned(),
-        content: okapi::map! {
-            "application/json".to_owned() => MediaType {
-                schema: Some(schema),
-                ..Default::default()
-            }
-        },
-        ..Default::default()
-    }
-}
-
-pub fn unauthorized_response(
-    gen: &mut OpenApiGenerator,
-) -> okapi::openapi3::Response {
-    let schema = gen.json_schema::<MyError>();
-    okapi::openapi3::Response {
-        description: "\
-        # 401 Unauthorized\n\
-        The authentication given was incorrect or insufficient. \
-        "
-        .to_owned(),
-        content: okapi::map! {
-            "application/json".to_owned() => MediaType {
-                schema: Some(schema),
-                ..Default::default()
-            }
-        },
-        ..Default::default()
-    }
-}
-
-impl<'r> Responder<'r, 'static> for MyError {
-    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
-        // Convert object to json
-        let body = serde_json::to_string(&self).unwrap();
-        Response::build()
-            .sized_body(body.len(), std::io::Cursor::new(body))
-            .header(rocket::http::ContentType::JSON)
-            .status(rocket::http::Status::new(self.http_status_code))
-            .ok()
-    }
-}
-
-impl OpenApiResponderInner for MyError {
-    fn responses(
-        gen: &mut OpenApiGenerator,
-    ) -> Result<Responses, OpenApiError> {
-        use okapi::openapi3::RefOr;
-        Ok(Responses {
-            responses: okapi::map! {
-                "400".to_owned() => RefOr::Object(bad_request_response(gen)),
-                "401".to_owned() => RefOr::Object(unauthorized_response(gen)),
-            },
-            ..Default::default()
-        })
-    }
-}
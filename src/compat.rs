@@ -0,0 +1,312 @@
+//! Which servers a map runs on, told from the items and layers of its file.
+//!
+//! Teeworlds 0.7 reads 0.6 maps and DDNet reads both, but not the other way
+//! around. The servers of a difficulty are given with
+//! `--compatibility <difficulty>=<servers>`, which limits it to the maps one
+//! of them runs.
+
+use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::str::FromStr;
+use structsy_derive::PersistentEmbedded;
+use strum::{EnumString, IntoStaticStr};
+
+use crate::{
+    datafile::{self, Datafile},
+    error::{ApiError, ErrorCode},
+    map_file_path,
+    twmap::{self, TILES_FRONT, TILES_TELE},
+    Difficulty, Map, CONFIG,
+};
+
+/// DDNet layers with speedups, switches and tunes.
+const TILES_SPEEDUP: i32 = 4;
+const TILES_SWITCH: i32 = 16;
+const TILES_TUNE: i32 = 32;
+const DDNET_TILES: i32 =
+    TILES_TELE | TILES_SPEEDUP | TILES_FRONT | TILES_SWITCH | TILES_TUNE;
+
+/// Sound layers, which only DDNet has. The first one is deprecated.
+const LAYER_SOUNDS: [i32; 2] = [9, 10];
+
+/// The item DDNet registers its own item types with.
+const ITEM_EX: u16 = 0xffff;
+
+/// The versions of items 0.7 writes and 0.6 can't read. Envelopes aren't
+/// looked at, as DDNet writes the same version as 0.7.
+const IMAGE_07_VERSION: i32 = 2;
+const TILEMAP_07_VERSION: i32 = 4;
+
+#[derive(
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    PersistentEmbedded,
+    EnumString,
+    IntoStaticStr,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(crate = "rocket::serde")]
+pub enum Compatibility {
+    #[serde(rename = "0.6")]
+    #[strum(serialize = "0.6")]
+    Teeworlds06,
+    #[serde(rename = "0.7")]
+    #[strum(serialize = "0.7")]
+    Teeworlds07,
+    #[serde(rename = "ddnet")]
+    #[strum(serialize = "ddnet")]
+    Ddnet,
+}
+
+impl Compatibility {
+    /// Whether a server of a class runs maps of this one.
+    pub fn runs_on(self, server: Compatibility) -> bool {
+        match self {
+            Compatibility::Teeworlds06 => true,
+            Compatibility::Teeworlds07 => server != Compatibility::Teeworlds06,
+            Compatibility::Ddnet => server == Compatibility::Ddnet,
+        }
+    }
+}
+
+fn version(item: &datafile::Item) -> i32 {
+    item.data.first().copied().unwrap_or_default()
+}
+
+/// The oldest servers that can run a map.
+pub fn classify(df: &Datafile) -> datafile::Result<Compatibility> {
+    let layers = twmap::tile_layers(df)?;
+    let sound_layers = df.items(twmap::ITEM_LAYER).any(|item| {
+        item.data
+            .get(1)
+            .map(|t| LAYER_SOUNDS.contains(t))
+            .unwrap_or(false)
+    });
+    let settings = df
+        .items(twmap::ITEM_INFO)
+        .any(|item| item.data.get(5).map(|s| *s >= 0).unwrap_or(false));
+    if layers.iter().any(|l| l.flags & DDNET_TILES != 0)
+        || sound_layers
+        || settings
+        || df.items(twmap::ITEM_SOUND).next().is_some()
+        || df.items(ITEM_EX).next().is_some()
+    {
+        return Ok(Compatibility::Ddnet);
+    }
+
+    if layers.iter().any(|l| l.version >= TILEMAP_07_VERSION)
+        || df
+            .items(twmap::ITEM_IMAGE)
+            .any(|i| version(i) >= IMAGE_07_VERSION)
+    {
+        return Ok(Compatibility::Teeworlds07);
+    }
+    Ok(Compatibility::Teeworlds06)
+}
+
+/// Reads the class of a map file, if it can be read.
+pub fn read(file: &[u8]) -> Option<Compatibility> {
+    Datafile::parse(file).and_then(|df| classify(&df)).ok()
+}
+
+/// The servers of a difficulty, given with `--compatibility`.
+#[derive(Debug, Clone)]
+pub struct Acceptance {
+    pub difficulty: Difficulty,
+    pub servers: Vec<Compatibility>,
+}
+
+impl FromStr for Acceptance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (difficulty, servers) = s.split_once('=').ok_or_else(|| {
+            format!("\"{}\" is not <difficulty>=<servers>", s)
+        })?;
+        Ok(Acceptance {
+            difficulty: difficulty.parse().map_err(|_| {
+                format!("There is no difficulty \"{}\"", difficulty)
+            })?,
+            servers: servers
+                .split(',')
+                .map(|server| {
+                    server.trim().parse().map_err(|_| {
+                        format!("\"{}\" is not 0.6, 0.7 or ddnet", server)
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// The servers of a difficulty, if they are given.
+fn servers(difficulty: Difficulty) -> Option<&'static [Compatibility]> {
+    CONFIG
+        .compatibility
+        .iter()
+        .rev()
+        .find(|a| a.difficulty == difficulty)
+        .map(|a| a.servers.as_slice())
+}
+
+fn incompatible(
+    what: String,
+    difficulty: Difficulty,
+    servers: &[Compatibility],
+) -> ApiError {
+    let servers = servers.iter().map(|c| <&str>::from(*c)).collect::<Vec<_>>();
+    ApiError::new(
+        ErrorCode::IncompatibleMap,
+        format!(
+            "{}, but {} only runs on {} servers!",
+            what,
+            difficulty,
+            servers.join(" or ")
+        ),
+    )
+}
+
+/// Fails if no server runs a map of the class. Maps whose class isn't known
+/// only run on DDNet servers, which run every class.
+fn check_servers(
+    name: &str,
+    class: Option<Compatibility>,
+    difficulty: Difficulty,
+    servers: &[Compatibility],
+) -> Result<(), ApiError> {
+    let runs = |class: Compatibility| servers.iter().any(|s| class.runs_on(*s));
+    match class {
+        Some(class) if runs(class) => Ok(()),
+        None if runs(Compatibility::Ddnet) => Ok(()),
+        Some(class) => Err(incompatible(
+            format!("{} is a {} map", name, <&str>::from(class)),
+            difficulty,
+            servers,
+        )),
+        None => Err(incompatible(
+            format!("It isn't known which servers {} runs on", name),
+            difficulty,
+            servers,
+        )),
+    }
+}
+
+/// Fails if a map of the class can't be put into the difficulty.
+pub fn check(
+    name: &str,
+    class: Option<Compatibility>,
+    difficulty: Difficulty,
+) -> Result<(), ApiError> {
+    match servers(difficulty) {
+        Some(servers) => check_servers(name, class, difficulty, servers),
+        None => Ok(()),
+    }
+}
+
+/// Like `check`, for a stored map. Maps stored before their class was kept
+/// are classified from their file.
+pub fn check_map(map: &Map, difficulty: Difficulty) -> Result<(), ApiError> {
+    if servers(difficulty).is_none() {
+        return Ok(());
+    }
+    let class = map.compatibility.or_else(|| {
+        std::fs::read(map_file_path(map))
+            .ok()
+            .and_then(|file| read(&file))
+    });
+    check(&map.name, class, difficulty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datafile::testing::build;
+    use Compatibility::{Ddnet, Teeworlds06, Teeworlds07};
+
+    /// A game layer of a tilemap version, with more layer flags.
+    fn layer(version: i32, flags: i32) -> (u16, Vec<i32>) {
+        let mut item = vec![0, twmap::LAYER_TILES, 0, version, 1, 1];
+        item.push(twmap::TILES_GAME | flags);
+        item.extend([255, 255, 255, 255, -1, 0, -1, 0, -1, -1, -1]);
+        item.extend([-1; 5]);
+        (twmap::ITEM_LAYER, item)
+    }
+
+    fn class(items: &[(u16, Vec<i32>)]) -> Compatibility {
+        classify(&Datafile::parse(&build(items, &[&[1, 0, 0, 0]])).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn classifies_maps() {
+        let envelope = (twmap::ITEM_ENVELOPE, vec![1, 4, 0, 0]);
+        assert_eq!(class(&[layer(3, 0), envelope]), Teeworlds06);
+
+        assert_eq!(class(&[layer(4, 0)]), Teeworlds07);
+        let image = (twmap::ITEM_IMAGE, vec![2, 64, 64, 1, 0, -1, 1]);
+        assert_eq!(class(&[layer(3, 0), image]), Teeworlds07);
+
+        assert_eq!(class(&[layer(3, twmap::TILES_TELE)]), Ddnet);
+        assert_eq!(class(&[layer(3, TILES_SWITCH)]), Ddnet);
+        let sounds = (twmap::ITEM_LAYER, vec![0, LAYER_SOUNDS[1], 0, 2]);
+        assert_eq!(class(&[layer(3, 0), sounds]), Ddnet);
+        let settings = (twmap::ITEM_INFO, vec![1, -1, -1, -1, -1, 0]);
+        assert_eq!(class(&[layer(3, 0), settings]), Ddnet);
+        let sound = (twmap::ITEM_SOUND, vec![1, 1, 0, -1, 0]);
+        assert_eq!(class(&[layer(3, 0), sound]), Ddnet);
+        assert_eq!(class(&[layer(3, 0), (ITEM_EX, vec![0; 4])]), Ddnet);
+    }
+
+    #[test]
+    fn classifies_ddnet_envelopes_as_0_6() {
+        // DDNet editors write envelopes of version 3 like 0.7 does.
+        let envelope = (twmap::ITEM_ENVELOPE, vec![3, 4, 0, 0, 0]);
+        assert_eq!(class(&[layer(3, 0), envelope]), Teeworlds06);
+    }
+
+    #[test]
+    fn runs_older_maps_on_newer_servers() {
+        let runs = |map: Compatibility| {
+            vec![Teeworlds06, Teeworlds07, Ddnet]
+                .into_iter()
+                .filter(|s| map.runs_on(*s))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(runs(Teeworlds06), [Teeworlds06, Teeworlds07, Ddnet]);
+        assert_eq!(runs(Teeworlds07), [Teeworlds07, Ddnet]);
+        assert_eq!(runs(Ddnet), [Ddnet]);
+    }
+
+    #[test]
+    fn checks_maps_against_the_servers_of_a_difficulty() {
+        let check = |class, servers: &[Compatibility]| {
+            check_servers("map", class, Difficulty::Easy, servers).is_ok()
+        };
+        assert!(check(Some(Teeworlds06), &[Ddnet]));
+        assert!(check(Some(Teeworlds07), &[Ddnet]));
+        assert!(check(Some(Teeworlds06), &[Teeworlds07]));
+        assert!(!check(Some(Ddnet), &[Teeworlds07]));
+        assert!(!check(Some(Teeworlds07), &[Teeworlds06]));
+        assert!(check(Some(Ddnet), &[Teeworlds06, Ddnet]));
+
+        assert!(check(None, &[Ddnet]));
+        assert!(!check(None, &[Teeworlds06, Teeworlds07]));
+        let e = check_servers(
+            "map",
+            Some(Ddnet),
+            Difficulty::Easy,
+            &[Teeworlds06, Teeworlds07],
+        )
+        .unwrap_err();
+        assert_eq!(e.code, ErrorCode::IncompatibleMap);
+        assert!(
+            e.msg.contains("only runs on 0.6 or 0.7 servers"),
+            "{}",
+            e.msg
+        );
+    }
+}
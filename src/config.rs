@@ -1,8 +1,35 @@
 use std::path::PathBuf;
 
+use crate::{
+    apikey::NamedKey, compat::Acceptance, lint::LintLevel, repository::Backend,
+};
+
 pub struct Config {
-    pub apikeys: Vec<String>,
+    pub apikeys: Vec<NamedKey>,
     pub test_map_folder: PathBuf,
     pub public_map_folder: PathBuf,
     pub dev: bool,
+    pub database_backend: Backend,
+    pub database_path: PathBuf,
+    pub backup_dir: Option<PathBuf>,
+    pub backup_interval_hours: u64,
+    pub keep_daily_backups: usize,
+    pub keep_weekly_backups: usize,
+    pub min_free_space_mib: u64,
+    pub max_map_size: u64,
+    pub max_archive_size: u64,
+    pub download_connect_timeout: u64,
+    pub download_read_timeout: u64,
+    pub download_timeout: u64,
+    pub allowed_hosts: Vec<String>,
+    pub denied_hosts: Vec<String>,
+    pub allow_private_downloads: bool,
+    pub max_redirects: usize,
+    pub config_commands: Vec<String>,
+    pub preview_dir: PathBuf,
+    pub revision_dir: PathBuf,
+    pub lint_levels: Vec<LintLevel>,
+    pub mapres: Option<PathBuf>,
+    pub compatibility: Vec<Acceptance>,
+    pub upload_workers: usize,
 }
@@ -0,0 +1,389 @@
+//! Reads teeworlds datafiles, the container maps are stored in.
+//!
+//! A datafile has items, which are small lists of integers with a type and
+//! an id, and data blocks, which are zlib compressed since version 4 and hold
+//! the bigger parts like tiles and names. Items refer to data blocks by their
+//! index. Every size and offset is checked, as the files come from uploads.
+
+use flate2::read::ZlibDecoder;
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    io::Read,
+};
+
+/// The largest a data block may be once unpacked.
+const MAX_DATA_SIZE: usize = 64 * 1024 * 1024;
+
+const HEADER_SIZE: usize = 36;
+
+#[derive(Debug)]
+pub struct DatafileError(pub String);
+
+impl fmt::Display for DatafileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DatafileError {}
+
+impl From<String> for DatafileError {
+    fn from(msg: String) -> Self {
+        DatafileError(msg)
+    }
+}
+
+impl From<&str> for DatafileError {
+    fn from(msg: &str) -> Self {
+        DatafileError(msg.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, DatafileError>;
+
+pub struct Item {
+    pub type_id: u16,
+    pub data: Vec<i32>,
+}
+
+pub struct Datafile<'a> {
+    pub version: i32,
+    pub items: Vec<Item>,
+    /// Where each data block starts in `data`.
+    data_offsets: Vec<usize>,
+    /// The unpacked sizes of the data blocks, only stored by version 4.
+    data_sizes: Vec<usize>,
+    data: &'a [u8],
+}
+
+/// Reads `count` little endian integers at `offset`.
+fn ints(file: &[u8], offset: usize, count: usize) -> Result<Vec<i32>> {
+    let end = count
+        .checked_mul(4)
+        .and_then(|len| len.checked_add(offset))
+        .filter(|end| *end <= file.len())
+        .ok_or("The datafile is cut off.")?;
+    Ok(file[offset..end]
+        .chunks_exact(4)
+        .map(|c| i32::from_le_bytes(c.try_into().unwrap()))
+        .collect())
+}
+
+/// A count or size from the file, which can't be negative.
+fn size(value: i32, what: &str) -> Result<usize> {
+    usize::try_from(value).map_err(|_| {
+        DatafileError(format!("The datafile has {} {}.", value, what))
+    })
+}
+
+impl<'a> Datafile<'a> {
+    pub fn parse(file: &'a [u8]) -> Result<Self> {
+        if !file.starts_with(b"DATA") && !file.starts_with(b"ATAD") {
+            return Err("The file is not a datafile.".into());
+        }
+        let header = ints(file, 4, 8)?;
+        let version = header[0];
+        if version != 3 && version != 4 {
+            return Err(format!(
+                "Datafile version {} is not supported.",
+                version
+            )
+            .into());
+        }
+        let num_item_types = size(header[3], "item types")?;
+        let num_items = size(header[4], "items")?;
+        let num_data = size(header[5], "data blocks")?;
+        let item_size = size(header[6], "bytes of items")?;
+        let data_size = size(header[7], "bytes of data")?;
+
+        // The item types only speed up lookups, the items have their types.
+        let mut offset = HEADER_SIZE + num_item_types * 12;
+        let item_offsets = ints(file, offset, num_items)?;
+        offset += num_items * 4;
+        let data_offsets = ints(file, offset, num_data)?;
+        offset += num_data * 4;
+        let data_sizes = if version == 4 {
+            let sizes = ints(file, offset, num_data)?;
+            offset += num_data * 4;
+            sizes
+        } else {
+            Vec::new()
+        };
+
+        let items_start = offset;
+        let data_start = items_start + item_size;
+        if data_start + data_size > file.len() {
+            return Err("The datafile is cut off.".into());
+        }
+        let item_area = &file[items_start..data_start];
+
+        let mut items = Vec::with_capacity(num_items);
+        for item_offset in item_offsets {
+            let item_offset = size(item_offset, "as an item offset")?;
+            let header = ints(item_area, item_offset, 2)?;
+            let len = size(header[1], "as an item size")?;
+            items.push(Item {
+                type_id: (header[0] >> 16 & 0xffff) as u16,
+                data: ints(item_area, item_offset + 8, len / 4)?,
+            });
+        }
+
+        let data_offsets = data_offsets
+            .into_iter()
+            .map(|o| size(o, "as a data offset"))
+            .collect::<Result<Vec<_>>>()?;
+        if data_offsets.iter().any(|o| *o > data_size) {
+            return Err("A data block is outside of the datafile.".into());
+        }
+
+        Ok(Datafile {
+            version,
+            items,
+            data_offsets,
+            data_sizes: data_sizes
+                .into_iter()
+                .map(|s| size(s, "as a data size"))
+                .collect::<Result<_>>()?,
+            data: &file[data_start..data_start + data_size],
+        })
+    }
+
+    /// The items of one type, in the order they are stored.
+    pub fn items(&self, type_id: u16) -> impl Iterator<Item = &Item> {
+        self.items.iter().filter(move |i| i.type_id == type_id)
+    }
+
+    /// The number of bytes a data block takes up in the file.
+    pub fn stored_size(&self, index: i32) -> Option<usize> {
+        let i = usize::try_from(index).ok()?;
+        let start = *self.data_offsets.get(i)?;
+        let end = self
+            .data_offsets
+            .get(i + 1)
+            .copied()
+            .unwrap_or(self.data.len());
+        end.checked_sub(start)
+    }
+
+    /// The unpacked contents of a data block.
+    pub fn data(&self, index: i32) -> Result<Vec<u8>> {
+        let i = usize::try_from(index)
+            .ok()
+            .filter(|i| *i < self.data_offsets.len())
+            .ok_or_else(|| format!("There is no data block {}.", index))?;
+        let start = self.data_offsets[i];
+        let end = self
+            .data_offsets
+            .get(i + 1)
+            .copied()
+            .unwrap_or(self.data.len());
+        let raw = self
+            .data
+            .get(start..end)
+            .ok_or_else(|| format!("Data block {} is out of order.", index))?;
+        if self.version == 3 {
+            return Ok(raw.to_vec());
+        }
+
+        let expected = self.data_sizes[i];
+        if expected > MAX_DATA_SIZE {
+            return Err(format!("Data block {} is too large.", index).into());
+        }
+        let mut data = Vec::with_capacity(expected);
+        ZlibDecoder::new(raw)
+            .take(expected as u64 + 1)
+            .read_to_end(&mut data)
+            .map_err(|e| format!("Data block {} is broken: {}", index, e))?;
+        if data.len() != expected {
+            return Err(format!(
+                "Data block {} doesn't have its stated size.",
+                index
+            )
+            .into());
+        }
+        Ok(data)
+    }
+
+    /// A data block holding a zero terminated string, like a name.
+    pub fn string(&self, index: i32) -> Result<String> {
+        let data = self.data(index)?;
+        let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+        Ok(String::from_utf8_lossy(&data[..end]).into_owned())
+    }
+}
+
+/// Writes datafiles for the tests of the modules that read them.
+#[cfg(test)]
+pub mod testing {
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    fn push(out: &mut Vec<u8>, ints: &[i32]) {
+        for i in ints {
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+    }
+
+    /// A version 4 datafile with items of a type and their fields, and
+    /// compressed data blocks.
+    pub fn build(items: &[(u16, Vec<i32>)], data: &[&[u8]]) -> Vec<u8> {
+        let mut item_area = Vec::new();
+        let mut item_offsets = Vec::new();
+        for (id, (type_id, fields)) in items.iter().enumerate() {
+            item_offsets.push(item_area.len() as i32);
+            let key = (*type_id as i32) << 16 | id as i32;
+            push(&mut item_area, &[key, fields.len() as i32 * 4]);
+            push(&mut item_area, fields);
+        }
+        let mut data_area = Vec::new();
+        let mut data_offsets = Vec::new();
+        for block in data {
+            data_offsets.push(data_area.len() as i32);
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(block).unwrap();
+            data_area.extend(encoder.finish().unwrap());
+        }
+
+        let mut file = b"DATA".to_vec();
+        push(
+            &mut file,
+            &[
+                4,
+                0,
+                0,
+                0,
+                items.len() as i32,
+                data.len() as i32,
+                item_area.len() as i32,
+                data_area.len() as i32,
+            ],
+        );
+        push(&mut file, &item_offsets);
+        push(&mut file, &data_offsets);
+        push(
+            &mut file,
+            &data.iter().map(|d| d.len() as i32).collect::<Vec<_>>(),
+        );
+        file.extend(item_area);
+        file.extend(data_area);
+        file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{testing::build, *};
+
+    /// Where the counts of the header start.
+    const COUNTS: usize = 4 + 3 * 4;
+
+    fn set_int(file: &mut [u8], offset: usize, value: i32) {
+        file[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn error(file: &[u8]) -> String {
+        match Datafile::parse(file) {
+            Ok(_) => panic!("the datafile was read"),
+            Err(e) => e.0,
+        }
+    }
+
+    #[test]
+    fn reads_what_was_written() {
+        let file = build(
+            &[(1, vec![1, 0, -1]), (5, vec![0, 2, 7]), (5, vec![3])],
+            &[b"alice\0", &[7; 1000]],
+        );
+        let df = Datafile::parse(&file).unwrap();
+        assert_eq!(df.version, 4);
+        assert_eq!(df.items.len(), 3);
+        let layers = df.items(5).map(|i| i.data.clone()).collect::<Vec<_>>();
+        assert_eq!(layers, [vec![0, 2, 7], vec![3]]);
+        assert_eq!(df.string(0).unwrap(), "alice");
+        assert_eq!(df.data(1).unwrap(), vec![7; 1000]);
+        assert!(df.stored_size(1).unwrap() < 1000);
+    }
+
+    #[test]
+    fn refuses_broken_headers() {
+        assert!(error(b"PNG\0 not a map").contains("not a datafile"));
+        assert!(error(b"DATA\x04\0\0\0").contains("cut off"));
+
+        let file = build(&[(1, vec![1])], &[b"x"]);
+        let mut old = file.clone();
+        set_int(&mut old, 4, 2);
+        assert!(error(&old).contains("version 2"));
+
+        let mut negative = file.clone();
+        set_int(&mut negative, COUNTS + 4, -1);
+        assert!(error(&negative).contains("-1 items"));
+
+        // More items than the file has room for.
+        let mut many = file.clone();
+        set_int(&mut many, COUNTS + 4, i32::MAX);
+        assert!(error(&many).contains("cut off"));
+
+        let mut cut = file.clone();
+        cut.truncate(file.len() - 1);
+        assert!(error(&cut).contains("cut off"));
+    }
+
+    #[test]
+    fn refuses_items_and_data_outside_the_file() {
+        let file = build(&[(1, vec![1])], &[b"x"]);
+        let items = HEADER_SIZE;
+
+        let mut item = file.clone();
+        set_int(&mut item, items, 1000);
+        assert!(error(&item).contains("cut off"));
+
+        // The item area follows an item, a data offset and a data size.
+        let mut item_size = file.clone();
+        set_int(&mut item_size, items + 3 * 4 + 4, 4000);
+        assert!(error(&item_size).contains("cut off"));
+
+        let mut odd_size = file.clone();
+        set_int(&mut odd_size, items + 3 * 4 + 4, -4);
+        assert!(error(&odd_size).contains("item size"));
+
+        let mut data = file.clone();
+        set_int(&mut data, items + 4, 1000);
+        assert!(error(&data).contains("outside"));
+    }
+
+    #[test]
+    fn refuses_missing_and_broken_data_blocks() {
+        let file = build(&[], &[b"hello", b"world"]);
+        let df = Datafile::parse(&file).unwrap();
+        assert!(df.data(2).is_err());
+        assert!(df.data(-1).is_err());
+        assert!(df.string(7).is_err());
+        assert_eq!(df.stored_size(-1), None);
+
+        // The sizes follow the two data offsets.
+        let sizes = HEADER_SIZE + 2 * 4;
+        let mut wrong_size = file.clone();
+        set_int(&mut wrong_size, sizes, 4);
+        let df = Datafile::parse(&wrong_size).unwrap();
+        assert!(df.data(0).unwrap_err().0.contains("stated size"));
+
+        let mut too_large = file.clone();
+        set_int(&mut too_large, sizes, i32::MAX);
+        let df = Datafile::parse(&too_large).unwrap();
+        assert!(df.data(0).unwrap_err().0.contains("too large"));
+
+        let mut broken = file.clone();
+        let data_start =
+            file.len() - Datafile::parse(&file).unwrap().data.len();
+        broken[data_start] ^= 0xff;
+        let df = Datafile::parse(&broken).unwrap();
+        assert!(df.data(0).unwrap_err().0.contains("broken"));
+
+        // The offsets of the blocks are out of order.
+        let mut order = file.clone();
+        set_int(&mut order, HEADER_SIZE, 12);
+        let df = Datafile::parse(&order).unwrap();
+        assert!(df.data(0).is_err());
+    }
+}
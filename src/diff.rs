@@ -0,0 +1,323 @@
+//! Compares two revisions of a map, so reviewers see what a new upload
+//! changed.
+
+use rocket::serde::Serialize;
+use schemars::JsonSchema;
+use std::{cmp::Reverse, collections::BTreeSet, error::Error};
+
+use crate::{
+    datafile::{self, Datafile},
+    preview,
+    revisions::RevisionFile,
+    twmap::{self, Tiles},
+};
+
+/// The most regions listed, the largest first.
+const MAX_REGIONS: usize = 100;
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A tile layer that changed its size, by its position among the tile
+/// layers. Layers that only exist in one of the revisions have no size in
+/// the other.
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct LayerChange {
+    pub layer: usize,
+    pub name: String,
+    pub from: Option<Size>,
+    pub to: Option<Size>,
+}
+
+/// Connected changed tiles and the rectangle around them.
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// The number of changed tiles in the rectangle.
+    pub tiles: u32,
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TileChanges {
+    /// The number of game tiles that changed, counting tiles outside of the
+    /// smaller layer as air.
+    pub changed: u32,
+    /// The largest regions of changed tiles.
+    pub regions: Vec<Region>,
+    /// The number of regions that weren't listed.
+    pub more_regions: usize,
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ImageChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// A field of the map info that changed. Settings are one per line.
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct InfoChange {
+    pub field: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct MapDiff {
+    /// The numbers of the compared revisions.
+    pub from: usize,
+    pub to: usize,
+    pub layers: Vec<LayerChange>,
+    pub game: TileChanges,
+    pub images: ImageChanges,
+    pub info: Vec<InfoChange>,
+}
+
+/// Which game tiles differ, on a grid as large as both game layers.
+struct Changed {
+    width: usize,
+    height: usize,
+    tiles: Vec<bool>,
+}
+
+impl Changed {
+    /// Compares two layers. Fails if the grid around both would have more
+    /// tiles than a layer may have, like for a very wide and a very high one.
+    fn between(from: &Tiles, to: &Tiles) -> datafile::Result<Self> {
+        let get = |tiles: &Tiles, x: usize, y: usize| {
+            if x < tiles.width && y < tiles.height {
+                tiles.get(x, y)
+            } else {
+                0
+            }
+        };
+        let width = from.width.max(to.width);
+        let height = from.height.max(to.height);
+        let count = width
+            .checked_mul(height)
+            .filter(|c| *c <= twmap::MAX_TILES)
+            .ok_or("The game layers are too large to compare.")?;
+        let mut tiles = Vec::with_capacity(count);
+        for y in 0..height {
+            for x in 0..width {
+                tiles.push(get(from, x, y) != get(to, x, y));
+            }
+        }
+        Ok(Changed {
+            width,
+            height,
+            tiles,
+        })
+    }
+
+    /// The groups of changed tiles that touch, also diagonally.
+    fn regions(&self) -> Vec<Region> {
+        let mut seen = vec![false; self.tiles.len()];
+        let mut regions = Vec::new();
+        for start in 0..self.tiles.len() {
+            if !self.tiles[start] || seen[start] {
+                continue;
+            }
+            seen[start] = true;
+            let mut stack = vec![start];
+            let (mut min_x, mut min_y) = (self.width, self.height);
+            let (mut max_x, mut max_y, mut count) = (0, 0, 0);
+            while let Some(i) = stack.pop() {
+                let (x, y) = (i % self.width, i / self.width);
+                min_x = min_x.min(x);
+                min_y = min_y.min(y);
+                max_x = max_x.max(x);
+                max_y = max_y.max(y);
+                count += 1;
+                for ny in y.saturating_sub(1)..(y + 2).min(self.height) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(self.width) {
+                        let n = ny * self.width + nx;
+                        if self.tiles[n] && !seen[n] {
+                            seen[n] = true;
+                            stack.push(n);
+                        }
+                    }
+                }
+            }
+            regions.push(Region {
+                x: min_x as u32,
+                y: min_y as u32,
+                width: (max_x - min_x + 1) as u32,
+                height: (max_y - min_y + 1) as u32,
+                tiles: count,
+            });
+        }
+        regions.sort_by_key(|r| Reverse(r.tiles));
+        regions
+    }
+}
+
+fn layer_changes(
+    from: &Datafile,
+    to: &Datafile,
+) -> datafile::Result<Vec<LayerChange>> {
+    let from = twmap::tile_layers(from)?;
+    let to = twmap::tile_layers(to)?;
+    let size = |layer: Option<&twmap::TileLayer>| {
+        layer.map(|l| Size {
+            width: l.width as u32,
+            height: l.height as u32,
+        })
+    };
+    Ok((0..from.len().max(to.len()))
+        .map(|i| (i, from.get(i), to.get(i)))
+        .filter(|(_, a, b)| size(*a) != size(*b))
+        .map(|(i, a, b)| LayerChange {
+            layer: i,
+            name: b.or(a).map(|l| l.name.clone()).unwrap_or_default(),
+            from: size(a),
+            to: size(b),
+        })
+        .collect())
+}
+
+fn image_names(df: &Datafile) -> datafile::Result<BTreeSet<String>> {
+    twmap::images(df)?
+        .iter()
+        .map(|image| df.string(image.name))
+        .collect()
+}
+
+fn info_changes(
+    from: &Datafile,
+    to: &Datafile,
+) -> datafile::Result<Vec<InfoChange>> {
+    let (from, to) = (twmap::info(from)?, twmap::info(to)?);
+    let settings = |info: &twmap::Info| {
+        Some(info.settings.join("\n")).filter(|s| !s.is_empty())
+    };
+    Ok(vec![
+        ("author", from.author.clone(), to.author.clone()),
+        ("version", from.version.clone(), to.version.clone()),
+        ("credits", from.credits.clone(), to.credits.clone()),
+        ("license", from.license.clone(), to.license.clone()),
+        ("settings", settings(&from), settings(&to)),
+    ]
+    .into_iter()
+    .filter(|(_, from, to)| from != to)
+    .map(|(field, from, to)| InfoChange {
+        field: field.to_string(),
+        from,
+        to,
+    })
+    .collect())
+}
+
+fn game_changes(from: &Datafile, to: &Datafile) -> datafile::Result<Changed> {
+    let from = twmap::tiles(from, &twmap::game_layer(from)?)?;
+    let to = twmap::tiles(to, &twmap::game_layer(to)?)?;
+    Changed::between(&from, &to)
+}
+
+/// Compares the layers, game tiles, images and info of two revisions.
+pub fn diff(
+    from: &RevisionFile,
+    to: &RevisionFile,
+) -> datafile::Result<MapDiff> {
+    let a = Datafile::parse(&from.data)?;
+    let b = Datafile::parse(&to.data)?;
+
+    let changed = game_changes(&a, &b)?;
+    let mut regions = changed.regions();
+    let more_regions = regions.len().saturating_sub(MAX_REGIONS);
+    regions.truncate(MAX_REGIONS);
+
+    let (old_images, new_images) = (image_names(&a)?, image_names(&b)?);
+    Ok(MapDiff {
+        from: from.number,
+        to: to.number,
+        layers: layer_changes(&a, &b)?,
+        game: TileChanges {
+            changed: changed.tiles.iter().filter(|c| **c).count() as u32,
+            regions,
+            more_regions,
+        },
+        images: ImageChanges {
+            added: new_images.difference(&old_images).cloned().collect(),
+            removed: old_images.difference(&new_images).cloned().collect(),
+        },
+        info: info_changes(&a, &b)?,
+    })
+}
+
+/// Draws the game tiles of the newer revision with the changed tiles and
+/// the rectangles around them highlighted.
+pub fn render(
+    from: &RevisionFile,
+    to: &RevisionFile,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let a = Datafile::parse(&from.data)?;
+    let b = Datafile::parse(&to.data)?;
+    let changed = game_changes(&a, &b)?;
+    let regions = changed.regions();
+    preview::highlight(
+        &twmap::game_tiles(&b)?,
+        (changed.width, changed.height),
+        &changed.tiles,
+        &regions,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiles(width: usize, rows: &[&str]) -> Tiles {
+        Tiles {
+            width,
+            height: rows.len(),
+            indices: rows
+                .iter()
+                .flat_map(|r| r.bytes().map(|b| (b == b'#') as u8))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn groups_changed_tiles_into_regions() {
+        let from = tiles(4, &["#...", "....", "...."]);
+        let to = tiles(5, &[".#...", "....#", "....#", "#...."]);
+        let changed = Changed::between(&from, &to).unwrap();
+        assert_eq!((changed.width, changed.height), (5, 4));
+
+        let regions = changed.regions();
+        let boxes = regions
+            .iter()
+            .map(|r| (r.x, r.y, r.width, r.height, r.tiles))
+            .collect::<Vec<_>>();
+        assert_eq!(boxes, [(0, 0, 2, 1, 2), (4, 1, 1, 2, 2), (0, 3, 1, 1, 1)]);
+    }
+
+    #[test]
+    fn refuses_grids_larger_than_a_layer() {
+        let wide = Tiles {
+            width: twmap::MAX_TILES,
+            height: 1,
+            indices: Vec::new(),
+        };
+        let high = Tiles {
+            width: 1,
+            height: twmap::MAX_TILES,
+            indices: Vec::new(),
+        };
+        assert!(Changed::between(&wide, &high).is_err());
+    }
+}
@@ -0,0 +1,307 @@
+//! Downloads of map files from urls given by clients.
+//!
+//! The urls come from anyone with an api key, so the server must not be made
+//! to fetch from its own network or to read endless bodies. Hosts are checked
+//! against the allow and deny lists, and names are resolved to public
+//! addresses only, which also covers redirects and names that resolve to
+//! something else the second time.
+
+use hyper::client::connect::dns::Name;
+use lazy_static::lazy_static;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect, Client, Url,
+};
+use rocket::tokio::{net::lookup_host, time::timeout};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    CONFIG,
+};
+
+const MIB: u64 = 1024 * 1024;
+
+lazy_static! {
+    static ref CLIENT: Client = Client::builder()
+        .connect_timeout(Duration::from_secs(CONFIG.download_connect_timeout))
+        .redirect(redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() > CONFIG.max_redirects {
+                attempt.error(Blocked("too many redirects".to_string()))
+            } else if let Err(e) = check_url(attempt.url()) {
+                attempt.error(e)
+            } else {
+                attempt.follow()
+            }
+        }))
+        .dns_resolver(Arc::new(PublicResolver))
+        // A proxy would resolve the names itself.
+        .no_proxy()
+        .build()
+        .expect("could not build the download client");
+}
+
+/// Why a url may not be downloaded.
+#[derive(Debug)]
+struct Blocked(String);
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+/// Whether `host` is `entry` or one of its subdomains.
+fn matches_host(host: &str, entry: &str) -> bool {
+    let entry = entry.trim_start_matches("*.");
+    host.eq_ignore_ascii_case(entry)
+        || host.len() > entry.len()
+            && host.as_bytes()[host.len() - entry.len() - 1] == b'.'
+            && host[host.len() - entry.len()..].eq_ignore_ascii_case(entry)
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Shared address space and the reserved 240.0.0.0/4.
+        || a == 100 && (64..128).contains(&b)
+        || a == 0
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    if let Some(v4) = ip.to_ipv4() {
+        // Mapped and compatible addresses reach the IPv4 address.
+        if segments[..5] == [0; 5] && !ip.is_loopback() {
+            return is_public_v4(v4);
+        }
+    }
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local and link local addresses.
+        || segments[0] & 0xfe00 == 0xfc00
+        || segments[0] & 0xffc0 == 0xfe80
+        // Documentation addresses.
+        || segments[0] == 0x2001 && segments[1] == 0xdb8
+        // NAT64 and 6to4 addresses are translated to IPv4 addresses by
+        // gateways that may reach the private network.
+        || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+        || segments[0] == 0x2002)
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn check_address(ip: IpAddr) -> Result<(), Blocked> {
+    if CONFIG.allow_private_downloads || is_public(ip) {
+        Ok(())
+    } else {
+        Err(Blocked(format!("{} is not a public address", ip)))
+    }
+}
+
+/// Checks the scheme and host of a url, and its address if it has no name.
+fn check_url(url: &Url) -> Result<(), Blocked> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(Blocked(format!("{} urls can't be used", url.scheme())));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| Blocked("the url has no host".to_string()))?;
+    if CONFIG.denied_hosts.iter().any(|e| matches_host(host, e)) {
+        return Err(Blocked(format!("{} is denied", host)));
+    }
+    if !CONFIG.allowed_hosts.is_empty()
+        && !CONFIG.allowed_hosts.iter().any(|e| matches_host(host, e))
+    {
+        return Err(Blocked(format!("{} is not allowed", host)));
+    }
+    // Addresses don't go through the resolver.
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => check_address(ip),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Resolves names to the addresses that may be downloaded from.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| check_address(addr.ip()).is_ok())
+                .collect::<Vec<_>>();
+            if addrs.is_empty() {
+                return Err(Blocked(format!(
+                    "{} has no public address",
+                    name.as_str()
+                ))
+                .into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn too_large() -> ApiError {
+    ApiError::new(
+        ErrorCode::MapTooLarge,
+        format!("The map file is larger than {} MiB.", CONFIG.max_map_size),
+    )
+}
+
+fn failed(e: reqwest::Error) -> ApiError {
+    // Blocked urls found while connecting or redirecting end up as the
+    // source of the error.
+    let mut source = std::error::Error::source(&e);
+    while let Some(cause) = source {
+        if let Some(blocked) = cause.downcast_ref::<Blocked>() {
+            return ApiError::new(
+                ErrorCode::DownloadForbidden,
+                format!("The url can't be downloaded: {}.", blocked),
+            );
+        }
+        source = cause.source();
+    }
+    ApiError::new(ErrorCode::DownloadFailed, e.without_url().to_string())
+}
+
+fn timed_out() -> ApiError {
+    ApiError::new(
+        ErrorCode::DownloadFailed,
+        "The server of the map file stopped answering.",
+    )
+}
+
+/// Checks that a url may be downloaded, as far as that is known before
+/// connecting.
+pub fn check(url: &str) -> Result<Url, ApiError> {
+    let url = Url::parse(url).map_err(|e| {
+        ApiError::new(
+            ErrorCode::DownloadForbidden,
+            format!("Invalid url: {}.", e),
+        )
+    })?;
+    check_url(&url).map_err(|e| {
+        ApiError::new(
+            ErrorCode::DownloadForbidden,
+            format!("The url can't be downloaded: {}.", e),
+        )
+    })?;
+    Ok(url)
+}
+
+/// Downloads a map file, giving up once it gets larger than the limit.
+/// `progress` is told the bytes downloaded so far and the size of the file,
+/// if the server sent it.
+pub async fn fetch(
+    url: &str,
+    progress: impl Fn(u64, Option<u64>),
+) -> Result<Vec<u8>, ApiError> {
+    let url = check(url)?;
+    // The read timeout is per chunk, so a server sending a byte now and then
+    // would keep the download going for hours.
+    let deadline = Duration::from_secs(CONFIG.download_timeout);
+    timeout(deadline, fetch_checked(url, progress))
+        .await
+        .map_err(|_| {
+            ApiError::new(
+                ErrorCode::DownloadFailed,
+                "The server of the map file took too long to send it.",
+            )
+        })?
+}
+
+async fn fetch_checked(
+    url: Url,
+    progress: impl Fn(u64, Option<u64>),
+) -> Result<Vec<u8>, ApiError> {
+    let read_timeout = Duration::from_secs(CONFIG.download_read_timeout);
+    let max = CONFIG.max_map_size * MIB;
+    let mut response = timeout(read_timeout, CLIENT.get(url).send())
+        .await
+        .map_err(|_| timed_out())?
+        .and_then(|r| r.error_for_status())
+        .map_err(failed)?;
+    let total = response.content_length();
+    if matches!(total, Some(len) if len > max) {
+        return Err(too_large());
+    }
+
+    let mut file = Vec::new();
+    while let Some(chunk) = timeout(read_timeout, response.chunk())
+        .await
+        .map_err(|_| timed_out())?
+        .map_err(failed)?
+    {
+        if (file.len() + chunk.len()) as u64 > max {
+            return Err(too_large());
+        }
+        file.extend_from_slice(&chunk);
+        progress(file.len() as u64, total);
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_match_their_subdomains() {
+        assert!(matches_host("example.com", "example.com"));
+        assert!(matches_host("maps.Example.com", "example.com"));
+        assert!(matches_host("maps.example.com", "*.example.com"));
+        assert!(!matches_host("badexample.com", "example.com"));
+        assert!(!matches_host("example.com.evil", "example.com"));
+    }
+
+    #[test]
+    fn private_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "64:ff9b::a00:1",
+            "64:ff9b::7f00:1",
+            "2002:a00:1::1",
+            "2002:5db8:d822::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
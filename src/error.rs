@@ -0,0 +1,372 @@
+//! The errors the api answers with.
+//!
+//! Every error has an `ErrorCode` that clients can rely on, and a message for
+//! people. Routes return a `RouteError` that names the codes the route can
+//! answer with, so its OpenAPI docs list exactly those.
+
+use rocket::{
+    catch,
+    http::Status,
+    response::{self, Responder},
+    serde::{json::Json, Deserialize, Serialize},
+    Request,
+};
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    okapi::openapi3::{MediaType, RefOr, Response, Responses},
+    response::OpenApiResponderInner,
+    OpenApiError,
+};
+use schemars::JsonSchema;
+use std::{collections::BTreeMap, fmt, marker::PhantomData};
+use strum::{EnumIter, IntoEnumIterator, IntoStaticStr};
+
+use crate::{apikey::AuthFailure, logging};
+
+#[derive(
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    EnumIter,
+    IntoStaticStr,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(crate = "rocket::serde", rename_all = "SCREAMING_SNAKE_CASE")]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    BadRequest,
+    InvalidBody,
+    MissingApiKey,
+    InvalidApiKey,
+    NotFound,
+    MapNotFound,
+    JobNotFound,
+    RevisionNotFound,
+    InvalidTransition,
+    MissingMapres,
+    IncompatibleMap,
+    InvalidDifficulty,
+    InvalidFilter,
+    InvalidName,
+    NameTaken,
+    DownloadFailed,
+    DownloadForbidden,
+    MapTooLarge,
+    InvalidMapFile,
+    InvalidArchive,
+    InvalidConfig,
+    LintFailed,
+    PreviewFailed,
+    InvalidImport,
+    InternalError,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> Status {
+        use ErrorCode::*;
+        match self {
+            BadRequest | MissingApiKey | InvalidTransition
+            | InvalidDifficulty | InvalidFilter | InvalidName
+            | DownloadForbidden | InvalidMapFile | InvalidArchive
+            | InvalidConfig | LintFailed | InvalidImport => Status::BadRequest,
+            InvalidApiKey => Status::Unauthorized,
+            NotFound | MapNotFound | JobNotFound | RevisionNotFound => {
+                Status::NotFound
+            }
+            NameTaken | MissingMapres | IncompatibleMap => Status::Conflict,
+            MapTooLarge => Status::PayloadTooLarge,
+            InvalidBody | PreviewFailed => Status::UnprocessableEntity,
+            DownloadFailed => Status::BadGateway,
+            InternalError => Status::InternalServerError,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        use ErrorCode::*;
+        match self {
+            BadRequest => "The request is malformed or misses data.",
+            InvalidBody => "The body doesn't fit the route.",
+            MissingApiKey => "The `x-api-key` header is missing.",
+            InvalidApiKey => "The api key is not valid.",
+            NotFound => "There is no such route.",
+            MapNotFound => {
+                "There is no map with that name. Similar names are given as \
+                 suggestions."
+            }
+            JobNotFound => "There is no job with that id, or it's too old.",
+            RevisionNotFound => {
+                "The map has no revision with that number, or its file isn't \
+                 kept."
+            }
+            InvalidTransition => "The map can't go to that state.",
+            MissingMapres => {
+                "The map uses external mapres the servers don't have."
+            }
+            IncompatibleMap => {
+                "The difficulty doesn't take maps for the servers this map \
+                 needs."
+            }
+            InvalidDifficulty => "The difficulty doesn't exist.",
+            InvalidFilter => "A filter has a value that doesn't exist.",
+            InvalidName => "The name can't be used for a map.",
+            NameTaken => "Another map already has that name.",
+            DownloadFailed => "The map file could not be downloaded.",
+            DownloadForbidden => {
+                "The url or a redirect leads to a blocked host."
+            }
+            MapTooLarge => "The map file is larger than the server allows.",
+            InvalidMapFile => "The file is not a teeworlds map.",
+            InvalidArchive => "The archive is broken or can't be used.",
+            InvalidConfig => "The config uses a command that isn't allowed.",
+            LintFailed => "The map has lint findings of severity `error`.",
+            PreviewFailed => "The layers of the map file can't be read.",
+            InvalidImport => "The export can't be imported.",
+            InternalError => "Something went wrong on the server.",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub msg: String,
+    pub suggestions: Vec<String>,
+    /// What actually went wrong for internal errors. Never sent to clients.
+    pub cause: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, msg: impl Into<String>) -> Self {
+        let msg = msg.into();
+        tracing::warn!(code = <&str>::from(code), error = %msg, "Request failed");
+        ApiError {
+            code,
+            msg,
+            suggestions: Vec::new(),
+            cause: None,
+        }
+    }
+
+    /// Logs the cause, which the client doesn't get to see.
+    pub fn internal(e: impl fmt::Display) -> Self {
+        tracing::error!(error = %e, "Internal server error");
+        ApiError {
+            code: ErrorCode::InternalError,
+            msg: "Something went wrong on server side!".to_string(),
+            suggestions: Vec::new(),
+            cause: Some(e.to_string()),
+        }
+    }
+
+    pub fn with_suggestions(self, suggestions: Vec<String>) -> Self {
+        ApiError {
+            suggestions,
+            ..self
+        }
+    }
+
+    pub fn body(&self, request_id: Option<String>) -> ErrorBody {
+        ErrorBody {
+            error: self.code,
+            msg: self.msg.clone(),
+            code: self.code.status().code,
+            suggestions: self.suggestions.clone(),
+            request_id,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ErrorBody {
+    pub error: ErrorCode,
+    pub msg: String,
+    /// The HTTP status.
+    pub code: u16,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suggestions: Vec<String>,
+    /// The id of the request, to find it in the logs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = self.body(Some(logging::id_of(request).to_string()));
+        (self.code.status(), Json(body)).respond_to(request)
+    }
+}
+
+impl OpenApiResponderInner for ApiError {
+    fn responses(
+        gen: &mut OpenApiGenerator,
+    ) -> Result<Responses, OpenApiError> {
+        Ok(responses(gen, &ErrorCode::iter().collect::<Vec<_>>()))
+    }
+}
+
+/// Documents the responses for `codes`, one per status.
+pub fn responses(gen: &mut OpenApiGenerator, codes: &[ErrorCode]) -> Responses {
+    let schema = gen.json_schema::<ErrorBody>();
+    let mut by_status = BTreeMap::<u16, Vec<ErrorCode>>::new();
+    for code in codes {
+        by_status.entry(code.status().code).or_default().push(*code);
+    }
+
+    let mut responses = Responses::default();
+    for (status, codes) in by_status {
+        // Descriptions of the same status from other parts of the route are
+        // joined with a newline, so there is one line per code.
+        let description = codes
+            .iter()
+            .map(|c| format!("`{}`: {}", <&str>::from(*c), c.description()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let response = Response {
+            description,
+            content: rocket_okapi::okapi::map! {
+                "application/json".to_owned() => MediaType {
+                    schema: Some(schema.clone()),
+                    ..Default::default()
+                }
+            },
+            ..Default::default()
+        };
+        responses
+            .responses
+            .insert(status.to_string(), RefOr::Object(response));
+    }
+    responses
+}
+
+/// The codes a route can answer with, besides the ones every route can.
+pub trait ErrorSet: Send {
+    const CODES: &'static [ErrorCode];
+}
+
+macro_rules! error_sets {
+    ($($(#[$attr:meta])* $name:ident: [$($code:ident),*];)*) => {
+        $(
+            $(#[$attr])*
+            pub struct $name;
+
+            impl ErrorSet for $name {
+                const CODES: &'static [ErrorCode] = &[$(ErrorCode::$code),*];
+            }
+        )*
+    };
+}
+
+error_sets! {
+    NoErrors: [];
+    ListErrors: [InvalidFilter];
+    CreateErrors: [
+        InvalidBody,
+        InvalidDifficulty,
+        InvalidName,
+        DownloadForbidden
+    ];
+    TransitionErrors: [
+        InvalidBody,
+        MapNotFound,
+        InvalidTransition,
+        MissingMapres,
+        IncompatibleMap
+    ];
+    DifficultyErrors: [
+        InvalidBody,
+        MapNotFound,
+        InvalidDifficulty,
+        IncompatibleMap
+    ];
+    BodyErrors: [InvalidBody];
+    MapErrors: [MapNotFound];
+    JobErrors: [JobNotFound];
+    ConfigErrors: [InvalidBody, MapNotFound, InvalidConfig];
+    PreviewErrors: [MapNotFound, PreviewFailed];
+    DependencyErrors: [MapNotFound, InvalidMapFile];
+    DiffErrors: [MapNotFound, RevisionNotFound, InvalidMapFile];
+    RenameErrors: [InvalidBody, MapNotFound, InvalidName, NameTaken];
+    ImportErrors: [InvalidBody, InvalidImport];
+}
+
+pub struct RouteError<S>(pub ApiError, PhantomData<S>);
+
+impl<S: ErrorSet> From<ApiError> for RouteError<S> {
+    fn from(error: ApiError) -> Self {
+        RouteError(error, PhantomData)
+    }
+}
+
+impl<'r, S: ErrorSet> Responder<'r, 'static> for RouteError<S> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        self.0.respond_to(request)
+    }
+}
+
+impl<S: ErrorSet> OpenApiResponderInner for RouteError<S> {
+    fn responses(
+        gen: &mut OpenApiGenerator,
+    ) -> Result<Responses, OpenApiError> {
+        // All routes need an api key. The `ApiKey` guard can't document its
+        // codes itself, as responses with the same status aren't merged.
+        let mut codes = vec![
+            ErrorCode::MissingApiKey,
+            ErrorCode::InvalidApiKey,
+            ErrorCode::InternalError,
+        ];
+        codes.extend_from_slice(S::CODES);
+        Ok(responses(gen, &codes))
+    }
+}
+
+/// The result of a route that can fail with the codes of `S`.
+pub type RouteResult<T, S> = Result<T, RouteError<S>>;
+
+#[catch(400)]
+pub fn bad_request(request: &Request) -> ApiError {
+    match request.local_cache(|| AuthFailure(None)).0 {
+        Some(code) => ApiError::new(code, "Missing `x-api-key` header."),
+        None => ApiError::new(
+            ErrorCode::BadRequest,
+            "The request given is wrongly formatted or data was missing.",
+        ),
+    }
+}
+
+#[catch(401)]
+pub fn unauthorized() -> ApiError {
+    ApiError::new(ErrorCode::InvalidApiKey, "Api key is invalid.")
+}
+
+#[catch(404)]
+pub fn not_found(request: &Request) -> ApiError {
+    ApiError::new(
+        ErrorCode::NotFound,
+        format!("There is no route for {}.", request.uri().path()),
+    )
+}
+
+#[catch(422)]
+pub fn unprocessable_entity() -> ApiError {
+    ApiError::new(
+        ErrorCode::InvalidBody,
+        "The body is not valid for this route.",
+    )
+}
+
+#[catch(500)]
+pub fn internal_server_error() -> ApiError {
+    ApiError::internal("The request failed without a response.")
+}
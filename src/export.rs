@@ -0,0 +1,119 @@
+//! A plain JSON copy of everything in the database, for backups and for
+//! moving mapmaster to another machine.
+
+use crate::{
+    get_current_time, normalize_map_name,
+    repository::{Change, MapFilter, MapRepository, RepositoryError},
+    Map,
+};
+use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::collections::HashSet;
+
+/// The version of the export format. Bump it when a change can't be read by
+/// older versions, and keep reading the old versions in `restore`.
+///
+/// Version 1 kept the hashes of the map files in a separate `checksums` list.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// The hash of a map file, as written by version 1.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Checksum {
+    pub name: String,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Export {
+    pub version: u32,
+    pub exported_at: u64,
+    pub maps: Vec<Map>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checksums: Vec<Checksum>,
+}
+
+pub fn export(db: &dyn MapRepository) -> Result<Export, RepositoryError> {
+    Ok(Export {
+        version: FORMAT_VERSION,
+        exported_at: get_current_time().unwrap_or_default(),
+        maps: db.list(&MapFilter::default())?,
+        checksums: Vec::new(),
+    })
+}
+
+/// Checks that an export is consistent and can be restored as is.
+pub fn validate(export: &Export) -> Result<(), String> {
+    if export.version > FORMAT_VERSION {
+        return Err(format!(
+            "Export version {} is newer than the supported version {}!",
+            export.version, FORMAT_VERSION
+        ));
+    }
+
+    let mut names = HashSet::new();
+    for map in &export.maps {
+        if map.name.is_empty() || normalize_map_name(&map.name) != map.name {
+            return Err(format!("\"{}\" is not a valid map name!", map.name));
+        }
+        if !names.insert(map.name.as_str()) {
+            return Err(format!("Map \"{}\" is listed twice!", map.name));
+        }
+        if map.last_changed < map.created_at {
+            return Err(format!(
+                "Map \"{}\" was changed before it was created!",
+                map.name
+            ));
+        }
+    }
+
+    let mut checksums = HashSet::new();
+    for checksum in &export.checksums {
+        if !names.contains(checksum.name.as_str()) {
+            return Err(format!(
+                "Checksum for unknown map \"{}\"!",
+                checksum.name
+            ));
+        }
+        if !checksums.insert(checksum.name.as_str()) {
+            return Err(format!(
+                "Checksum for map \"{}\" is listed twice!",
+                checksum.name
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes an export into an empty database.
+pub fn restore(
+    db: &dyn MapRepository,
+    export: &Export,
+) -> Result<(), Box<dyn std::error::Error>> {
+    validate(export)?;
+
+    if !db.list(&MapFilter::default())?.is_empty() {
+        return Err("The database is not empty!".into());
+    }
+
+    let changes = export
+        .maps
+        .iter()
+        .map(|map| {
+            let checksum = export.checksums.iter().find(|c| c.name == map.name);
+            match checksum {
+                Some(checksum) if map.sha256.is_none() => Map {
+                    sha256: Some(checksum.sha256.clone()),
+                    ..map.clone()
+                },
+                _ => map.clone(),
+            }
+        })
+        .map(Change::Insert)
+        .collect::<Vec<_>>();
+    db.apply(&changes, None)?;
+
+    Ok(())
+}
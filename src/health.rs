@@ -0,0 +1,121 @@
+//! Health and readiness checks, for docker and load balancers.
+
+use lazy_static::lazy_static;
+use rocket::serde::Serialize;
+use schemars::JsonSchema;
+use std::{fs::OpenOptions, path::Path, sync::Mutex};
+
+use crate::{get_current_time, repository::MapRepository, CONFIG};
+
+const MIB: u64 = 1024 * 1024;
+
+lazy_static! {
+    /// When the votes files were last regenerated, or why that failed.
+    static ref LAST_VOTES_UPDATE: Mutex<Option<Result<u64, String>>> =
+        Mutex::new(None);
+}
+
+pub fn record_votes_update(result: Result<(), String>) {
+    let result = result.map(|()| get_current_time().unwrap_or_default());
+    *LAST_VOTES_UPDATE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(result);
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Check {
+    name: &'static str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Readiness {
+    /// Whether all checks passed.
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+fn check(name: &'static str, result: Result<String, String>) -> Check {
+    match result {
+        Ok(detail) => Check {
+            name,
+            ok: true,
+            detail: Some(detail).filter(|d| !d.is_empty()),
+        },
+        Err(detail) => Check {
+            name,
+            ok: false,
+            detail: Some(detail),
+        },
+    }
+}
+
+/// Writes and removes a file, as the permissions alone don't tell whether
+/// e.g. a read-only mount can be written.
+fn check_writable(dir: &Path) -> Result<String, String> {
+    let path = dir.join(".mapmaster-ready");
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .and_then(|_| std::fs::remove_file(&path))
+        .map(|()| String::new())
+        .map_err(|e| format!("{}: {}", dir.display(), e))
+}
+
+fn check_free_space(dirs: &[&Path]) -> Result<String, String> {
+    let min = CONFIG.min_free_space_mib;
+    let mut details = Vec::new();
+    let mut ok = true;
+    for dir in dirs {
+        let free = fs2::available_space(dir)
+            .map_err(|e| format!("{}: {}", dir.display(), e))?
+            / MIB;
+        ok &= free >= min;
+        details.push(format!("{} MiB free in {}", free, dir.display()));
+    }
+    let details = details.join(", ");
+    if ok {
+        Ok(details)
+    } else {
+        Err(format!("{}, need {} MiB", details, min))
+    }
+}
+
+fn check_votes() -> Result<String, String> {
+    let last = LAST_VOTES_UPDATE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone();
+    match last {
+        Some(Ok(time)) => Ok(format!("last updated at {}", time)),
+        Some(Err(e)) => Err(e),
+        None => Err("not updated yet".to_string()),
+    }
+}
+
+pub fn readiness(db: &dyn MapRepository) -> Readiness {
+    let test = CONFIG.test_map_folder.as_path();
+    let published = CONFIG.public_map_folder.as_path();
+    let checks = vec![
+        check(
+            "database",
+            db.find("")
+                .map(|_| String::new())
+                .map_err(|e| e.to_string()),
+        ),
+        check("test_folder", check_writable(test)),
+        check("published_folder", check_writable(published)),
+        check("free_space", check_free_space(&[test, published])),
+        check("votes", check_votes()),
+    ];
+    Readiness {
+        ready: checks.iter().all(|c| c.ok),
+        checks,
+    }
+}
@@ -0,0 +1,332 @@
+//! Adopts an existing folder of maps, e.g. from a server that ran without
+//! mapmaster, by creating a record for every map file in it.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use strum::EnumString;
+
+use crate::{
+    compat, lint, map_file_path, reconcile,
+    repository::{Change, MapRepository},
+    revisions::Revision,
+    stats, Difficulty, Map, MapState,
+};
+
+/// Where the creation time of imported maps comes from.
+#[derive(EnumString, Debug, Clone, Copy, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum CreatedAtSource {
+    /// The modification time of the map file.
+    Mtime,
+    /// The modification time of the map file, reordered to match the order
+    /// of the maps in the `votes.cfg` of their folder, where there is one.
+    Votes,
+}
+
+/// A map as it appears in a `votes.cfg` written by mapmaster.
+struct Vote {
+    name: String,
+    state: Option<MapState>,
+    difficulty: Option<Difficulty>,
+}
+
+/// Parses an `add_vote "<description>" "<command>"` line. Only votes that
+/// change the map are returned.
+fn parse_vote(line: &str) -> Option<Vote> {
+    let rest = line.trim().strip_prefix("add_vote \"")?;
+    let (description, command) = rest.split_once("\" \"")?;
+    let target = command.split("change_map \\\"").nth(1)?;
+    let target = target.split("\\\"").next()?;
+    let name = target.rsplit('/').next()?.to_lowercase();
+
+    // Test votes look like `🆕 [Easy]     name`.
+    let mut words = description.split_whitespace();
+    let state = match words.next() {
+        Some("☑") => Some(MapState::Approved),
+        Some("☒") => Some(MapState::Declined),
+        Some("🆕") => Some(MapState::New),
+        _ => None,
+    };
+    let difficulty = words
+        .next()
+        .and_then(|d| d.strip_prefix('[')?.strip_suffix(']'))
+        .and_then(|d| d.to_lowercase().parse().ok());
+
+    Some(Vote {
+        name,
+        state,
+        difficulty,
+    })
+}
+
+/// Reads the votes of a folder, up to the first separator that follows a map
+/// vote. For published maps, only the votes above that separator are sorted
+/// by creation time, the rest are sorted by name.
+fn read_votes(folder: &Path) -> Vec<Vote> {
+    let text =
+        std::fs::read_to_string(folder.join("votes.cfg")).unwrap_or_default();
+    let mut votes = Vec::new();
+    for line in text.lines() {
+        match parse_vote(line) {
+            Some(vote) => votes.push(vote),
+            None if !votes.is_empty() && line.ends_with("\"info\"") => break,
+            None => {}
+        }
+    }
+    votes
+}
+
+struct Candidate {
+    name: String,
+    path: PathBuf,
+    state: MapState,
+    difficulty: Option<Difficulty>,
+    created_at: u64,
+}
+
+fn modified(path: &Path) -> u64 {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Figures out where a file belongs from the folders between `root` and it.
+/// Anything below a `test` folder is a test map, anything below a folder
+/// named after a difficulty is published with that difficulty.
+fn locate(root: &Path, path: &Path) -> Option<reconcile::Location> {
+    let relative = path.strip_prefix(root).ok()?;
+    let mut difficulty = None;
+    for component in relative.parent()?.components() {
+        let component = component.as_os_str().to_string_lossy().to_lowercase();
+        if component == "test" {
+            return Some(reconcile::Location::Test);
+        }
+        if difficulty.is_none() {
+            difficulty = component.parse::<Difficulty>().ok();
+        }
+    }
+    difficulty.map(reconcile::Location::Published)
+}
+
+fn walk(
+    folder: &Path,
+    files: &mut Vec<PathBuf>,
+    folders: &mut Vec<PathBuf>,
+) -> Result<(), std::io::Error> {
+    folders.push(folder.to_path_buf());
+    let mut entries = std::fs::read_dir(folder)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            walk(&path, files, folders)?;
+        } else if path.extension() == Some("map".as_ref()) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct Summary {
+    pub imported: Vec<String>,
+    /// Maps that already have a record.
+    pub existing: Vec<String>,
+    /// Files whose name was already seen elsewhere in the imported folder.
+    pub duplicates: Vec<PathBuf>,
+    /// Files that couldn't be imported, with the reason.
+    pub skipped: Vec<(PathBuf, String)>,
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for name in &self.existing {
+            writeln!(f, "already in the database: {}", name)?;
+        }
+        for path in &self.duplicates {
+            writeln!(f, "duplicate: {}", path.display())?;
+        }
+        for (path, reason) in &self.skipped {
+            writeln!(f, "skipped {}: {}", path.display(), reason)?;
+        }
+        write!(
+            f,
+            "{} imported, {} already in the database, {} duplicates, {} skipped.",
+            self.imported.len(),
+            self.existing.len(),
+            self.duplicates.len(),
+            self.skipped.len()
+        )
+    }
+}
+
+/// Creates records for all map files below `root`. Files outside of the
+/// configured map folders are copied to where their record expects them.
+pub fn import_maps(
+    db: &dyn MapRepository,
+    root: &Path,
+    created_at: CreatedAtSource,
+    default_difficulty: Option<Difficulty>,
+    dry_run: bool,
+) -> Result<Summary, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    let mut folders = Vec::new();
+    walk(root, &mut files, &mut folders)?;
+
+    let votes = folders
+        .iter()
+        .map(|folder| (folder.clone(), read_votes(folder)))
+        .collect::<HashMap<_, _>>();
+
+    let mut summary = Summary::default();
+    let mut found: Vec<(String, PathBuf, reconcile::Location)> = Vec::new();
+    for path in files {
+        let name = match path.file_stem() {
+            Some(stem) => stem.to_string_lossy().to_lowercase(),
+            None => continue,
+        };
+        let location = match locate(root, &path) {
+            Some(location) => location,
+            None => {
+                summary.skipped.push((
+                    path,
+                    "not below a test or difficulty folder".to_string(),
+                ));
+                continue;
+            }
+        };
+        if db.find(&name)?.is_some() {
+            summary.existing.push(name);
+            continue;
+        }
+        // Published maps win over test maps, as they got further.
+        match found.iter_mut().find(|(other, _, _)| *other == name) {
+            Some(other)
+                if other.2 == reconcile::Location::Test
+                    && location != reconcile::Location::Test =>
+            {
+                let (_, other_path, _) =
+                    std::mem::replace(other, (name, path, location));
+                summary.duplicates.push(other_path);
+            }
+            Some(_) => summary.duplicates.push(path),
+            None => found.push((name, path, location)),
+        }
+    }
+
+    let mut candidates = found
+        .into_iter()
+        .map(|(name, path, location)| {
+            let vote = path
+                .parent()
+                .and_then(|folder| votes.get(folder))
+                .and_then(|votes| votes.iter().find(|v| v.name == name));
+            let (state, difficulty) = match location {
+                reconcile::Location::Test => (
+                    vote.and_then(|v| v.state).unwrap_or(MapState::New),
+                    vote.and_then(|v| v.difficulty).or(default_difficulty),
+                ),
+                reconcile::Location::Published(difficulty) => {
+                    (MapState::Published, Some(difficulty))
+                }
+            };
+            Candidate {
+                created_at: modified(&path),
+                name,
+                path,
+                state,
+                difficulty,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if created_at == CreatedAtSource::Votes {
+        // Hand out the creation times of the voted maps of each folder in
+        // the order of their votes.
+        for (folder, votes) in &votes {
+            let mut voted = candidates
+                .iter_mut()
+                .filter(|c| c.path.parent() == Some(folder.as_path()))
+                .filter_map(|c| {
+                    votes.iter().position(|v| v.name == c.name).map(|i| (i, c))
+                })
+                .collect::<Vec<_>>();
+            let mut times =
+                voted.iter().map(|(_, c)| c.created_at).collect::<Vec<_>>();
+            times.sort_unstable();
+            voted.sort_by_key(|(i, _)| *i);
+            for ((_, candidate), time) in voted.into_iter().zip(times) {
+                candidate.created_at = time;
+            }
+        }
+    }
+
+    let mut changes = Vec::new();
+    for candidate in candidates {
+        let difficulty = match candidate.difficulty {
+            Some(difficulty) => difficulty,
+            None => {
+                summary.skipped.push((
+                    candidate.path,
+                    "unknown difficulty, pass --default-difficulty".to_string(),
+                ));
+                continue;
+            }
+        };
+        let data = std::fs::read(&candidate.path)?;
+        let sha256 = reconcile::sha256(&data);
+        let map = Map {
+            name: candidate.name,
+            difficulty,
+            state: candidate.state,
+            created_at: candidate.created_at,
+            last_changed: candidate.created_at,
+            sha256: Some(sha256.clone()),
+            config: None,
+            lint: lint::lint(&data),
+            stats: stats::read(&data).ok(),
+            compatibility: compat::read(&data),
+            revisions: vec![Revision {
+                sha256,
+                uploaded_at: candidate.created_at,
+            }],
+        };
+
+        let target = map_file_path(&map);
+        let same_file =
+            match (target.canonicalize(), candidate.path.canonicalize()) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => false,
+            };
+        if !same_file && target.exists() {
+            summary.skipped.push((
+                candidate.path,
+                format!("{} already exists", target.display()),
+            ));
+            continue;
+        }
+
+        if !dry_run && !same_file {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&target, &data)?;
+        }
+        summary.imported.push(map.name.clone());
+        changes.push(Change::Insert(map));
+    }
+
+    if !dry_run {
+        db.apply(&changes, None)?;
+        crate::update_votes(db)?;
+    }
+
+    Ok(summary)
+}
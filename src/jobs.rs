@@ -0,0 +1,293 @@
+//! Uploads of map files, which run after `create` has answered.
+//!
+//! `create` queues a job and answers with its id right away. Workers download,
+//! check and store the map, and the job can be polled for its progress. Jobs
+//! are only kept in memory, so they are lost on restart, and finished jobs are
+//! dropped after a day.
+
+use rocket::{
+    fairing::AdHoc,
+    serde::Serialize,
+    tokio::sync::{mpsc, Mutex as AsyncMutex},
+};
+use schemars::JsonSchema;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tracing::Span;
+
+use crate::{
+    archive::{self, ArchiveMap},
+    check_map_file, check_map_name, compat, download,
+    error::{ApiError, ErrorBody},
+    get_current_time, lint, logging, metrics, normalize_map_name,
+    repository::MapRepository,
+    stats, store_test_maps, CustomState, Difficulty, Map, CONFIG,
+};
+
+/// How long finished jobs can be polled.
+const RETENTION_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Downloading,
+    Validating,
+    Storing,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, JsonSchema, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Job {
+    id: String,
+    status: JobStatus,
+    /// The name given for the map, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    map_name: Option<String>,
+    /// The difficulty of the map, or of the maps of an archive that don't
+    /// have their own.
+    difficulty: Difficulty,
+    url: String,
+    /// The bytes of the map file downloaded so far.
+    downloaded_bytes: u64,
+    /// The size of the map file, if the server told it.
+    total_bytes: Option<u64>,
+    created_at: u64,
+    finished_at: Option<u64>,
+    /// Why the job failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+    /// The stored maps, more than one for an archive.
+    maps: Vec<Map>,
+    /// The request that created the job, to log under its id.
+    #[serde(skip)]
+    request_id: String,
+    #[serde(skip)]
+    api_key: String,
+}
+
+/// The jobs and the queue of the ones still to run.
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, Job>>,
+    /// Held while maps are stored, as two uploads of a new map would both
+    /// insert it otherwise.
+    storing: Mutex<()>,
+    sender: mpsc::UnboundedSender<String>,
+    receiver: AsyncMutex<mpsc::UnboundedReceiver<String>>,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Jobs {
+            jobs: Mutex::new(HashMap::new()),
+            storing: Mutex::new(()),
+            sender,
+            receiver: AsyncMutex::new(receiver),
+        }
+    }
+}
+
+impl Jobs {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Job>> {
+        self.jobs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Queues the upload of a map and returns the new job.
+    pub fn submit(
+        &self,
+        map_name: Option<String>,
+        difficulty: Difficulty,
+        url: String,
+        api_key: String,
+    ) -> Job {
+        let now = get_current_time().unwrap_or_default();
+        let job = Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            status: JobStatus::Queued,
+            map_name,
+            difficulty,
+            url,
+            downloaded_bytes: 0,
+            total_bytes: None,
+            created_at: now,
+            finished_at: None,
+            error: None,
+            maps: Vec::new(),
+            request_id: logging::request_id().unwrap_or_default(),
+            api_key,
+        };
+
+        let mut jobs = self.lock();
+        jobs.retain(|_, job| {
+            !matches!(job.finished_at, Some(at) if at + RETENTION_SECS <= now)
+        });
+        jobs.insert(job.id.clone(), job.clone());
+        drop(jobs);
+
+        // The receiver lives as long as the sender, both are in `self`.
+        let _ = self.sender.send(job.id.clone());
+        job
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.lock().get(id).cloned()
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.lock().get_mut(id) {
+            f(job);
+        }
+    }
+
+    fn set_status(&self, id: &str, status: JobStatus) {
+        self.update(id, |job| job.status = status);
+    }
+
+    async fn next(&self) -> Option<Job> {
+        let id = self.receiver.lock().await.recv().await?;
+        self.get(&id)
+    }
+}
+
+/// The name for a single map, which is the file name in the url if none was
+/// given.
+fn map_name(job: &Job) -> Result<String, ApiError> {
+    if let Some(name) = &job.map_name {
+        return Ok(name.clone());
+    }
+    let file_name = job
+        .url
+        .split(&['?', '#'][..])
+        .next()
+        .and_then(|url| url.rsplit('/').next())
+        .unwrap_or_default();
+    let name = normalize_map_name(file_name);
+    check_map_name(&name, file_name)?;
+    Ok(name)
+}
+
+/// Unpacks, checks and stores the maps of a downloaded file.
+fn check_and_store(
+    jobs: &Jobs,
+    db: &dyn MapRepository,
+    job: &Job,
+    file: Vec<u8>,
+) -> Result<Vec<Map>, ApiError> {
+    let mut maps = match archive::unpack(&file, job.difficulty)? {
+        Some(maps) => maps,
+        None => {
+            check_map_file(&file)?;
+            vec![ArchiveMap {
+                name: map_name(job)?,
+                difficulty: job.difficulty,
+                data: file,
+                config: None,
+                lint: Vec::new(),
+                stats: None,
+                compatibility: None,
+            }]
+        }
+    };
+    for map in &mut maps {
+        map.lint = lint::lint(&map.data);
+        lint::enforce(&map.name, &map.lint)?;
+        map.stats = stats::read(&map.data).ok();
+        map.compatibility = compat::read(&map.data);
+        compat::check(&map.name, map.compatibility, map.difficulty)?;
+    }
+
+    jobs.set_status(&job.id, JobStatus::Storing);
+    let _storing = jobs
+        .storing
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    store_test_maps(db, &maps)
+}
+
+/// Downloads, checks and stores the maps of a job.
+async fn upload(
+    jobs: &Arc<Jobs>,
+    db: &Arc<dyn MapRepository>,
+    job: &Job,
+) -> Result<Vec<Map>, ApiError> {
+    if let Some(name) = &job.map_name {
+        logging::record_map(name);
+    }
+    jobs.set_status(&job.id, JobStatus::Downloading);
+    let download = download::fetch(&job.url, |downloaded, total| {
+        jobs.update(&job.id, |job| {
+            job.downloaded_bytes = downloaded;
+            job.total_bytes = total;
+        })
+    });
+    let file = match download.await {
+        Ok(file) => file,
+        Err(e) => {
+            metrics::DOWNLOAD_FAILURES.inc();
+            return Err(e);
+        }
+    };
+    metrics::DOWNLOAD_BYTES.inc_by(file.len() as u64);
+
+    jobs.set_status(&job.id, JobStatus::Validating);
+    // Reading the maps and writing their files would hold up the other
+    // requests on the runtime.
+    let (jobs, db, job) = (jobs.clone(), db.clone(), job.clone());
+    let span = Span::current();
+    rocket::tokio::task::spawn_blocking(move || {
+        span.in_scope(|| check_and_store(&jobs, db.as_ref(), &job, file))
+    })
+    .await
+    .map_err(ApiError::internal)?
+}
+
+async fn run(jobs: &Arc<Jobs>, db: &Arc<dyn MapRepository>, job: Job) {
+    let result = logging::follow_up(
+        job.request_id.clone(),
+        "upload",
+        &job.api_key,
+        upload(jobs, db, &job),
+    )
+    .await;
+    let request_id = Some(job.request_id.clone()).filter(|id| !id.is_empty());
+    jobs.update(&job.id, |job| {
+        job.finished_at = Some(get_current_time().unwrap_or_default());
+        match result {
+            Ok(maps) => {
+                job.status = JobStatus::Done;
+                job.maps = maps;
+            }
+            Err(e) => {
+                job.status = JobStatus::Failed;
+                job.error = Some(e.body(request_id));
+            }
+        }
+    });
+}
+
+/// Starts the workers that run the queued jobs once the server is running.
+pub fn workers() -> AdHoc {
+    AdHoc::on_liftoff("Upload workers", |rocket| {
+        Box::pin(async move {
+            let state = match rocket.state::<CustomState>() {
+                Some(state) => state,
+                None => return,
+            };
+            for _ in 0..CONFIG.upload_workers.max(1) {
+                let (jobs, db) = (state.jobs.clone(), state.db.clone());
+                rocket::tokio::spawn(async move {
+                    while let Some(job) = jobs.next().await {
+                        run(&jobs, &db, job).await;
+                    }
+                });
+            }
+        })
+    })
+}
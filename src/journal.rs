@@ -0,0 +1,283 @@
+//! Keeps the map folders consistent with the database.
+//!
+//! Moving a map file and updating its record can't happen atomically, so
+//! every move is written to the journal before it is performed. The journal
+//! entry is deleted in the same transaction that updates the maps, which
+//! means an entry that is still around after a crash belongs to a change that
+//! never made it into the database, and its moves have to be undone.
+
+use rocket::serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use structsy_derive::{Persistent, PersistentEmbedded};
+
+use crate::repository::{Change, MapRepository};
+
+#[derive(Serialize, Deserialize, PersistentEmbedded, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct FileMove {
+    pub from: String,
+    pub to: String,
+}
+
+impl FileMove {
+    pub fn new<P: AsRef<Path>>(from: P, to: P) -> Self {
+        FileMove {
+            from: from.as_ref().to_string_lossy().into_owned(),
+            to: to.as_ref().to_string_lossy().into_owned(),
+        }
+    }
+
+    fn perform(&self) -> Result<(), std::io::Error> {
+        move_map(&self.from, &self.to)
+    }
+
+    /// Puts the file back to where it was before the move, based on what
+    /// actually exists on disk, so it is safe to call no matter how far the
+    /// move got.
+    fn undo(&self) -> Result<(), std::io::Error> {
+        let from = PathBuf::from(&self.from);
+        let to = PathBuf::from(&self.to);
+        match (from.exists(), to.exists()) {
+            // Moves onto existing files are refused, so `to` is a copy that
+            // may be incomplete, and the source is still intact.
+            (true, true) => std::fs::remove_file(to),
+            (false, true) => move_map(to, from),
+            (true, false) => Ok(()),
+            (false, false) => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "\"{}\" and \"{}\" are both missing",
+                    self.from, self.to
+                ),
+            )),
+        }
+    }
+}
+
+#[derive(Persistent, Debug)]
+pub struct JournalEntry {
+    pub moves: Vec<FileMove>,
+    pub started_at: u64,
+}
+
+pub fn move_map<P: AsRef<Path>>(from: P, to: P) -> Result<(), std::io::Error> {
+    let p = to.as_ref();
+    if let Some(parent) = p.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // A rename is atomic, but only works within the same filesystem.
+    if std::fs::rename(&from, &to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(&from, to)?;
+    std::fs::remove_file(from)
+}
+
+/// Undoes as many of `moves` as possible. Returns whether all of them were
+/// undone.
+fn undo_all(moves: &[FileMove]) -> bool {
+    let mut undone = true;
+    for file_move in moves.iter().rev() {
+        if let Err(e) = file_move.undo() {
+            tracing::error!(
+                from = %file_move.from,
+                to = %file_move.to,
+                error = %e,
+                "Could not undo move"
+            );
+            undone = false;
+        }
+    }
+    undone
+}
+
+fn forget(db: &dyn MapRepository, id: &str) {
+    if let Err(e) = db.remove_journal_entry(&id.to_string()) {
+        tracing::error!(error = %e, "Could not clear journal entry");
+    }
+}
+
+/// Performs `moves` and applies `changes`, so that either both or neither of
+/// them take effect.
+pub fn commit(
+    db: &dyn MapRepository,
+    changes: Vec<Change>,
+    moves: Vec<FileMove>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if moves.is_empty() {
+        return Ok(db.apply(&changes, None)?);
+    }
+
+    // A move would replace the file, which undoing it couldn't bring back.
+    if let Some(taken) = moves.iter().find(|m| Path::new(&m.to).exists()) {
+        return Err(format!("\"{}\" already exists", taken.to).into());
+    }
+
+    let id = db.add_journal_entry(&JournalEntry {
+        moves: moves.clone(),
+        started_at: crate::get_current_time().unwrap_or_default(),
+    })?;
+
+    let mut done = 0;
+    let mut result: Result<(), Box<dyn std::error::Error>> = Ok(());
+    for file_move in &moves {
+        if let Err(e) = file_move.perform() {
+            result = Err(e.into());
+            break;
+        }
+        done += 1;
+    }
+
+    let result = result.and_then(|_| Ok(db.apply(&changes, Some(&id))?));
+
+    if result.is_err() {
+        // Include the move that failed, it may have left a partial copy.
+        // Moves that can't be undone stay in the journal for `recover`.
+        if undo_all(&moves[..(done + 1).min(moves.len())]) {
+            forget(db, &id);
+        }
+    }
+    result
+}
+
+/// Rolls back the moves of every operation that was interrupted before it
+/// was committed. Returns the number of operations that were rolled back.
+/// Operations with moves that can't be undone are kept, to try again on the
+/// next start.
+pub fn recover(
+    db: &dyn MapRepository,
+) -> Result<usize, crate::repository::RepositoryError> {
+    let mut rolled_back = 0;
+    for (id, entry) in db.journal_entries()? {
+        tracing::warn!(
+            started_at = entry.started_at,
+            moves = entry.moves.len(),
+            "Rolling back interrupted operation"
+        );
+        if undo_all(&entry.moves) {
+            db.remove_journal_entry(&id)?;
+            rolled_back += 1;
+        }
+    }
+    Ok(rolled_back)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite_repository::SqliteRepository;
+
+    fn state(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "mapmaster-journal-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (from, to) = (dir.join("test/a.map"), dir.join("easy/a.map"));
+        std::fs::create_dir_all(from.parent().unwrap()).unwrap();
+        std::fs::create_dir_all(to.parent().unwrap()).unwrap();
+        (dir, from, to)
+    }
+
+    fn open(dir: &Path) -> SqliteRepository {
+        SqliteRepository::open(&dir.join("maps.sqlite")).unwrap()
+    }
+
+    /// Writes the move to the journal of a new database in `dir` and
+    /// recovers from it. Returns whether it was rolled back.
+    fn recover_move(dir: &Path, from: &Path, to: &Path) -> bool {
+        let db = open(dir);
+        db.add_journal_entry(&JournalEntry {
+            moves: vec![FileMove::new(from, to)],
+            started_at: 0,
+        })
+        .unwrap();
+        let rolled_back = recover(&db).unwrap() == 1;
+        assert_eq!(db.journal_entries().unwrap().is_empty(), rolled_back);
+        rolled_back
+    }
+
+    #[test]
+    fn keeps_moves_that_cant_be_undone() {
+        let (dir, from, to) = state("none");
+        assert!(!recover_move(&dir, &from, &to));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_source_of_moves_that_didnt_happen() {
+        let (dir, from, to) = state("from");
+        std::fs::write(&from, "map").unwrap();
+        assert!(recover_move(&dir, &from, &to));
+        assert_eq!(std::fs::read(&from).unwrap(), b"map");
+        assert!(!to.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn moves_files_back() {
+        let (dir, from, to) = state("to");
+        std::fs::write(&to, "map").unwrap();
+        assert!(recover_move(&dir, &from, &to));
+        assert_eq!(std::fs::read(&from).unwrap(), b"map");
+        assert!(!to.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn removes_partial_copies() {
+        let (dir, from, to) = state("both");
+        std::fs::write(&from, "map").unwrap();
+        std::fs::write(&to, "ma").unwrap();
+        assert!(recover_move(&dir, &from, &to));
+        assert_eq!(std::fs::read(&from).unwrap(), b"map");
+        assert!(!to.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_moves_onto_existing_files() {
+        let (dir, from, to) = state("taken");
+        std::fs::write(&from, "map").unwrap();
+        std::fs::write(&to, "other map").unwrap();
+        let db = open(&dir);
+        let moves = vec![FileMove::new(&from, &to)];
+        assert!(commit(&db, Vec::new(), moves).is_err());
+        assert_eq!(std::fs::read(&from).unwrap(), b"map");
+        assert_eq!(std::fs::read(&to).unwrap(), b"other map");
+        assert!(db.journal_entries().unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn undoes_moves_of_failed_changes() {
+        let (dir, from, to) = state("failed");
+        std::fs::write(&from, "map").unwrap();
+        let db = open(&dir);
+        // There is no such map to update.
+        let map = crate::Map {
+            name: "a".to_string(),
+            difficulty: crate::Difficulty::Easy,
+            state: crate::MapState::Approved,
+            created_at: 0,
+            last_changed: 0,
+            sha256: None,
+            config: None,
+            lint: Vec::new(),
+            stats: None,
+            revisions: Vec::new(),
+            compatibility: None,
+        };
+        let change = Change::Update {
+            name: map.name.clone(),
+            map,
+        };
+        let moves = vec![FileMove::new(&from, &to)];
+        assert!(commit(&db, vec![change], moves).is_err());
+        assert_eq!(std::fs::read(&from).unwrap(), b"map");
+        assert!(!to.exists());
+        assert!(db.journal_entries().unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
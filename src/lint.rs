@@ -0,0 +1,550 @@
+//! Checks the game tiles of uploaded maps for the mechanical problems race
+//! maps are usually declined for.
+//!
+//! Every finding comes from a rule, whose severity can be changed with
+//! `--lint <rule>=<severity>`. Uploads with findings of severity `error`
+//! fail, the others are stored with the map. All rules are warnings unless
+//! raised, so maps that were accepted before keep being accepted.
+
+use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    str::FromStr,
+};
+use structsy_derive::PersistentEmbedded;
+use strum::{EnumString, IntoStaticStr};
+
+use crate::{
+    datafile::{self, Datafile},
+    error::{ApiError, ErrorCode},
+    mapres,
+    twmap::{self, Tele, Tiles},
+    CONFIG,
+};
+
+const SOLID: u8 = 1;
+const DEATH: u8 = 2;
+const UNHOOKABLE: u8 = 3;
+const START: u8 = 33;
+const FINISH: u8 = 34;
+/// The spawn entities: any team, red and blue.
+const SPAWNS: [u8; 3] = [192, 193, 194];
+
+/// Teleporters that send players to the `TELE_OUT` with their number.
+const TELE_INS: [u8; 4] = [10, 14, 15, 26];
+const TELE_OUT: u8 = 27;
+/// A checkpoint, which sends players that fall into a checkpoint teleporter
+/// later on to the `TELE_CHECK_OUT` with its number.
+const TELE_CHECK: u8 = 29;
+const TELE_CHECK_OUT: u8 = 30;
+
+#[derive(
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    PersistentEmbedded,
+    EnumString,
+    IntoStaticStr,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Rule {
+    /// The game layer of the map can't be read.
+    Unreadable,
+    NoSpawn,
+    NoStart,
+    NoFinish,
+    /// A teleporter or checkpoint has no tile to send players to.
+    TeleWithoutTarget,
+    /// No finish tile can be reached from a spawn, ignoring gravity.
+    UnreachableFinish,
+    /// An external image or sound isn't in `--mapres`. Publishing such maps
+    /// fails whatever the severity, see `mapres::check_publish`.
+    MissingMapres,
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    PersistentEmbedded,
+    EnumString,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Severity {
+    Off,
+    Warning,
+    Error,
+}
+
+/// A severity given for a rule with `--lint`.
+#[derive(Debug, Clone, Copy)]
+pub struct LintLevel {
+    pub rule: Rule,
+    pub severity: Severity,
+}
+
+impl FromStr for LintLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rule, severity) = s
+            .split_once('=')
+            .ok_or_else(|| format!("\"{}\" is not <rule>=<severity>", s))?;
+        Ok(LintLevel {
+            rule: rule
+                .parse()
+                .map_err(|_| format!("There is no lint rule \"{}\"", rule))?,
+            severity: severity.parse().map_err(|_| {
+                format!("\"{}\" is not off, warning or error", severity)
+            })?,
+        })
+    }
+}
+
+fn severity(levels: &[LintLevel], rule: Rule) -> Severity {
+    levels
+        .iter()
+        .rev()
+        .find(|l| l.rule == rule)
+        .map(|l| l.severity)
+        .unwrap_or(Severity::Warning)
+}
+
+#[derive(
+    Serialize, Deserialize, JsonSchema, PersistentEmbedded, Debug, Clone,
+)]
+#[serde(crate = "rocket::serde")]
+pub struct Finding {
+    pub rule: Rule,
+    pub severity: Severity,
+    pub message: String,
+    /// The tile the finding is about, counted from the top left.
+    pub x: Option<u32>,
+    pub y: Option<u32>,
+}
+
+/// The findings of a map, with the severities they are given.
+struct Findings<'a> {
+    levels: &'a [LintLevel],
+    found: Vec<Finding>,
+}
+
+impl<'a> Findings<'a> {
+    fn add(&mut self, rule: Rule, message: String, at: Option<(usize, usize)>) {
+        let severity = severity(self.levels, rule);
+        if severity != Severity::Off {
+            self.found.push(Finding {
+                rule,
+                severity,
+                message,
+                x: at.map(|(x, _)| x as u32),
+                y: at.map(|(_, y)| y as u32),
+            });
+        }
+    }
+}
+
+/// The game tiles of all layers at every position, the front layers last.
+struct Game {
+    width: usize,
+    height: usize,
+    layers: Vec<Tiles>,
+}
+
+impl Game {
+    fn tiles(&self, x: usize, y: usize) -> impl Iterator<Item = u8> + '_ {
+        self.layers
+            .iter()
+            .filter(move |l| x < l.width && y < l.height)
+            .map(move |l| l.get(x, y))
+    }
+
+    fn find(&self, matches: impl Fn(u8) -> bool) -> Vec<(usize, usize)> {
+        let mut found = Vec::new();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.tiles(x, y).any(&matches) {
+                    found.push((x, y));
+                }
+            }
+        }
+        found
+    }
+
+    fn passable(&self, x: usize, y: usize) -> bool {
+        !self
+            .tiles(x, y)
+            .any(|t| matches!(t, SOLID | DEATH | UNHOOKABLE))
+    }
+}
+
+/// The teleporters of all tele layers, with their positions.
+struct Teleporters(Vec<(usize, usize, Tele)>);
+
+impl Teleporters {
+    fn read(df: &Datafile) -> datafile::Result<Self> {
+        let mut teles = Vec::new();
+        for layer in twmap::tile_layers(df)? {
+            if layer.tele.is_none() {
+                continue;
+            }
+            for (i, tele) in
+                twmap::tele_tiles(df, &layer)?.into_iter().enumerate()
+            {
+                if tele.kind != 0 {
+                    teles.push((i % layer.width, i / layer.width, tele));
+                }
+            }
+        }
+        Ok(Teleporters(teles))
+    }
+
+    /// The targets of the teleporters, by their numbers.
+    fn targets(&self, kind: u8) -> HashMap<u8, Vec<(usize, usize)>> {
+        let mut targets = HashMap::<_, Vec<_>>::new();
+        for (x, y, tele) in &self.0 {
+            if tele.kind == kind {
+                targets.entry(tele.number).or_default().push((*x, *y));
+            }
+        }
+        targets
+    }
+}
+
+fn check_teleporters(teles: &Teleporters, findings: &mut Findings) {
+    let outs = teles.targets(TELE_OUT);
+    let check_outs = teles.targets(TELE_CHECK_OUT);
+    // Every number is reported once, teleporters are usually areas.
+    let mut reported = HashSet::new();
+    for (x, y, tele) in &teles.0 {
+        let missing = if TELE_INS.contains(&tele.kind) {
+            !outs.contains_key(&tele.number)
+        } else if tele.kind == TELE_CHECK {
+            !check_outs.contains_key(&tele.number)
+        } else {
+            false
+        };
+        if missing && reported.insert((tele.kind == TELE_CHECK, tele.number)) {
+            let what = if tele.kind == TELE_CHECK {
+                "Checkpoint"
+            } else {
+                "Teleporter"
+            };
+            findings.add(
+                Rule::TeleWithoutTarget,
+                format!("{} {} has no target.", what, tele.number),
+                Some((*x, *y)),
+            );
+        }
+    }
+}
+
+/// Whether a finish can be reached from a spawn by moving through tiles that
+/// don't block or kill, and by taking teleporters.
+fn finish_reachable(
+    game: &Game,
+    teles: &Teleporters,
+    spawns: &[(usize, usize)],
+) -> bool {
+    let outs = teles.targets(TELE_OUT);
+    let ins = teles
+        .0
+        .iter()
+        .filter(|(_, _, t)| TELE_INS.contains(&t.kind))
+        .map(|(x, y, t)| ((*x, *y), t.number))
+        .collect::<HashMap<_, _>>();
+
+    let mut visited = vec![false; game.width * game.height];
+    let mut queue = VecDeque::new();
+    for &(x, y) in spawns {
+        visited[y * game.width + x] = true;
+        queue.push_back((x, y));
+    }
+    while let Some((x, y)) = queue.pop_front() {
+        if game.tiles(x, y).any(|t| t == FINISH) {
+            return true;
+        }
+        let mut next = vec![
+            (x.wrapping_sub(1), y),
+            (x + 1, y),
+            (x, y.wrapping_sub(1)),
+            (x, y + 1),
+        ];
+        if let Some(targets) = ins.get(&(x, y)).and_then(|n| outs.get(n)) {
+            next.extend_from_slice(targets);
+        }
+        for (x, y) in next {
+            if x < game.width && y < game.height {
+                let seen = &mut visited[y * game.width + x];
+                if !*seen && game.passable(x, y) {
+                    *seen = true;
+                    queue.push_back((x, y));
+                }
+            }
+        }
+    }
+    false
+}
+
+/// The rules about the game and tele tiles.
+fn check_game(game: &Game, teles: &Teleporters, findings: &mut Findings) {
+    let spawns = game.find(|t| SPAWNS.contains(&t));
+    if spawns.is_empty() {
+        findings.add(Rule::NoSpawn, "The map has no spawn.".to_string(), None);
+    }
+    if game.find(|t| t == START).is_empty() {
+        findings.add(
+            Rule::NoStart,
+            "The map has no start line.".to_string(),
+            None,
+        );
+    }
+    let finishes = game.find(|t| t == FINISH);
+    if finishes.is_empty() {
+        findings.add(
+            Rule::NoFinish,
+            "The map has no finish line.".to_string(),
+            None,
+        );
+    }
+    check_teleporters(teles, findings);
+
+    if !spawns.is_empty()
+        && !finishes.is_empty()
+        && !finish_reachable(game, teles, &spawns)
+    {
+        findings.add(
+            Rule::UnreachableFinish,
+            "No finish tile can be reached from a spawn.".to_string(),
+            Some(finishes[0]),
+        );
+    }
+}
+
+fn check(file: &[u8], findings: &mut Findings) -> datafile::Result<()> {
+    let df = Datafile::parse(file)?;
+    let layers = twmap::game_tiles(&df)?;
+    let game = Game {
+        width: layers[0].width,
+        height: layers[0].height,
+        layers,
+    };
+    check_game(&game, &Teleporters::read(&df)?, findings);
+
+    match mapres::dependencies(&df) {
+        Ok(dependencies) => {
+            for missing in dependencies.missing() {
+                findings.add(
+                    Rule::MissingMapres,
+                    format!("The servers don't have the {}.", missing),
+                    None,
+                );
+            }
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "Could not check the mapres of a map")
+        }
+    }
+    Ok(())
+}
+
+fn lint_with(file: &[u8], levels: &[LintLevel]) -> Vec<Finding> {
+    let mut findings = Findings {
+        levels,
+        found: Vec::new(),
+    };
+    if let Err(e) = check(file, &mut findings) {
+        findings.add(
+            Rule::Unreadable,
+            format!("The game layer can't be read: {}", e),
+            None,
+        );
+    }
+    findings.found
+}
+
+/// Checks a map file with all rules that aren't turned off.
+pub fn lint(file: &[u8]) -> Vec<Finding> {
+    lint_with(file, &CONFIG.lint_levels)
+}
+
+/// Fails if a map has findings of severity `error`.
+pub fn enforce(name: &str, findings: &[Finding]) -> Result<(), ApiError> {
+    let errors = findings
+        .iter()
+        .filter(|f| f.severity == Severity::Error)
+        .map(|f| match (f.x, f.y) {
+            (Some(x), Some(y)) => format!("{} ({}, {})", f.message, x, y),
+            _ => f.message.clone(),
+        })
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(ApiError::new(
+        ErrorCode::LintFailed,
+        format!("{} can't be uploaded: {}", name, errors.join(" ")),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A game layer drawn with `#` for solid, `x` for death, `S` for spawns,
+    /// `s` for the start and `f` for the finish.
+    fn game(rows: &[&str]) -> Game {
+        let indices = rows
+            .iter()
+            .flat_map(|r| r.bytes())
+            .map(|b| match b {
+                b'#' => SOLID,
+                b'x' => DEATH,
+                b'S' => SPAWNS[0],
+                b's' => START,
+                b'f' => FINISH,
+                _ => 0,
+            })
+            .collect();
+        let layer = Tiles {
+            width: rows[0].len(),
+            height: rows.len(),
+            indices,
+        };
+        Game {
+            width: layer.width,
+            height: layer.height,
+            layers: vec![layer],
+        }
+    }
+
+    fn tele(x: usize, y: usize, kind: u8, number: u8) -> (usize, usize, Tele) {
+        (x, y, Tele { number, kind })
+    }
+
+    fn check(game: &Game, teles: Vec<(usize, usize, Tele)>) -> Vec<Finding> {
+        let mut findings = Findings {
+            levels: &[],
+            found: Vec::new(),
+        };
+        check_game(game, &Teleporters(teles), &mut findings);
+        findings.found
+    }
+
+    fn rules(findings: &[Finding]) -> Vec<Rule> {
+        findings.iter().map(|f| f.rule).collect()
+    }
+
+    #[test]
+    fn accepts_a_finished_map() {
+        let map = game(&["#####", "#Ssf#", "#####"]);
+        assert!(check(&map, Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn finds_missing_spawns_starts_and_finishes() {
+        let map = game(&["###", "#.#", "###"]);
+        assert_eq!(
+            rules(&check(&map, Vec::new())),
+            [Rule::NoSpawn, Rule::NoStart, Rule::NoFinish]
+        );
+    }
+
+    #[test]
+    fn follows_paths_around_walls() {
+        let around =
+            game(&["#######", "#S.#..#", "#s.#.f#", "#.....#", "#######"]);
+        assert!(check(&around, Vec::new()).is_empty());
+
+        let walled = game(&["#######", "#S.#..#", "#s.#.f#", "#######"]);
+        let findings = check(&walled, Vec::new());
+        assert_eq!(rules(&findings), [Rule::UnreachableFinish]);
+        assert_eq!((findings[0].x, findings[0].y), (Some(5), Some(2)));
+
+        let deadly = game(&["#######", "#Ss.xf#", "#######"]);
+        assert_eq!(
+            rules(&check(&deadly, Vec::new())),
+            [Rule::UnreachableFinish]
+        );
+    }
+
+    #[test]
+    fn follows_teleporters() {
+        let map = game(&["#######", "#Ss#.f#", "#######"]);
+        let teles = vec![tele(2, 1, TELE_INS[0], 3), tele(4, 1, TELE_OUT, 3)];
+        assert!(check(&map, teles).is_empty());
+
+        // Teleporters into walls and to other numbers don't help.
+        let teles = vec![
+            tele(2, 1, TELE_INS[0], 3),
+            tele(4, 1, TELE_OUT, 4),
+            tele(1, 0, TELE_OUT, 3),
+        ];
+        assert_eq!(rules(&check(&map, teles)), [Rule::UnreachableFinish]);
+    }
+
+    #[test]
+    fn finds_teleporters_without_targets() {
+        let map = game(&["#####", "#Ssf#", "#####"]);
+        let teles = vec![
+            tele(1, 0, TELE_INS[1], 5),
+            tele(2, 0, TELE_INS[1], 5),
+            tele(3, 0, TELE_CHECK, 5),
+            tele(1, 2, TELE_INS[2], 6),
+            tele(2, 2, TELE_OUT, 6),
+        ];
+        let findings = check(&map, teles);
+        let messages = findings.iter().map(|f| f.message.as_str());
+        assert_eq!(
+            messages.collect::<Vec<_>>(),
+            ["Teleporter 5 has no target.", "Checkpoint 5 has no target."]
+        );
+        assert_eq!((findings[0].x, findings[0].y), (Some(1), Some(0)));
+    }
+
+    #[test]
+    fn applies_the_severities_of_rules() {
+        let levels = [
+            "no_spawn=off".parse().unwrap(),
+            "no_finish=error".parse().unwrap(),
+        ];
+        let mut findings = Findings {
+            levels: &levels,
+            found: Vec::new(),
+        };
+        let map = game(&["###", "#.#", "###"]);
+        check_game(&map, &Teleporters(Vec::new()), &mut findings);
+        let severities = findings
+            .found
+            .iter()
+            .map(|f| (f.rule, f.severity))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            severities,
+            [
+                (Rule::NoStart, Severity::Warning),
+                (Rule::NoFinish, Severity::Error)
+            ]
+        );
+        assert!(enforce("map", &findings.found[..1]).is_ok());
+        assert!(enforce("map", &findings.found).is_err());
+    }
+
+    #[test]
+    fn finds_unreadable_maps() {
+        let findings = lint_with(b"not a map", &[]);
+        assert_eq!(rules(&findings), [Rule::Unreadable]);
+        assert_eq!(findings[0].severity, Severity::Warning);
+    }
+}
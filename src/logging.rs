@@ -0,0 +1,183 @@
+//! Structured logging, with every request tagged by an id.
+//!
+//! Each request gets an id, taken from its `x-request-id` header if it has a
+//! usable one. The id is sent back in the same header and in error bodies.
+//! The handlers run inside a span with the id, the operation, the api key and
+//! the map, so everything they log can be found by the id.
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    route::{Handler, Outcome},
+    Data, Request, Response, Route,
+};
+use std::{future::Future, time::Instant};
+use strum::EnumString;
+use tracing::{field, Instrument, Span};
+use tracing_subscriber::EnvFilter;
+
+pub const HEADER: &str = "x-request-id";
+
+rocket::tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+#[derive(EnumString, Debug, Clone, Copy)]
+#[strum(serialize_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Sends the logs to stderr, filtered by `MAPMASTER_LOG` (`info` if not set).
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_env("MAPMASTER_LOG")
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// The id of the request being handled, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Adds the map a request is about to its log lines.
+pub fn record_map(name: &str) {
+    Span::current().record("map", name);
+}
+
+/// Adds the name of the api key a request was sent with to its log lines.
+pub fn record_api_key(name: &str) {
+    Span::current().record("api_key", name);
+}
+
+struct RequestId(String);
+
+struct Started(Instant);
+
+/// Ids from other services are kept, as long as they can't mess up a log
+/// line.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The id of a request, which is made up on first use.
+pub fn id_of<'r>(request: &'r Request<'_>) -> &'r str {
+    &request
+        .local_cache(|| {
+            let id = request
+                .headers()
+                .get_one(HEADER)
+                .filter(|id| is_valid_id(id));
+            RequestId(id.map_or_else(
+                || uuid::Uuid::new_v4().to_string(),
+                ToString::to_string,
+            ))
+        })
+        .0
+}
+
+/// Gives every request its id, sends it back and logs the finished request.
+pub struct RequestLog;
+
+#[rocket::async_trait]
+impl Fairing for RequestLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| Started(Instant::now()));
+        id_of(request);
+    }
+
+    async fn on_response<'r>(
+        &self,
+        request: &'r Request<'_>,
+        response: &mut Response<'r>,
+    ) {
+        let id = id_of(request);
+        let started = request.local_cache(|| Started(Instant::now()));
+        response.set_raw_header(HEADER, id.to_string());
+        tracing::info!(
+            request_id = id,
+            method = request.method().as_str(),
+            uri = %request.uri(),
+            status = response.status().code,
+            duration_ms = started.0.elapsed().as_millis() as u64,
+            "Handled request"
+        );
+    }
+}
+
+/// Runs a handler inside the span of its request.
+#[derive(Clone)]
+struct Traced {
+    handler: Box<dyn Handler>,
+    operation: String,
+}
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(
+        &self,
+        request: &'r Request<'_>,
+        data: Data<'r>,
+    ) -> Outcome<'r> {
+        let id = id_of(request).to_string();
+        let span = request_span(&id, &self.operation);
+        REQUEST_ID
+            .scope(id, self.handler.handle(request, data).instrument(span))
+            .await
+    }
+}
+
+fn request_span(id: &str, operation: &str) -> Span {
+    tracing::info_span!(
+        "request",
+        request_id = %id,
+        operation = %operation,
+        api_key = field::Empty,
+        map = field::Empty,
+    )
+}
+
+/// Runs work a request left behind after it was answered, so it is logged
+/// under the id of the request.
+pub async fn follow_up<F: Future>(
+    id: String,
+    operation: &str,
+    api_key: &str,
+    future: F,
+) -> F::Output {
+    let span = request_span(&id, operation);
+    span.record("api_key", api_key);
+    REQUEST_ID.scope(id, future.instrument(span)).await
+}
+
+/// Makes the routes log inside the span of their request.
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            let operation = route.name.as_deref().unwrap_or("").to_string();
+            route.handler = Box::new(Traced {
+                handler: route.handler,
+                operation,
+            });
+            route
+        })
+        .collect()
+}
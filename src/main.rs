@@ -3,7 +3,9 @@ extern crate rocket;
 
 use lazy_static::lazy_static;
 use rocket::{
-    http::Status,
+    fs::NamedFile,
+    http::{ContentType, Status},
+    response::status::Accepted,
     serde::{json::Json, Deserialize, Serialize},
     State,
 };
@@ -11,49 +13,114 @@ use rocket_okapi::{
     openapi, openapi_get_routes, rapidoc::*, settings::UrlObject, swagger_ui::*,
 };
 use schemars::JsonSchema;
-use std::{path::Path, str::FromStr, time::SystemTime};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::SystemTime,
+};
 use structopt::StructOpt;
-use structsy::{Ref, Structsy, StructsyError, StructsyTx};
-use structsy_derive::{queries, Persistent, PersistentEmbedded};
-use strum::EnumString;
+use structsy_derive::{Persistent, PersistentEmbedded};
+use strum::{EnumIter, EnumString, IntoStaticStr};
 
 mod apikey;
-mod common;
+mod archive;
+mod backup;
+mod compat;
 mod config;
+mod datafile;
+mod diff;
+mod download;
+mod error;
+mod export;
+mod health;
+mod import;
+mod jobs;
+mod journal;
+mod lint;
+mod logging;
+mod map_config;
+mod mapres;
+mod metrics;
 mod options;
+mod preview;
+mod query;
+mod reconcile;
+mod repository;
+mod revisions;
+mod schema;
+mod search;
+mod sqlite_repository;
+mod stats;
+mod structsy_repository;
+mod twmap;
 
 use apikey::ApiKey;
+use compat::Compatibility;
 use config::Config;
-use options::Options;
+use error::{
+    ApiError, BodyErrors, ConfigErrors, CreateErrors, DependencyErrors,
+    DiffErrors, DifficultyErrors, ErrorBody, ErrorCode, ImportErrors,
+    JobErrors, ListErrors, MapErrors, NoErrors, PreviewErrors, RenameErrors,
+    RouteResult, TransitionErrors,
+};
+use journal::FileMove;
+use lint::Finding;
+use options::{Command, Options};
+use query::{Checked, List, Page};
+use repository::{Change, MapFilter, MapRepository, StatsFilter};
+use revisions::Revision;
+use stats::MapStats;
 
 lazy_static! {
     static ref CONFIG: Config = {
         let options = Options::from_args();
+        let backend = options.database_backend;
         Config {
-            apikeys: std::fs::read_to_string(options.apikeys)
-                .unwrap_or_default()
-                .lines()
-                .map(ToString::to_string)
-                .collect(),
+            apikeys: apikey::load(
+                &options.apikeys,
+                options.named_apikeys.as_deref(),
+            )
+            .unwrap_or_else(|e| panic!("could not read the api keys: {}", e)),
             test_map_folder: options.test_maps,
             public_map_folder: options.published_maps,
             dev: options.dev,
+            database_backend: backend,
+            database_path: options
+                .database
+                .unwrap_or_else(|| backend.default_path().into()),
+            backup_dir: options.backup_dir,
+            backup_interval_hours: options.backup_interval,
+            keep_daily_backups: options.keep_daily,
+            keep_weekly_backups: options.keep_weekly,
+            min_free_space_mib: options.min_free_space,
+            max_map_size: options.max_map_size,
+            max_archive_size: options.max_archive_size,
+            download_connect_timeout: options.download_connect_timeout,
+            download_read_timeout: options.download_read_timeout,
+            download_timeout: options.download_timeout,
+            allowed_hosts: options.allow_hosts,
+            denied_hosts: options.deny_hosts,
+            allow_private_downloads: options.allow_private_downloads,
+            max_redirects: options.max_redirects,
+            config_commands: options.config_commands,
+            preview_dir: options.preview_dir,
+            revision_dir: options.revision_dir,
+            lint_levels: options.lint_levels,
+            mapres: options.mapres,
+            compatibility: options.compatibility,
+            upload_workers: options.upload_workers,
         }
     };
 }
 
-#[derive(Serialize, Deserialize, JsonSchema)]
-#[serde(crate = "rocket::serde")]
-struct CustomError {
-    msg: String,
-    code: u16,
-}
-
-type CustomStatus = (Status, Json<CustomError>);
-
 // In a real application, this would likely be more complex.
 struct CustomState {
-    db: Structsy,
+    db: Arc<dyn MapRepository>,
+    jobs: Arc<jobs::Jobs>,
+    /// Held for as long as the server runs.
+    _database_lock: std::fs::File,
 }
 
 fn map_to_test_vote_string(map: &Map) -> String {
@@ -74,19 +141,18 @@ fn map_to_test_vote_string(map: &Map) -> String {
 }
 
 fn map_to_vote_string(map: &Map) -> String {
-    let base = &CONFIG.public_map_folder;
-    let folder = base.join(map.difficulty).join("flexreset.cfg");
     format!(
         "add_vote \"{}\" \"sv_reset_file \"{}\"; change_map \\\"{}/{}\\\"\"",
         map.name,
-        folder.to_string_lossy(),
+        map_config::reset_file(map).to_string_lossy(),
         map.difficulty.to_string().to_lowercase(),
         map.name,
     )
 }
 
 fn generate_test_votes(maps: &[Map]) -> String {
-    let votes = maps.iter()
+    let votes = maps
+        .iter()
         .map(map_to_test_vote_string)
         .collect::<Vec<_>>()
         .join("\n");
@@ -104,14 +170,29 @@ fn generate_published_votes(maps: &[Map]) -> String {
     text.join("\n")
 }
 
-fn update_votes(db: &Structsy) -> Result<(), CustomStatus> {
-    let query = db.query::<Map>().fetch();
+fn update_votes(db: &dyn MapRepository) -> Result<(), ApiError> {
+    let timer = metrics::VOTES_UPDATE_DURATION.start_timer();
+    let result = write_votes(db);
+    timer.observe_duration();
+    let label = if result.is_ok() { "success" } else { "failure" };
+    metrics::VOTES_UPDATES.with_label_values(&[label]).inc();
+    health::record_votes_update(
+        result
+            .as_ref()
+            .map(|_| ())
+            .map_err(|e| e.cause.clone().unwrap_or_else(|| e.msg.clone())),
+    );
+    result
+}
+
+fn write_votes(db: &dyn MapRepository) -> Result<(), ApiError> {
+    let maps = db.list(&MapFilter::default()).map_err(ApiError::internal)?;
     let mut test = Vec::new();
     let mut easy = Vec::new();
     let mut main = Vec::new();
     let mut hard = Vec::new();
     let mut insane = Vec::new();
-    for map in query.map(|(_id, map)| map) {
+    for map in maps {
         if [MapState::New, MapState::Approved, MapState::Declined]
             .contains(&map.state)
         {
@@ -134,7 +215,7 @@ fn update_votes(db: &Structsy) -> Result<(), CustomStatus> {
     insane.sort_by_key(Map::created_at);
 
     std::fs::create_dir_all(&CONFIG.test_map_folder)
-        .map_err(to_internal_server_error)?;
+        .map_err(ApiError::internal)?;
 
     let base = &CONFIG.public_map_folder;
     let easy_folder = base.join(Difficulty::Easy);
@@ -142,37 +223,45 @@ fn update_votes(db: &Structsy) -> Result<(), CustomStatus> {
     let hard_folder = base.join(Difficulty::Hard);
     let insane_folder = base.join(Difficulty::Insane);
 
-    std::fs::create_dir_all(&easy_folder).map_err(to_internal_server_error)?;
-    std::fs::create_dir_all(&main_folder).map_err(to_internal_server_error)?;
-    std::fs::create_dir_all(&hard_folder).map_err(to_internal_server_error)?;
-    std::fs::create_dir_all(&insane_folder)
-        .map_err(to_internal_server_error)?;
+    std::fs::create_dir_all(&easy_folder).map_err(ApiError::internal)?;
+    std::fs::create_dir_all(&main_folder).map_err(ApiError::internal)?;
+    std::fs::create_dir_all(&hard_folder).map_err(ApiError::internal)?;
+    std::fs::create_dir_all(&insane_folder).map_err(ApiError::internal)?;
 
     std::fs::write(
         CONFIG.test_map_folder.join("votes.cfg"),
         generate_test_votes(&test),
     )
-    .map_err(to_internal_server_error)?;
+    .map_err(ApiError::internal)?;
     std::fs::write(
         easy_folder.join("votes.cfg"),
         generate_published_votes(&easy),
     )
-    .map_err(to_internal_server_error)?;
+    .map_err(ApiError::internal)?;
     std::fs::write(
         main_folder.join("votes.cfg"),
         generate_published_votes(&main),
     )
-    .map_err(to_internal_server_error)?;
+    .map_err(ApiError::internal)?;
     std::fs::write(
         hard_folder.join("votes.cfg"),
         generate_published_votes(&hard),
     )
-    .map_err(to_internal_server_error)?;
+    .map_err(ApiError::internal)?;
     std::fs::write(
         insane_folder.join("votes.cfg"),
         generate_published_votes(&insane),
     )
-    .map_err(to_internal_server_error)?;
+    .map_err(ApiError::internal)?;
+
+    for (folder, maps) in [
+        (&easy_folder, &easy),
+        (&main_folder, &main),
+        (&hard_folder, &hard),
+        (&insane_folder, &insane),
+    ] {
+        map_config::write_files(folder, maps).map_err(ApiError::internal)?;
+    }
 
     Ok(())
 }
@@ -185,6 +274,8 @@ fn update_votes(db: &Structsy) -> Result<(), CustomStatus> {
     PersistentEmbedded,
     Debug,
     EnumString,
+    EnumIter,
+    IntoStaticStr,
     PartialEq,
     Clone,
     Copy,
@@ -231,6 +322,8 @@ impl AsRef<Path> for Difficulty {
     PersistentEmbedded,
     Debug,
     EnumString,
+    EnumIter,
+    IntoStaticStr,
     PartialEq,
     Clone,
     Copy,
@@ -244,7 +337,7 @@ enum MapState {
     Published,
 }
 
-#[derive(Serialize, Deserialize, JsonSchema, Persistent, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Persistent, Debug, Clone)]
 struct Map {
     #[index]
     name: String,
@@ -252,6 +345,20 @@ struct Map {
     state: MapState,
     created_at: u64,
     last_changed: u64,
+    /// The hash of the map file, taken when it was uploaded.
+    sha256: Option<String>,
+    /// Commands run when the published map is voted, one per line.
+    config: Option<String>,
+    /// The findings of the lint, taken when the map file was uploaded.
+    #[serde(default)]
+    lint: Vec<Finding>,
+    /// The statistics of the map file, if it could be read.
+    stats: Option<MapStats>,
+    /// The uploaded map files, the oldest first.
+    #[serde(default)]
+    revisions: Vec<Revision>,
+    /// The oldest servers the map file runs on, if it could be read.
+    compatibility: Option<Compatibility>,
 }
 
 impl Map {
@@ -260,121 +367,226 @@ impl Map {
     }
 }
 
-#[queries(Map)]
-trait MapByName {
-    fn by_name(self, name: &str) -> Self;
-    fn by_state(self, #[allow(unused)] state: &MapState) -> Self;
-    fn by_difficulty(self, #[allow(unused)] difficulty: &Difficulty) -> Self;
+#[derive(FromFormField, JsonSchema, Debug, Clone, Copy)]
+enum SortKey {
+    #[field(value = "name")]
+    Name,
+    #[field(value = "created_at")]
+    CreatedAt,
+    #[field(value = "last_changed")]
+    LastChanged,
 }
 
-#[queries(Map)]
-trait MapByState {}
-
-fn find_map(db: &Structsy, name: &str) -> Option<(Ref<Map>, Map)> {
-    let query = db.query::<Map>().by_name(&name.to_lowercase());
-    query.fetch().next()
+#[derive(FromFormField, JsonSchema, Debug, Clone, Copy)]
+enum SortOrder {
+    #[field(value = "asc")]
+    Asc,
+    #[field(value = "desc")]
+    Desc,
 }
 
-enum Either<L, R> {
-    Left(L),
-    Right(R),
-}
-
-fn get_current_time(
-) -> Result<u64, Either<StructsyError, Box<dyn std::error::Error>>> {
+fn get_current_time() -> Result<u64, std::time::SystemTimeError> {
     Ok(SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .map_err(|e| Either::Right(e.into()))?
+        .duration_since(SystemTime::UNIX_EPOCH)?
         .as_secs())
 }
 
+/// Creates the record of an uploaded map, or updates it if the map was
+/// uploaded before.
 fn add_or_update_map(
-    db: &Structsy,
-    name: String,
-    difficulty: Difficulty,
-    state: MapState,
-) -> Result<(), Either<StructsyError, Box<dyn std::error::Error>>> {
-    let now = get_current_time()?;
+    db: &dyn MapRepository,
+    upload: &archive::ArchiveMap,
+) -> Result<Map, ApiError> {
+    let now = get_current_time().map_err(ApiError::internal)?;
+    let sha256 = reconcile::sha256(&upload.data);
     let my_data = Map {
-        name: name.to_lowercase(),
-        difficulty,
-        state,
+        name: upload.name.to_lowercase(),
+        difficulty: upload.difficulty,
+        state: MapState::New,
         created_at: now,
         last_changed: now,
+        sha256: Some(sha256.clone()),
+        config: upload.config.clone(),
+        lint: upload.lint.clone(),
+        stats: upload.stats.clone(),
+        compatibility: upload.compatibility,
+        revisions: vec![Revision {
+            sha256: sha256.clone(),
+            uploaded_at: now,
+        }],
     };
-    match find_map(db, &my_data.name) {
-        None => {
-            let mut tx = db.begin().map_err(Either::Left)?;
-            tx.insert(&my_data).map_err(Either::Left)?;
-            tx.commit().map_err(Either::Left)?;
-        }
-        Some((id, map)) => {
-            let mut tx = db.begin().map_err(Either::Left)?;
-            tx.update(
-                &id,
-                &Map {
-                    difficulty,
+    let change = match db.find(&my_data.name).map_err(ApiError::internal)? {
+        None => Change::Insert(my_data.clone()),
+        Some(mut map) => {
+            revisions::add(&mut map.revisions, &sha256, now);
+            Change::Update {
+                name: map.name.clone(),
+                map: Map {
+                    difficulty: my_data.difficulty,
                     last_changed: now,
+                    sha256: my_data.sha256.clone(),
+                    // A new file without a config keeps the one of the map.
+                    config: my_data.config.clone().or(map.config),
+                    lint: my_data.lint.clone(),
+                    stats: my_data.stats.clone(),
+                    compatibility: my_data.compatibility,
                     ..map
                 },
-            )
-            .map_err(Either::Left)?;
-            tx.commit().map_err(Either::Left)?
+            }
         }
-    }
+    };
+    db.apply(&[change], None).map_err(ApiError::internal)?;
 
-    Ok(())
+    let map = db.find(&my_data.name).map_err(ApiError::internal)?;
+    map.ok_or_else(|| ApiError::internal("the map vanished after storing it"))
+}
+
+/// Runs work that reads files or takes long on the blocking threads, so it
+/// doesn't hold up other requests on the runtime.
+async fn blocking<T, F>(work: F) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, ApiError> + Send + 'static,
+{
+    let span = tracing::Span::current();
+    rocket::tokio::task::spawn_blocking(move || span.in_scope(work))
+        .await
+        .map_err(ApiError::internal)?
 }
 
-fn move_map<P: AsRef<Path>>(from: P, to: P) -> Result<(), std::io::Error> {
-    let p = to.as_ref();
-    if let Some(parent) = p.parent() {
-        std::fs::create_dir_all(parent)?;
+fn map_file_path(map: &Map) -> PathBuf {
+    let file_name = format!("{}.map", map.name);
+    if map.state == MapState::Published {
+        CONFIG
+            .public_map_folder
+            .join(map.difficulty)
+            .join(file_name)
+    } else {
+        CONFIG.test_map_folder.join(file_name)
+    }
+}
+
+fn normalize_map_name(name: &str) -> String {
+    let name = name.to_lowercase();
+    if name.ends_with(".map") {
+        name[0..name.len() - 4].to_string()
+    } else {
+        name
     }
-    std::fs::copy(&from, to)?;
-    std::fs::remove_file(from)
 }
 
+#[allow(clippy::too_many_arguments)]
 #[openapi]
-#[get("/list?<name>&<map_state>&<difficulty>")]
+#[get(
+    "/list?<name>&<map_state>&<difficulty>&<created_after>&<created_before>\
+     &<changed_after>&<changed_before>&<min_file_size>&<max_file_size>\
+     &<min_embedded_image_size>&<max_embedded_image_size>&<sort>&<order>\
+     &<offset>&<limit>"
+)]
 fn list_maps(
     _key: ApiKey,
     state: &State<CustomState>,
     name: Option<String>,
-    map_state: Option<MapState>,
-    difficulty: Option<Difficulty>,
-) -> Json<Vec<Map>> {
-    let query = state.db.query::<Map>();
-
-    let query = if let Some(name) = name {
-        query.by_name(&name)
-    } else {
-        query
+    map_state: Option<List<MapState>>,
+    difficulty: Option<List<Difficulty>>,
+    created_after: Option<u64>,
+    created_before: Option<u64>,
+    changed_after: Option<u64>,
+    changed_before: Option<u64>,
+    min_file_size: Option<u64>,
+    max_file_size: Option<u64>,
+    min_embedded_image_size: Option<u64>,
+    max_embedded_image_size: Option<u64>,
+    sort: Option<Checked<SortKey>>,
+    order: Option<Checked<SortOrder>>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+) -> RouteResult<Page<Map>, ListErrors> {
+    let filter = MapFilter {
+        name: name.map(|n| n.to_lowercase()),
+        states: match map_state {
+            Some(states) => states.get("map_state")?,
+            None => Vec::new(),
+        },
+        difficulties: match difficulty {
+            Some(difficulties) => difficulties.get("difficulty")?,
+            None => Vec::new(),
+        },
+        created_after,
+        created_before,
+        changed_after,
+        changed_before,
+        sort: sort.map(|s| s.get("sort")).transpose()?,
+        order: order.map(|o| o.get("order")).transpose()?,
+        stats: StatsFilter {
+            min_file_size,
+            max_file_size,
+            min_embedded_image_size,
+            max_embedded_image_size,
+        },
+        offset: offset.unwrap_or_default(),
+        limit,
     };
+    let items = state.db.list(&filter).map_err(ApiError::internal)?;
+    let total = state.db.count(&filter).map_err(ApiError::internal)?;
+    Ok(Page { items, total })
+}
 
-    let values = query.into_iter().filter_map(|(_id, map)| {
-        if let Some(map_state) = map_state {
-            if map.state != map_state {
-                return None;
-            }
-        };
+fn search_maps_by_name(
+    db: &dyn MapRepository,
+    q: &str,
+) -> Result<Vec<(search::Score, Map)>, repository::RepositoryError> {
+    let maps = db.list(&MapFilter::default())?;
+    Ok(search::rank(q, maps, |map| vec![map.name.clone()]))
+}
 
-        if let Some(difficulty) = difficulty {
-            if map.difficulty != difficulty {
-                return None;
-            }
-        };
+fn suggest_map_names(db: &dyn MapRepository, name: &str) -> Vec<String> {
+    search_maps_by_name(db, name)
+        .unwrap_or_default()
+        .into_iter()
+        .take(3)
+        .map(|(_score, map)| map.name)
+        .collect()
+}
 
-        Some(map)
-    });
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct SearchResult {
+    #[serde(rename = "match")]
+    kind: search::MatchKind,
+    /// The edit distance for fuzzy matches, or the number of additional
+    /// characters for prefix and substring matches.
+    distance: usize,
+    map: Map,
+}
 
-    values.collect::<Vec<_>>().into()
+#[openapi]
+#[get("/search?<q>&<limit>")]
+fn search_maps(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    q: &str,
+    limit: Option<usize>,
+) -> RouteResult<Json<Vec<SearchResult>>, NoErrors> {
+    Ok(search_maps_by_name(state.db.as_ref(), q)
+        .map_err(ApiError::internal)?
+        .into_iter()
+        .take(limit.unwrap_or(20))
+        .map(|(score, map)| SearchResult {
+            kind: score.kind,
+            distance: score.distance,
+            map,
+        })
+        .collect::<Vec<_>>()
+        .into())
 }
 
 #[derive(Deserialize, JsonSchema)]
 #[serde(crate = "rocket::serde")]
 struct CreateMapData<'r> {
-    name: &'r str,
+    /// The name of the map. Without it, the map is named after the file in
+    /// the url. The maps of an archive are named after its entries.
+    name: Option<&'r str>,
     difficulty: &'r str,
     url: &'r str,
 }
@@ -392,48 +604,155 @@ struct JustTheMapName<'r> {
     name: &'r str,
 }
 
-fn to_bad_request<T: ToString>(e: T) -> CustomStatus {
-    eprintln!("{}", e.to_string());
-    (
-        Status::BadRequest,
-        Json(CustomError {
-            msg: "Something went wrong on client side!".to_string(),
-            code: Status::BadRequest.code,
-        }),
-    )
+#[derive(Deserialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct RenameMapData<'r> {
+    new_name: &'r str,
 }
 
-fn to_custom_bad_request(msg: String) -> CustomStatus {
-    eprintln!("{}", msg);
-    (
-        Status::BadRequest,
-        Json(CustomError {
-            msg,
-            code: Status::BadRequest.code,
-        }),
-    )
+fn to_map_not_found_error(db: &dyn MapRepository, name: &str) -> ApiError {
+    let suggestions = suggest_map_names(db, name);
+    let msg = if suggestions.is_empty() {
+        "Map not found!".to_string()
+    } else {
+        format!("Map not found! Did you mean \"{}\"?", suggestions[0])
+    };
+    ApiError::new(ErrorCode::MapNotFound, msg).with_suggestions(suggestions)
 }
 
-fn to_internal_server_error<T: ToString>(e: T) -> CustomStatus {
-    eprintln!("{}", e.to_string());
-    (
-        Status::InternalServerError,
-        Json(CustomError {
-            msg: "Something went wrong on server side!".to_string(),
-            code: Status::InternalServerError.code,
-        }),
-    )
+fn parse_difficulty(difficulty: &str) -> Result<Difficulty, ApiError> {
+    Difficulty::from_str(difficulty).map_err(|_| {
+        ApiError::new(
+            ErrorCode::InvalidDifficulty,
+            format!("\"{}\" is not a difficulty!", difficulty),
+        )
+    })
 }
 
-fn to_map_not_found_error<T: ToString>(e: T) -> CustomStatus {
-    eprintln!("{}", e.to_string());
-    (
-        Status::NotFound,
-        Json(CustomError {
-            msg: "Map not found!".to_string(),
-            code: Status::NotFound.code,
-        }),
-    )
+/// Checks that a normalized name can be used as a file name.
+/// Checks a normalized map name. Names end up in file paths and unescaped in
+/// the commands of votes.cfg, so only a few characters are safe.
+fn check_map_name(name: &str, given: &str) -> Result<(), ApiError> {
+    let safe = |c: char| {
+        c.is_ascii_lowercase() || c.is_ascii_digit() || "_.-".contains(c)
+    };
+    if name.is_empty() || !name.chars().all(safe) {
+        return Err(ApiError::new(
+            ErrorCode::InvalidName,
+            format!(
+                "\"{}\" is not a valid map name, it may only contain \
+                 letters, digits, \"_\", \".\" and \"-\"!",
+                given
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// A change of state or metadata that can be applied to a single map.
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy)]
+#[serde(crate = "rocket::serde")]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum MapOperation {
+    Approve,
+    Decline,
+    Publish,
+    Recall,
+    ChangeDifficulty { difficulty: Difficulty },
+}
+
+fn check_transition(
+    from: MapState,
+    allowed: &[MapState],
+    to: MapState,
+) -> Result<MapState, ApiError> {
+    if allowed.contains(&from) {
+        Ok(to)
+    } else if from == to {
+        Err(ApiError::new(
+            ErrorCode::InvalidTransition,
+            format!(
+                "This map is already {}!",
+                format!("{:?}", to).to_lowercase()
+            ),
+        ))
+    } else {
+        Err(ApiError::new(
+            ErrorCode::InvalidTransition,
+            format!("Cannot go from state {:?} to {:?}!", from, to),
+        ))
+    }
+}
+
+impl MapOperation {
+    /// Returns the map as it looks after this operation, or an error if the
+    /// operation is not allowed in the current state of the map.
+    fn apply(&self, map: &Map, now: u64) -> Result<Map, ApiError> {
+        use MapState::*;
+        let (state, difficulty) = match *self {
+            MapOperation::Approve => (
+                check_transition(map.state, &[Declined, New], Approved)?,
+                map.difficulty,
+            ),
+            //TODO: Delete Map after 3Days from all Testservers
+            MapOperation::Decline => (
+                check_transition(map.state, &[Approved, New], Declined)?,
+                map.difficulty,
+            ),
+            MapOperation::Publish => {
+                let state =
+                    check_transition(map.state, &[Approved], Published)?;
+                mapres::check_publish(map)?;
+                compat::check_map(map, map.difficulty)?;
+                (state, map.difficulty)
+            }
+            MapOperation::Recall => (New, map.difficulty),
+            MapOperation::ChangeDifficulty { difficulty } => {
+                compat::check_map(map, difficulty)?;
+                (map.state, difficulty)
+            }
+        };
+        Ok(Map {
+            state,
+            difficulty,
+            last_changed: now,
+            ..map.clone()
+        })
+    }
+}
+
+/// The file move needed to put a map where it belongs after it was updated.
+fn relocation(from: &Map, to: &Map) -> Option<FileMove> {
+    let source = map_file_path(from);
+    let target = map_file_path(to);
+    if source != target {
+        Some(FileMove::new(source, target))
+    } else {
+        None
+    }
+}
+
+fn run_operation(
+    db: &dyn MapRepository,
+    name: &str,
+    operation: MapOperation,
+) -> Result<(), ApiError> {
+    logging::record_map(name);
+    if let Some(map) = db.find(name).map_err(ApiError::internal)? {
+        let now = get_current_time().map_err(ApiError::internal)?;
+        let updated = operation.apply(&map, now)?;
+
+        let moves = relocation(&map, &updated).into_iter().collect();
+        let change = Change::Update {
+            name: map.name,
+            map: updated,
+        };
+        journal::commit(db, vec![change], moves).map_err(ApiError::internal)?;
+        update_votes(db)?;
+        Ok(())
+    } else {
+        Err(to_map_not_found_error(db, name))
+    }
 }
 
 #[openapi]
@@ -442,289 +761,863 @@ async fn recall_map(
     _key: ApiKey,
     state: &State<CustomState>,
     data: Json<JustTheMapName<'_>>,
-) -> Result<(), CustomStatus> {
-    if let Some((id, map)) = find_map(&state.db, data.name) {
-        let mut tx = state.db.begin().map_err(to_internal_server_error)?;
-        let map_name = format!("{}.map", map.name);
-        tx.update(
-            &id,
-            &Map {
-                state: MapState::New,
-                last_changed: get_current_time()
-                    .map_err(either_to_custom_status)?,
-                ..map
+) -> RouteResult<(), TransitionErrors> {
+    Ok(run_operation(
+        state.db.as_ref(),
+        data.name,
+        MapOperation::Recall,
+    )?)
+}
+
+#[openapi]
+#[post("/decline", format = "json", data = "<data>")]
+async fn decline_map(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    data: Json<JustTheMapName<'_>>,
+) -> RouteResult<(), TransitionErrors> {
+    Ok(run_operation(
+        state.db.as_ref(),
+        data.name,
+        MapOperation::Decline,
+    )?)
+}
+
+#[openapi]
+#[post("/publish", format = "json", data = "<data>")]
+async fn publish_map(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    data: Json<JustTheMapName<'_>>,
+) -> RouteResult<(), TransitionErrors> {
+    Ok(run_operation(
+        state.db.as_ref(),
+        data.name,
+        MapOperation::Publish,
+    )?)
+}
+
+#[openapi]
+#[post("/approve", format = "json", data = "<data>")]
+async fn approve_map(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    data: Json<JustTheMapName<'_>>,
+) -> RouteResult<(), TransitionErrors> {
+    Ok(run_operation(
+        state.db.as_ref(),
+        data.name,
+        MapOperation::Approve,
+    )?)
+}
+
+#[openapi]
+#[post("/change_difficulty", format = "json", data = "<data>")]
+async fn change_map_difficulty(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    data: Json<ChangeMapDifficultyData<'_>>,
+) -> RouteResult<(), DifficultyErrors> {
+    let difficulty = parse_difficulty(data.difficulty)?;
+    Ok(run_operation(
+        state.db.as_ref(),
+        data.name,
+        MapOperation::ChangeDifficulty { difficulty },
+    )?)
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct BulkOperation {
+    name: String,
+    #[serde(flatten)]
+    operation: MapOperation,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct BulkData {
+    /// If set, nothing is applied unless every operation is valid.
+    #[serde(default)]
+    atomic: bool,
+    operations: Vec<BulkOperation>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct BulkItemResult {
+    name: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+    /// The map as it looks after the operation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    map: Option<Map>,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct BulkResult {
+    /// Whether any changes were written.
+    applied: bool,
+    results: Vec<BulkItemResult>,
+}
+
+/// The outcome of every operation of a bulk request, before anything is
+/// written.
+struct BulkPlan {
+    results: Vec<BulkItemResult>,
+    /// The maps changed by the operations that succeeded, as they were and
+    /// as they will be.
+    updated: Vec<(Map, Map)>,
+}
+
+fn plan_bulk(
+    db: &dyn MapRepository,
+    operations: &[BulkOperation],
+    now: u64,
+) -> Result<BulkPlan, ApiError> {
+    // Validate everything against the state the maps will have after the
+    // previous operations, so e.g. approve followed by publish works.
+    let mut originals = Vec::<Map>::new();
+    let mut current = HashMap::<String, Map>::new();
+    let mut results = Vec::new();
+    for BulkOperation { name, operation } in operations {
+        let name = normalize_map_name(name);
+        if !current.contains_key(&name) {
+            if let Some(map) = db.find(&name).map_err(ApiError::internal)? {
+                current.insert(name.clone(), map.clone());
+                originals.push(map);
+            }
+        }
+
+        let result = match current.get(&name) {
+            Some(map) => operation.apply(map, now),
+            None => Err(to_map_not_found_error(db, &name)),
+        };
+        results.push(match result {
+            Ok(map) => {
+                current.insert(name.clone(), map.clone());
+                BulkItemResult {
+                    name,
+                    ok: true,
+                    error: None,
+                    map: Some(map),
+                }
+            }
+            Err(error) => BulkItemResult {
+                name,
+                ok: false,
+                error: Some(error.body(logging::request_id())),
+                map: None,
             },
-        )
-        .map_err(to_internal_server_error)?;
+        });
+    }
+
+    // Failed operations never touched `current`, so it only contains the
+    // effects of the operations that succeeded.
+    let updated = originals
+        .into_iter()
+        .filter(|map| results.iter().any(|r| r.ok && r.name == map.name))
+        .map(|map| {
+            let updated = current[&map.name].clone();
+            (map, updated)
+        })
+        .collect();
+    Ok(BulkPlan { results, updated })
+}
+
+#[openapi]
+#[post("/bulk", format = "json", data = "<data>")]
+async fn bulk_operations(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    data: Json<BulkData>,
+) -> RouteResult<Json<BulkResult>, BodyErrors> {
+    let db = state.db.as_ref();
+    let now = get_current_time().map_err(ApiError::internal)?;
+    let BulkPlan { results, updated } = plan_bulk(db, &data.operations, now)?;
+
+    let failed = results.iter().any(|r| !r.ok);
+    if data.atomic && failed {
+        return Ok(Json(BulkResult {
+            applied: false,
+            results,
+        }));
+    }
+
+    let mut changes = Vec::new();
+    let mut moves = Vec::new();
+    for (original, map) in &updated {
+        moves.extend(relocation(original, map));
+        changes.push(Change::Update {
+            name: original.name.clone(),
+            map: map.clone(),
+        });
+    }
+    journal::commit(db, changes, moves).map_err(ApiError::internal)?;
+
+    update_votes(db)?;
 
-        if map.state == MapState::Published {
-            let source_dir = CONFIG.public_map_folder.join(map.difficulty);
-            let target_dir = &CONFIG.test_map_folder;
+    Ok(Json(BulkResult {
+        applied: !updated.is_empty(),
+        results,
+    }))
+}
 
-            std::fs::create_dir_all(&target_dir)
-                .map_err(to_internal_server_error)?;
-            move_map(source_dir.join(&map_name), target_dir.join(&map_name))
-                .map_err(to_internal_server_error)?;
+#[openapi]
+#[get("/maps/<name>")]
+fn get_map(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    name: &str,
+) -> RouteResult<Json<Map>, MapErrors> {
+    logging::record_map(name);
+    if let Some(map) = state.db.find(name).map_err(ApiError::internal)? {
+        Ok(Json(map))
+    } else {
+        Err(to_map_not_found_error(state.db.as_ref(), name).into())
+    }
+}
+
+#[openapi]
+#[delete("/maps/<name>")]
+async fn delete_map(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    name: &str,
+) -> RouteResult<(), MapErrors> {
+    logging::record_map(name);
+    if let Some(map) = state.db.find(name).map_err(ApiError::internal)? {
+        state
+            .db
+            .apply(&[Change::Delete(map.name.clone())], None)
+            .map_err(ApiError::internal)?;
+
+        // Only remove the file once the record is gone, a leftover file is
+        // easier to clean up than a record without one.
+        let path = map_file_path(&map);
+        if path.exists() {
+            std::fs::remove_file(path).map_err(ApiError::internal)?;
         }
-        tx.commit().map_err(to_internal_server_error)?;
-        update_votes(&state.db)?;
+        update_votes(state.db.as_ref())?;
         Ok(())
     } else {
-        Err(to_map_not_found_error(format!(
-            "Map \"{}\" not found!",
-            data.name
-        )))
+        Err(to_map_not_found_error(state.db.as_ref(), name).into())
     }
 }
 
 #[openapi]
-#[post("/decline", format = "json", data = "<data>")]
-async fn decline_map(
+#[post("/maps/<name>/rename", format = "json", data = "<data>")]
+async fn rename_map(
     _key: ApiKey,
     state: &State<CustomState>,
-    data: Json<JustTheMapName<'_>>,
-) -> Result<(), CustomStatus> {
-    //TODO: Delete Map after 3Days from all Testservers
-    if let Some((id, map)) = find_map(&state.db, &data.name.to_lowercase()) {
-        if [MapState::Approved, MapState::New].contains(&map.state) {
-            let mut tx = state.db.begin().map_err(to_internal_server_error)?;
-            tx.update(
-                &id,
-                &Map {
-                    state: MapState::Declined,
-                    last_changed: get_current_time()
-                        .map_err(either_to_custom_status)?,
-                    ..map
-                },
+    name: &str,
+    data: Json<RenameMapData<'_>>,
+) -> RouteResult<(), RenameErrors> {
+    logging::record_map(name);
+    let new_name = normalize_map_name(data.new_name);
+    check_map_name(&new_name, data.new_name)?;
+
+    if let Some(map) = state.db.find(name).map_err(ApiError::internal)? {
+        // Names are lowercase, so renaming `foo` to `Foo` keeps the name.
+        if new_name == map.name {
+            return Ok(());
+        }
+        let existing = state.db.find(&new_name).map_err(ApiError::internal)?;
+        if existing.is_some() {
+            return Err(ApiError::new(
+                ErrorCode::NameTaken,
+                format!("A map named \"{}\" already exists!", new_name),
             )
-            .map_err(to_internal_server_error)?;
-            tx.commit().map_err(to_internal_server_error)?;
-            update_votes(&state.db)?;
-            Ok(())
-        } else if map.state == MapState::Declined {
-            Err(to_custom_bad_request(
-                "This map is already declined!".to_string(),
-            ))
-        } else {
-            Err(to_custom_bad_request(format!(
-                "Cannot go from state {:?} to {:?}!",
-                map.state,
-                MapState::Declined
-            )))
+            .into());
+        }
+
+        let renamed = Map {
+            name: new_name,
+            last_changed: get_current_time().map_err(ApiError::internal)?,
+            ..map.clone()
+        };
+        let source = map_file_path(&map);
+        let target = map_file_path(&renamed);
+        if target.exists() {
+            return Err(ApiError::new(
+                ErrorCode::NameTaken,
+                format!(
+                    "A map file named \"{}.map\" already exists!",
+                    renamed.name
+                ),
+            )
+            .into());
         }
+
+        let change = Change::Update {
+            name: map.name.clone(),
+            map: renamed,
+        };
+        journal::commit(
+            state.db.as_ref(),
+            vec![change],
+            vec![FileMove::new(source, target)],
+        )
+        .map_err(ApiError::internal)?;
+        update_votes(state.db.as_ref())?;
+        Ok(())
     } else {
-        Err(to_map_not_found_error(format!(
-            "Map \"{}\" not found!",
-            data.name
-        )))
+        Err(to_map_not_found_error(state.db.as_ref(), name).into())
     }
 }
 
+#[derive(Deserialize, JsonSchema)]
+#[serde(crate = "rocket::serde")]
+struct MapConfigData {
+    /// The commands, one per line. `null` or an empty config removes it.
+    config: Option<String>,
+}
+
+/// Sets the config snippet of a map, which is run when the published map is
+/// voted.
 #[openapi]
-#[post("/publish", format = "json", data = "<data>")]
-async fn publish_map(
+#[put("/maps/<name>/config", format = "json", data = "<data>")]
+async fn set_map_config(
     _key: ApiKey,
     state: &State<CustomState>,
-    data: Json<JustTheMapName<'_>>,
-) -> Result<(), CustomStatus> {
-    if let Some((id, map)) = find_map(&state.db, data.name) {
-        if MapState::Approved == map.state {
-            let mut tx = state.db.begin().map_err(to_internal_server_error)?;
-            let map_name = format!("{}.map", map.name);
-            tx.update(
-                &id,
-                &Map {
-                    state: MapState::Published,
-                    last_changed: get_current_time()
-                        .map_err(either_to_custom_status)?,
-                    ..map
-                },
-            )
-            .map_err(to_internal_server_error)?;
-
-            let source_dir = &CONFIG.test_map_folder;
-            let target_dir = CONFIG.public_map_folder.join(map.difficulty);
-
-            std::fs::create_dir_all(&target_dir)
-                .map_err(to_internal_server_error)?;
-            move_map(source_dir.join(&map_name), target_dir.join(&map_name))
-                .map_err(to_internal_server_error)?;
-            tx.commit().map_err(to_internal_server_error)?;
-            update_votes(&state.db)?;
-            Ok(())
-        } else if MapState::Published == map.state {
-            Err(to_custom_bad_request(
-                "This map is already published!".to_string(),
-            ))
-        } else {
-            Err(to_custom_bad_request(format!(
-                "Cannot go from state {:?} to {:?}!",
-                map.state,
-                MapState::Published
-            )))
+    name: &str,
+    data: Json<MapConfigData>,
+) -> RouteResult<Json<Map>, ConfigErrors> {
+    logging::record_map(name);
+    let config = data.into_inner().config.filter(|c| !c.trim().is_empty());
+    if let Some(config) = &config {
+        map_config::validate(config)
+            .map_err(|e| ApiError::new(ErrorCode::InvalidConfig, e))?;
+    }
+
+    let map = match state.db.find(name).map_err(ApiError::internal)? {
+        Some(map) => map,
+        None => {
+            return Err(to_map_not_found_error(state.db.as_ref(), name).into())
         }
-    } else {
-        Err(to_map_not_found_error(format!(
-            "Map \"{}\" not found!",
-            data.name
-        )))
+    };
+    let updated = Map {
+        config,
+        last_changed: get_current_time().map_err(ApiError::internal)?,
+        ..map.clone()
+    };
+    let change = Change::Update {
+        name: map.name,
+        map: updated.clone(),
+    };
+    state
+        .db
+        .apply(&[change], None)
+        .map_err(ApiError::internal)?;
+    update_votes(state.db.as_ref())?;
+    Ok(Json(updated))
+}
+
+/// The lint findings of the map file, from when it was uploaded.
+#[openapi]
+#[get("/maps/<name>/lint")]
+fn get_map_lint(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    name: &str,
+) -> RouteResult<Json<Vec<Finding>>, MapErrors> {
+    logging::record_map(name);
+    match state.db.find(name).map_err(ApiError::internal)? {
+        Some(map) => Ok(Json(map.lint)),
+        None => Err(to_map_not_found_error(state.db.as_ref(), name).into()),
     }
 }
 
+/// The external images and sounds of the map file.
 #[openapi]
-#[post("/approve", format = "json", data = "<data>")]
-async fn approve_map(
+#[get("/maps/<name>/dependencies")]
+fn get_map_dependencies(
     _key: ApiKey,
     state: &State<CustomState>,
-    data: Json<JustTheMapName<'_>>,
-) -> Result<(), CustomStatus> {
-    if let Some((id, map)) = find_map(&state.db, data.name) {
-        if [MapState::Declined, MapState::New].contains(&map.state) {
-            let mut tx = state.db.begin().map_err(to_internal_server_error)?;
-            tx.update(
-                &id,
-                &Map {
-                    state: MapState::Approved,
-                    last_changed: get_current_time()
-                        .map_err(either_to_custom_status)?,
-                    ..map
-                },
+    name: &str,
+) -> RouteResult<Json<mapres::Dependencies>, DependencyErrors> {
+    logging::record_map(name);
+    let map = match state.db.find(name).map_err(ApiError::internal)? {
+        Some(map) => map,
+        None => {
+            return Err(to_map_not_found_error(state.db.as_ref(), name).into())
+        }
+    };
+    let file =
+        std::fs::read(map_file_path(&map)).map_err(ApiError::internal)?;
+    let df = datafile::Datafile::parse(&file).map_err(|e| {
+        ApiError::new(
+            ErrorCode::InvalidMapFile,
+            format!("The map file can't be read: {}", e),
+        )
+    })?;
+    let dependencies = mapres::dependencies(&df).map_err(|e| {
+        ApiError::new(
+            ErrorCode::InvalidMapFile,
+            format!("The mapres of the map can't be read: {}", e),
+        )
+    })?;
+    Ok(Json(dependencies))
+}
+
+/// An overview of the game tiles of a map.
+#[openapi]
+#[get("/maps/<name>/preview.png")]
+async fn get_map_preview(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    name: &str,
+) -> RouteResult<NamedFile, PreviewErrors> {
+    logging::record_map(name);
+    let map = match state.db.find(name).map_err(ApiError::internal)? {
+        Some(map) => map,
+        None => {
+            return Err(to_map_not_found_error(state.db.as_ref(), name).into())
+        }
+    };
+    let file_path = map_file_path(&map);
+    let path = blocking(move || {
+        let file = std::fs::read(file_path).map_err(ApiError::internal)?;
+        preview::cached(&file).map_err(|e| {
+            ApiError::new(
+                ErrorCode::PreviewFailed,
+                format!("The map can't be drawn: {}", e),
             )
-            .map_err(to_internal_server_error)?;
-            tx.commit().map_err(to_internal_server_error)?;
-            update_votes(&state.db)?;
-            Ok(())
-        } else if map.state == MapState::Approved {
-            Err(to_custom_bad_request(
-                "This map is already Approved!".to_string(),
+        })
+    })
+    .await?;
+    Ok(NamedFile::open(path).await.map_err(ApiError::internal)?)
+}
+
+/// The revisions of a map to compare, by default the latest one and the one
+/// before it.
+fn diff_revisions(
+    db: &dyn MapRepository,
+    name: &str,
+    from: Option<usize>,
+    to: Option<usize>,
+) -> Result<(revisions::RevisionFile, revisions::RevisionFile), ApiError> {
+    let map = match db.find(name).map_err(ApiError::internal)? {
+        Some(map) => map,
+        None => return Err(to_map_not_found_error(db, name)),
+    };
+    let to = revisions::read(&map, to.unwrap_or(map.revisions.len()))?;
+    let from = match from {
+        Some(from) => from,
+        None if to.number > 1 => to.number - 1,
+        None => {
+            return Err(ApiError::new(
+                ErrorCode::RevisionNotFound,
+                format!("{} has no revision before {}.", map.name, to.number),
             ))
-        } else {
-            Err(to_custom_bad_request(format!(
-                "Cannot go from state {:?} to {:?}!",
-                map.state,
-                MapState::Approved
-            )))
         }
+    };
+    Ok((revisions::read(&map, from)?, to))
+}
+
+fn invalid_revision(e: impl std::fmt::Display) -> ApiError {
+    ApiError::new(
+        ErrorCode::InvalidMapFile,
+        format!("A revision of the map can't be read: {}", e),
+    )
+}
+
+/// What changed between two revisions of a map.
+#[openapi]
+#[get("/maps/<name>/diff?<from>&<to>")]
+async fn get_map_diff(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    name: &str,
+    from: Option<usize>,
+    to: Option<usize>,
+) -> RouteResult<Json<diff::MapDiff>, DiffErrors> {
+    logging::record_map(name);
+    let (db, name) = (state.db.clone(), name.to_string());
+    let diff = blocking(move || {
+        let (from, to) = diff_revisions(db.as_ref(), &name, from, to)?;
+        diff::diff(&from, &to).map_err(invalid_revision)
+    })
+    .await?;
+    Ok(Json(diff))
+}
+
+/// The game tiles of a map with the tiles that changed between two
+/// revisions highlighted.
+#[openapi]
+#[get("/maps/<name>/diff.png?<from>&<to>")]
+async fn get_map_diff_png(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    name: &str,
+    from: Option<usize>,
+    to: Option<usize>,
+) -> RouteResult<(ContentType, Vec<u8>), DiffErrors> {
+    logging::record_map(name);
+    let (db, name) = (state.db.clone(), name.to_string());
+    let png = blocking(move || {
+        let (from, to) = diff_revisions(db.as_ref(), &name, from, to)?;
+        diff::render(&from, &to).map_err(invalid_revision)
+    })
+    .await?;
+    Ok((ContentType::PNG, png))
+}
+
+#[get("/health")]
+fn health_check() -> Json<HashMap<&'static str, &'static str>> {
+    Json(HashMap::from([("status", "ok")]))
+}
+
+#[get("/ready")]
+fn readiness_check(
+    state: &State<CustomState>,
+) -> (Status, Json<health::Readiness>) {
+    let readiness = health::readiness(state.db.as_ref());
+    let status = if readiness.ready {
+        Status::Ok
     } else {
-        Err(to_map_not_found_error(format!(
-            "Map \"{}\" not found!",
-            data.name
-        )))
-    }
+        Status::ServiceUnavailable
+    };
+    (status, Json(readiness))
+}
+
+#[get("/metrics")]
+fn get_metrics(
+    state: &State<CustomState>,
+) -> Result<(ContentType, String), ApiError> {
+    let metrics =
+        metrics::render(state.db.as_ref()).map_err(ApiError::internal)?;
+    // The version of the text format, which scrapers look for.
+    let content_type = ContentType::new("text", "plain")
+        .with_params([("charset", "utf-8"), ("version", "0.0.4")]);
+    Ok((content_type, metrics))
 }
 
 #[openapi]
-#[post("/change_difficulty", format = "json", data = "<data>")]
-async fn change_map_difficulty(
+#[get("/admin/reconcile")]
+fn reconcile_report(
     _key: ApiKey,
     state: &State<CustomState>,
-    data: Json<ChangeMapDifficultyData<'_>>,
-) -> Result<(), CustomStatus> {
-    let difficulty =
-        Difficulty::from_str(data.difficulty).map_err(to_bad_request)?;
-
-    if let Some((id, map)) = find_map(&state.db, data.name) {
-        let mut tx = state.db.begin().map_err(to_internal_server_error)?;
-
-        tx.update(
-            &id,
-            &Map {
-                difficulty,
-                last_changed: get_current_time()
-                    .map_err(either_to_custom_status)?,
-                ..map
-            },
-        )
-        .map_err(to_internal_server_error)?;
-        tx.commit().map_err(to_internal_server_error)?;
-        update_votes(&state.db)?;
+) -> RouteResult<Json<reconcile::Report>, NoErrors> {
+    let report = reconcile::reconcile(state.db.as_ref(), None)
+        .map_err(ApiError::internal)?;
+    Ok(Json(report))
+}
+
+#[openapi]
+#[post("/admin/reconcile?<policy>")]
+fn reconcile_fix(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    policy: reconcile::FixPolicy,
+) -> RouteResult<Json<reconcile::Report>, NoErrors> {
+    let report = reconcile::reconcile(state.db.as_ref(), Some(policy))
+        .map_err(ApiError::internal)?;
+    Ok(Json(report))
+}
+
+#[openapi]
+#[get("/admin/export")]
+fn export_database(
+    _key: ApiKey,
+    state: &State<CustomState>,
+) -> RouteResult<Json<export::Export>, NoErrors> {
+    let export =
+        export::export(state.db.as_ref()).map_err(ApiError::internal)?;
+    Ok(Json(export))
+}
+
+#[openapi]
+#[post("/admin/import", format = "json", data = "<data>")]
+fn import_database(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    data: Json<export::Export>,
+) -> RouteResult<(), ImportErrors> {
+    export::restore(state.db.as_ref(), &data)
+        .map_err(|e| ApiError::new(ErrorCode::InvalidImport, e.to_string()))?;
+    Ok(update_votes(state.db.as_ref())?)
+}
+
+/// Checks the header of a teeworlds datafile, which maps are stored in.
+fn is_map_file(data: &[u8]) -> bool {
+    data.starts_with(b"DATA") || data.starts_with(b"ATAD")
+}
+
+fn check_map_file(data: &[u8]) -> Result<(), ApiError> {
+    if is_map_file(data) {
         Ok(())
     } else {
-        Err(to_map_not_found_error(format!(
-            "Map \"{}\" not found!",
-            data.name
-        )))
+        Err(ApiError::new(
+            ErrorCode::InvalidMapFile,
+            "The file is not a teeworlds map!",
+        ))
     }
 }
 
-fn either_to_custom_status(
-    either: Either<StructsyError, Box<dyn std::error::Error>>,
-) -> CustomStatus {
-    use Either::*;
-    match either {
-        Left(l) => to_bad_request(l),
-        Right(r) => to_internal_server_error(r),
+/// Writes downloaded maps into the test folder and creates or updates their
+/// records. The votes are regenerated once for all of them.
+fn store_test_maps(
+    db: &dyn MapRepository,
+    maps: &[archive::ArchiveMap],
+) -> Result<Vec<Map>, ApiError> {
+    let dir = &CONFIG.test_map_folder;
+
+    std::fs::create_dir_all(dir).map_err(ApiError::internal)?;
+
+    let mut stored = Vec::new();
+    let mut res = Ok(());
+    for map in maps {
+        let path = dir.join(format!("{}.map", map.name));
+        // The replaced file may be from before revisions were kept.
+        if let Ok(old) = std::fs::read(&path) {
+            revisions::store(&old).map_err(ApiError::internal)?;
+        }
+        revisions::store(&map.data).map_err(ApiError::internal)?;
+        std::fs::write(path, &map.data).map_err(ApiError::internal)?;
+
+        if let Err(e) = preview::cached(&map.data) {
+            tracing::warn!(map = %map.name, error = %e, "Could not draw preview");
+        }
+
+        match add_or_update_map(db, map) {
+            Ok(map) => stored.push(map),
+            Err(e) => {
+                res = Err(e);
+                break;
+            }
+        }
     }
+
+    update_votes(db)?;
+
+    res.map(|()| stored)
 }
 
+/// Queues the upload of a map. The map file is downloaded afterwards, the
+/// returned job tells how that went.
 #[openapi]
 #[post("/create", format = "json", data = "<data>")]
 async fn create_map(
-    _key: ApiKey,
+    key: ApiKey,
     state: &State<CustomState>,
     data: Json<CreateMapData<'_>>,
-) -> Result<(), CustomStatus> {
-    let difficulty =
-        Difficulty::from_str(data.difficulty).map_err(to_bad_request)?;
-    let file = reqwest::get(data.url)
-        .await
-        .map_err(to_bad_request)?
-        .bytes()
-        .await
-        .map_err(to_bad_request)?;
-
-    let dir = &CONFIG.test_map_folder;
+) -> RouteResult<Accepted<Json<jobs::Job>>, CreateErrors> {
+    let difficulty = parse_difficulty(data.difficulty)?;
+    let name = data.name.map(normalize_map_name);
+    if let (Some(name), Some(given)) = (&name, data.name) {
+        logging::record_map(name);
+        check_map_name(name, given)?;
+    }
+    download::check(data.url)?;
 
-    std::fs::create_dir_all(&dir).map_err(to_internal_server_error)?;
+    let job = state
+        .jobs
+        .submit(name, difficulty, data.url.to_string(), key.0);
+    Ok(Accepted(Some(Json(job))))
+}
 
-    let name = data.name.to_lowercase();
+#[openapi]
+#[get("/jobs/<id>")]
+fn get_job(
+    _key: ApiKey,
+    state: &State<CustomState>,
+    id: &str,
+) -> RouteResult<Json<jobs::Job>, JobErrors> {
+    match state.jobs.get(id) {
+        Some(job) => Ok(Json(job)),
+        None => Err(ApiError::new(
+            ErrorCode::JobNotFound,
+            format!("There is no job with the id \"{}\".", id),
+        )
+        .into()),
+    }
+}
 
-    let name = if name.ends_with(".map") {
-        name[0..name.len() - 4].to_string()
-    } else {
-        name
-    };
+fn open_database() -> Arc<dyn MapRepository> {
+    if CONFIG.database_backend == repository::Backend::Structsy {
+        match schema::migrate(&CONFIG.database_path, false) {
+            Ok(report) if report.steps.is_empty() => {}
+            Ok(report) => tracing::info!("{}", report),
+            Err(e) => panic!("could not migrate the database: {}", e),
+        }
+    }
 
-    std::fs::write(dir.join(&format!("{}.map", name)), file)
-        .map_err(to_internal_server_error)?;
+    let db = repository::open(CONFIG.database_backend, &CONFIG.database_path)
+        .expect("could not open database file");
 
-    let res = add_or_update_map(&state.db, name, difficulty, MapState::New)
-        .map_err(either_to_custom_status);
+    match journal::recover(db.as_ref()) {
+        Ok(0) => {}
+        Ok(n) => tracing::warn!("Rolled back {} interrupted operations.", n),
+        Err(e) => panic!("could not recover from the journal: {}", e),
+    }
 
-    update_votes(&state.db)?;
+    db
+}
 
-    res
+fn run_command(command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Reconcile { fix } => {
+            let report = reconcile::reconcile(open_database().as_ref(), fix)?;
+            for finding in &report.findings {
+                let fixed = if finding.fixed { " (fixed)" } else { "" };
+                println!("{}{}", finding.issue, fixed);
+            }
+            println!("{} differences found.", report.findings.len());
+        }
+        Command::ImportMaps {
+            folder,
+            created_at,
+            default_difficulty,
+            dry_run,
+        } => {
+            let summary = import::import_maps(
+                open_database().as_ref(),
+                &folder,
+                created_at,
+                default_difficulty,
+                dry_run,
+            )?;
+            println!("{}", summary);
+        }
+        Command::Export { output } => {
+            let export = export::export(open_database().as_ref())?;
+            let json = serde_json::to_string_pretty(&export)?;
+            match output {
+                Some(path) => std::fs::write(path, json)?,
+                None => println!("{}", json),
+            }
+        }
+        Command::Import { file } => {
+            let export = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+            let db = open_database();
+            export::restore(db.as_ref(), &export)?;
+            update_votes(db.as_ref())?;
+            println!("{} maps restored.", export.maps.len());
+        }
+        Command::Migrate { dry_run } => {
+            if CONFIG.database_backend != repository::Backend::Structsy {
+                return Err("Only structsy databases need migrations.".into());
+            }
+            let report = schema::migrate(&CONFIG.database_path, dry_run)?;
+            println!("{}", report);
+        }
+        Command::CopyDatabase { to, output } => {
+            if output.exists() {
+                return Err(
+                    format!("{} already exists!", output.display()).into()
+                );
+            }
+            let export = export::export(open_database().as_ref())?;
+            if to == repository::Backend::Structsy {
+                // Start at the current version, so it isn't mistaken for an
+                // old database.
+                schema::migrate(&output, false)?;
+            }
+            let target = repository::open(to, &output)?;
+            export::restore(target.as_ref(), &export)?;
+            println!(
+                "{} maps copied to {}.",
+                export.maps.len(),
+                output.display()
+            );
+        }
+        Command::Backup => {
+            let dir = CONFIG
+                .backup_dir
+                .as_ref()
+                .ok_or("No backup directory configured, pass --backup-dir.")?;
+            let path = backup::run(open_database().as_ref(), dir)?;
+            println!("Wrote backup {}.", path.display());
+        }
+        Command::Restore { archive } => {
+            let count = backup::restore(&archive)?;
+            println!("{} maps restored.", count);
+        }
+    }
+    Ok(())
 }
 
-#[launch]
-fn rocket() -> _ {
+#[rocket::main]
+async fn main() {
     // this is needed in order to display help texts, because they dont work in lazy_static
-    let _ = Options::from_args();
+    let options = Options::from_args();
+    logging::init(options.log_format);
 
-    let db: Structsy = {
-        let db = Structsy::open("maps.persydb")
-            .expect("could not open database file");
-        db.define::<Map>().unwrap();
-        db
-    };
+    if let Some(command) = options.command {
+        if let Err(e) = run_command(command) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    println!("Updating maps...");
-    let _ = update_votes(&db);
+    if let Err(e) = rocket().launch().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
 
-    let custom_state = CustomState { db };
+fn rocket() -> rocket::Rocket<rocket::Build> {
+    let database_lock =
+        repository::lock(&CONFIG.database_path).unwrap_or_else(|e| {
+            panic!("could not lock the database, is mapmaster running? {}", e)
+        });
+    let db = open_database();
 
-    rocket::build()
+    tracing::info!("Updating maps...");
+    let _ = update_votes(db.as_ref());
+
+    let custom_state = CustomState {
+        db,
+        jobs: Arc::default(),
+        _database_lock: database_lock,
+    };
+    metrics::init();
+
+    // Rocket logs through tracing, which colors the lines itself.
+    let figment = rocket::Config::figment().merge(("cli_colors", false));
+
+    rocket::custom(figment)
+        .attach(logging::RequestLog)
+        .attach(metrics::RequestMetrics)
+        .mount(
+            "/",
+            logging::traced(routes![
+                health_check,
+                readiness_check,
+                get_metrics
+            ]),
+        )
         .mount(
             "/mapmaster",
-            openapi_get_routes![
+            logging::traced(openapi_get_routes![
                 list_maps,
                 create_map,
+                get_job,
                 change_map_difficulty,
                 approve_map,
                 publish_map,
                 recall_map,
-                decline_map
-            ],
+                decline_map,
+                get_map,
+                delete_map,
+                rename_map,
+                set_map_config,
+                get_map_lint,
+                get_map_dependencies,
+                get_map_preview,
+                get_map_diff,
+                get_map_diff_png,
+                search_maps,
+                bulk_operations,
+                reconcile_report,
+                reconcile_fix,
+                export_database,
+                import_database
+            ]),
         )
         .mount(
             "/",
@@ -733,7 +1626,6 @@ fn rocket() -> _ {
                 ..Default::default()
             }),
         )
-
         .mount(
             "/rapidoc/",
             make_rapidoc(&RapiDocConfig {
@@ -757,5 +1649,132 @@ fn rocket() -> _ {
             }),
         )
         .manage(custom_state)
-        .register("/", catchers![common::bad_request, common::unauthorized])
+        .attach(backup::scheduler())
+        .attach(jobs::workers())
+        .register(
+            "/",
+            catchers![
+                error::bad_request,
+                error::unauthorized,
+                error::not_found,
+                error::unprocessable_entity,
+                error::internal_server_error
+            ],
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlite_repository::SqliteRepository;
+
+    fn map(name: &str, state: MapState) -> Map {
+        Map {
+            name: name.to_string(),
+            difficulty: Difficulty::Easy,
+            state,
+            created_at: 1,
+            last_changed: 1,
+            sha256: None,
+            config: None,
+            lint: Vec::new(),
+            stats: None,
+            revisions: Vec::new(),
+            compatibility: None,
+        }
+    }
+
+    fn operation(name: &str, operation: MapOperation) -> BulkOperation {
+        BulkOperation {
+            name: name.to_string(),
+            operation,
+        }
+    }
+
+    #[test]
+    fn plans_the_bulk_operations_that_succeed() {
+        let path = std::env::temp_dir()
+            .join(format!("mapmaster-bulk-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = SqliteRepository::open(&path).unwrap();
+        let maps = vec![
+            Change::Insert(map("alpha", MapState::New)),
+            Change::Insert(map("beta", MapState::Approved)),
+            Change::Insert(map("gamma", MapState::Declined)),
+        ];
+        db.apply(&maps, None).unwrap();
+
+        let plan = plan_bulk(
+            &db,
+            &[
+                operation("Alpha", MapOperation::Approve),
+                // Already approved by the operation before.
+                operation("alpha", MapOperation::Approve),
+                operation("beta", MapOperation::Decline),
+                operation("alphaa", MapOperation::Decline),
+                operation("gamma", MapOperation::Decline),
+                operation("beta", MapOperation::Recall),
+            ],
+            2,
+        )
+        .unwrap();
+
+        let ok = plan.results.iter().map(|r| r.ok).collect::<Vec<_>>();
+        assert_eq!(ok, [true, false, true, false, false, true]);
+        let errors = plan
+            .results
+            .iter()
+            .filter_map(|r| r.error.as_ref().map(|e| e.error))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            [
+                ErrorCode::InvalidTransition,
+                ErrorCode::MapNotFound,
+                ErrorCode::InvalidTransition
+            ]
+        );
+        assert_eq!(
+            plan.results[3].error.as_ref().unwrap().suggestions[0],
+            "alpha"
+        );
+
+        // Gamma has no operation that succeeded, beta ends up where the
+        // last one took it.
+        let updated = plan
+            .updated
+            .iter()
+            .map(|(from, to)| (from.name.as_str(), from.state, to.state))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            updated,
+            [
+                ("alpha", MapState::New, MapState::Approved),
+                ("beta", MapState::Approved, MapState::New),
+            ]
+        );
+        assert!(plan.updated.iter().all(|(_, to)| to.last_changed == 2));
+
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn map_names_are_safe_for_votes() {
+        for name in ["run_1", "v2.0-final"] {
+            assert!(check_map_name(name, name).is_ok(), "{}", name);
+        }
+        for name in [
+            "",
+            "a/b",
+            "..\\x",
+            "a\" \"b",
+            "a;quit",
+            "a\nquit",
+            "two words",
+            "Upper",
+        ] {
+            assert!(check_map_name(name, name).is_err(), "{}", name);
+        }
+    }
 }
@@ -0,0 +1,174 @@
+//! Config snippets of maps, with their own tunings or settings.
+//!
+//! The snippet of a published map is written to `<name>.map.cfg` next to the
+//! map, after an `exec` of the `flexreset.cfg` of its difficulty. The vote of
+//! the map uses that file as its reset file instead of `flexreset.cfg`. Only
+//! the commands of `--config-command` can be used in a snippet. Config files
+//! without the marker line of mapmaster are left alone.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{Map, MapState, CONFIG};
+
+/// The commands allowed when no `--config-command` is given.
+pub const DEFAULT_COMMANDS: &[&str] = &[
+    "tune",
+    "tune_reset",
+    "tune_zone",
+    "tune_zone_enter",
+    "tune_zone_leave",
+    "sv_scorelimit",
+    "sv_timelimit",
+];
+
+/// The longest snippet a map can have, in bytes.
+const MAX_LEN: usize = 4096;
+
+const EXTENSION: &str = ".map.cfg";
+
+fn is_allowed(command: &str) -> bool {
+    if CONFIG.config_commands.is_empty() {
+        DEFAULT_COMMANDS.contains(&command)
+    } else {
+        CONFIG.config_commands.iter().any(|c| c == command)
+    }
+}
+
+/// Checks that every line of a snippet runs one allowed command. Empty lines
+/// and comments are fine.
+pub fn validate(config: &str) -> Result<(), String> {
+    if config.len() > MAX_LEN {
+        return Err(format!("The config is longer than {} bytes!", MAX_LEN));
+    }
+    for (i, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // The console runs everything after a `;` as another command.
+        if line.contains(';') {
+            return Err(format!("Line {} has more than one command!", i + 1));
+        }
+        let command = line.split_whitespace().next().unwrap_or_default();
+        if !is_allowed(command) {
+            return Err(format!(
+                "Line {}: \"{}\" is not an allowed command!",
+                i + 1,
+                command
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The reset file of a published map, which is its config file if it has a
+/// config.
+pub fn reset_file(map: &Map) -> PathBuf {
+    let folder = CONFIG.public_map_folder.join(map.difficulty);
+    match &map.config {
+        Some(_) => folder.join(format!("{}{}", map.name, EXTENSION)),
+        None => folder.join("flexreset.cfg"),
+    }
+}
+
+/// The first line of the config files mapmaster writes. Files without it
+/// are maintained by hand and never touched.
+const MARKER: &str = "# Written by mapmaster, changes are overwritten.";
+
+fn is_generated(path: &Path) -> bool {
+    std::fs::read_to_string(path)
+        .map(|contents| contents.lines().next() == Some(MARKER))
+        .unwrap_or(false)
+}
+
+/// Writes the config files of the published maps of one difficulty folder,
+/// and removes the ones it wrote for maps that no longer have a config there.
+pub fn write_files(folder: &Path, maps: &[Map]) -> io::Result<()> {
+    let mut written = Vec::new();
+    for map in maps {
+        if let (MapState::Published, Some(config)) = (map.state, &map.config) {
+            let path = folder.join(format!("{}{}", map.name, EXTENSION));
+            if path.exists() && !is_generated(&path) {
+                tracing::warn!(
+                    path = %path.display(),
+                    "Not replacing a config file that mapmaster didn't write"
+                );
+                continue;
+            }
+            let flexreset = folder.join("flexreset.cfg");
+            let contents = format!(
+                "{}\nexec \"{}\"\n{}\n",
+                MARKER,
+                flexreset.to_string_lossy(),
+                config.trim_end()
+            );
+            std::fs::write(&path, contents)?;
+            written.push(path);
+        }
+    }
+
+    for entry in std::fs::read_dir(folder)? {
+        let path = entry?.path();
+        let is_config = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.ends_with(EXTENSION))
+            .unwrap_or(false);
+        if is_config
+            && path.is_file()
+            && !written.contains(&path)
+            && is_generated(&path)
+        {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Difficulty;
+
+    fn map(name: &str, config: Option<&str>) -> Map {
+        Map {
+            name: name.to_string(),
+            difficulty: Difficulty::Easy,
+            state: MapState::Published,
+            created_at: 0,
+            last_changed: 0,
+            sha256: None,
+            config: config.map(str::to_string),
+            lint: Vec::new(),
+            stats: None,
+            revisions: Vec::new(),
+            compatibility: None,
+        }
+    }
+
+    #[test]
+    fn keeps_config_files_written_by_hand() {
+        let dir = std::env::temp_dir()
+            .join(format!("mapmaster-configs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let foreign = dir.join("hand.map.cfg");
+        std::fs::write(&foreign, "sv_gravity 0.5\n").unwrap();
+
+        write_files(&dir, &[map("tuned", Some("tune gravity 0.25"))]).unwrap();
+        let tuned = dir.join("tuned.map.cfg");
+        assert!(is_generated(&tuned));
+
+        // The config is removed, the file written by hand stays.
+        write_files(&dir, &[map("tuned", None), map("hand", Some("tune"))])
+            .unwrap();
+        assert!(!tuned.exists());
+        assert_eq!(
+            std::fs::read_to_string(&foreign).unwrap(),
+            "sv_gravity 0.5\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+//! External images and sounds, which maps leave to the mapres of the client
+//! instead of storing them in the map file.
+//!
+//! With `--mapres`, the names a map uses are checked against the mapres the
+//! servers have. That is either a folder with the `.png` images and `.opus`
+//! or `.wv` sounds, or a JSON manifest like
+//! `{"images": ["grass_main"], "sounds": []}`. What the servers have is
+//! read again after `RELOAD_AFTER`, so new mapres count without a restart.
+
+use lazy_static::lazy_static;
+use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::{
+    collections::HashSet,
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    datafile::Datafile,
+    error::{ApiError, ErrorCode},
+    map_file_path, twmap, Map, CONFIG,
+};
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Dependency {
+    pub name: String,
+    /// Whether the servers don't have it. Never set without `--mapres`.
+    pub missing: bool,
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Dependencies {
+    pub images: Vec<Dependency>,
+    pub sounds: Vec<Dependency>,
+}
+
+impl Dependencies {
+    /// Describes the missing mapres, one per entry.
+    pub fn missing(&self) -> Vec<String> {
+        let images = self
+            .images
+            .iter()
+            .filter(|i| i.missing)
+            .map(|i| format!("image \"{}\"", i.name));
+        let sounds = self
+            .sounds
+            .iter()
+            .filter(|s| s.missing)
+            .map(|s| format!("sound \"{}\"", s.name));
+        images.chain(sounds).collect()
+    }
+}
+
+/// The names of the mapres the servers have.
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde")]
+struct Known {
+    #[serde(default)]
+    images: HashSet<String>,
+    #[serde(default)]
+    sounds: HashSet<String>,
+}
+
+fn scan(folder: &Path, known: &mut Known) -> io::Result<()> {
+    for entry in std::fs::read_dir(folder)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            scan(&path, known)?;
+            continue;
+        }
+        let name = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("png") => known.images.insert(name),
+            Some("opus") | Some("wv") => known.sounds.insert(name),
+            _ => false,
+        };
+    }
+    Ok(())
+}
+
+fn load(path: &Path) -> Result<Known, Box<dyn Error>> {
+    if path.is_dir() {
+        let mut known = Known::default();
+        scan(path, &mut known)?;
+        Ok(known)
+    } else {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+/// How long the mapres the servers have are kept before they are read again.
+const RELOAD_AFTER: Duration = Duration::from_secs(10);
+
+/// The mapres last read, from where and when they were read.
+struct Loaded {
+    path: PathBuf,
+    at: Instant,
+    known: Arc<Known>,
+}
+
+lazy_static! {
+    static ref LOADED: Mutex<Option<Loaded>> = Mutex::new(None);
+}
+
+/// The mapres at `path`, read again if they were read too long ago. Bulk
+/// operations publish many maps at once, which shouldn't read the folder for
+/// every map.
+fn cached(path: &Path) -> Result<Arc<Known>, Box<dyn Error>> {
+    let mut loaded = LOADED.lock().unwrap_or_else(|e| e.into_inner());
+    match &*loaded {
+        Some(l) if l.path == path && l.at.elapsed() < RELOAD_AFTER => {
+            Ok(l.known.clone())
+        }
+        _ => {
+            let known = Arc::new(load(path)?);
+            *loaded = Some(Loaded {
+                path: path.to_path_buf(),
+                at: Instant::now(),
+                known: known.clone(),
+            });
+            Ok(known)
+        }
+    }
+}
+
+fn sorted(
+    mut names: Vec<String>,
+    known: Option<&HashSet<String>>,
+) -> Vec<Dependency> {
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .map(|name| Dependency {
+            missing: known.map(|k| !k.contains(&name)).unwrap_or(false),
+            name,
+        })
+        .collect()
+}
+
+/// The external images and sounds of a map, checked against `--mapres` if
+/// it is given.
+pub fn dependencies(df: &Datafile) -> Result<Dependencies, Box<dyn Error>> {
+    let mut images = Vec::new();
+    for image in twmap::images(df)? {
+        if image.external {
+            images.push(df.string(image.name)?);
+        }
+    }
+    let mut sounds = Vec::new();
+    for sound in twmap::sounds(df)? {
+        if sound.external {
+            sounds.push(df.string(sound.name)?);
+        }
+    }
+
+    let known = CONFIG.mapres.as_deref().map(cached).transpose()?;
+    Ok(Dependencies {
+        images: sorted(images, known.as_ref().map(|k| &k.images)),
+        sounds: sorted(sounds, known.as_ref().map(|k| &k.sounds)),
+    })
+}
+
+/// Fails if the servers don't have all mapres of a map that is about to be
+/// published. Map files that can't be read are left to the lint.
+pub fn check_publish(map: &Map) -> Result<(), ApiError> {
+    if CONFIG.mapres.is_none() {
+        return Ok(());
+    }
+    let file = std::fs::read(map_file_path(map)).map_err(ApiError::internal)?;
+    let df = match Datafile::parse(&file) {
+        Ok(df) => df,
+        Err(_) => return Ok(()),
+    };
+    let missing = dependencies(&df).map_err(ApiError::internal)?.missing();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(ApiError::new(
+        ErrorCode::MissingMapres,
+        format!(
+            "The servers don't have the {} of this map!",
+            missing.join(", ")
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_folders_and_manifests() {
+        let dir = std::env::temp_dir()
+            .join(format!("mapmaster-mapres-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sounds")).unwrap();
+        std::fs::write(dir.join("grass_main.png"), b"").unwrap();
+        std::fs::write(dir.join("readme.txt"), b"").unwrap();
+        std::fs::write(dir.join("sounds/wind.opus"), b"").unwrap();
+
+        let known = load(&dir).unwrap();
+        assert_eq!(known.images, ["grass_main".to_string()].into());
+        assert_eq!(known.sounds, ["wind".to_string()].into());
+
+        let manifest = dir.join("mapres.json");
+        std::fs::write(&manifest, r#"{"images": ["desert_main"]}"#).unwrap();
+        let known = load(&manifest).unwrap();
+        assert!(known.images.contains("desert_main"));
+        assert!(known.sounds.is_empty());
+
+        // Kept until they are read again, unless they are somewhere else.
+        assert!(cached(&manifest).unwrap().images.contains("desert_main"));
+        std::fs::write(&manifest, r#"{"images": ["jungle_main"]}"#).unwrap();
+        assert!(cached(&manifest).unwrap().images.contains("desert_main"));
+        assert!(cached(&dir).unwrap().images.contains("grass_main"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+//! Prometheus metrics, served at `GET /metrics`.
+//!
+//! Counters and histograms are updated where things happen. The map counts
+//! are taken from the database whenever the metrics are scraped.
+
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request, Response,
+};
+use std::{error::Error, time::Instant};
+use strum::IntoEnumIterator;
+
+use crate::{
+    get_current_time,
+    repository::{MapFilter, MapRepository, RepositoryError},
+    Difficulty, MapState,
+};
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    static ref REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("mapmaster_http_requests_total", "Handled requests."),
+        &["method", "route", "status"],
+    ));
+    static ref REQUEST_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "mapmaster_http_request_duration_seconds",
+            "Time taken to handle a request.",
+        ),
+        &["method", "route"],
+    ));
+    static ref MAPS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("mapmaster_maps", "Maps by state and difficulty."),
+        &["state", "difficulty"],
+    ));
+    static ref OLDEST_NEW_MAP_AGE: IntGauge = register(IntGauge::new(
+        "mapmaster_oldest_new_map_age_seconds",
+        "Time since the oldest map that is still new was uploaded.",
+    ));
+    pub static ref DOWNLOAD_BYTES: IntCounter = register(IntCounter::new(
+        "mapmaster_download_bytes_total",
+        "Bytes of map files downloaded by create.",
+    ));
+    pub static ref DOWNLOAD_FAILURES: IntCounter = register(IntCounter::new(
+        "mapmaster_download_failures_total",
+        "Map files that create failed to download.",
+    ));
+    pub static ref VOTES_UPDATES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "mapmaster_votes_updates_total",
+            "Regenerations of the votes files.",
+        ),
+        &["result"],
+    ));
+    pub static ref VOTES_UPDATE_DURATION: Histogram =
+        register(Histogram::with_opts(HistogramOpts::new(
+            "mapmaster_votes_update_duration_seconds",
+            "Time taken to regenerate the votes files.",
+        )));
+    pub static ref AUTH_FAILURES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "mapmaster_auth_failures_total",
+            "Requests rejected for their api key.",
+        ),
+        &["reason"],
+    ));
+}
+
+fn register<T>(metric: prometheus::Result<T>) -> T
+where
+    T: prometheus::core::Collector + Clone + 'static,
+{
+    let metric = metric.expect("invalid metric");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+/// When the request arrived, kept in the request's local cache.
+struct Started(Instant);
+
+/// Counts and times every request by its route.
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| Started(Instant::now()));
+    }
+
+    async fn on_response<'r>(
+        &self,
+        request: &'r Request<'_>,
+        response: &mut Response<'r>,
+    ) {
+        let started = request.local_cache(|| Started(Instant::now()));
+        // The path pattern instead of the path, which would give every map
+        // its own time series.
+        let route = request.route().map_or_else(
+            || "unmatched".to_string(),
+            |r| r.uri.path().to_string(),
+        );
+        let method = request.method().as_str();
+        REQUESTS
+            .with_label_values(&[
+                method,
+                &route,
+                &response.status().code.to_string(),
+            ])
+            .inc();
+        REQUEST_DURATION
+            .with_label_values(&[method, &route])
+            .observe(started.0.elapsed().as_secs_f64());
+    }
+}
+
+/// Touches the labelled metrics, so they are exported before they are first
+/// used.
+pub fn init() {
+    for reason in ["missing_header", "invalid_key"] {
+        AUTH_FAILURES.with_label_values(&[reason]);
+    }
+    for result in ["success", "failure"] {
+        VOTES_UPDATES.with_label_values(&[result]);
+    }
+    lazy_static::initialize(&DOWNLOAD_BYTES);
+    lazy_static::initialize(&DOWNLOAD_FAILURES);
+    lazy_static::initialize(&VOTES_UPDATE_DURATION);
+}
+
+fn update_map_metrics(db: &dyn MapRepository) -> Result<(), RepositoryError> {
+    let maps = db.list(&MapFilter::default())?;
+    for s in MapState::iter() {
+        for d in Difficulty::iter() {
+            let count = maps
+                .iter()
+                .filter(|m| m.state == s && m.difficulty == d)
+                .count();
+            MAPS.with_label_values(&[s.into(), d.into()])
+                .set(count as i64);
+        }
+    }
+    let oldest = maps
+        .iter()
+        .filter(|m| m.state == MapState::New)
+        .map(|m| m.created_at)
+        .min();
+    let age = oldest.map_or(0, |created_at| {
+        get_current_time()
+            .unwrap_or_default()
+            .saturating_sub(created_at)
+    });
+    OLDEST_NEW_MAP_AGE.set(age as i64);
+    Ok(())
+}
+
+/// All metrics in the Prometheus text format.
+pub fn render(db: &dyn MapRepository) -> Result<String, Box<dyn Error>> {
+    update_map_metrics(db)?;
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
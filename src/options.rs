@@ -1,6 +1,11 @@
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+use crate::{
+    compat::Acceptance, import::CreatedAtSource, lint::LintLevel,
+    logging::LogFormat, reconcile::FixPolicy, repository::Backend, Difficulty,
+};
+
 #[derive(StructOpt, Debug)]
 pub struct Options {
     /// The folder to use as a base for all test maps.
@@ -25,8 +30,234 @@ pub struct Options {
     )]
     pub apikeys: PathBuf,
 
+    /// A file with API keys that show up by name in the logs, one
+    /// `<name>:<key>` per line.
+    #[structopt(long, name = "named api text file")]
+    pub named_apikeys: Option<PathBuf>,
+
     /// Enables developer mode. With developer mode enabled, you wont need an api key to call the
     /// api.
     #[structopt(short, long)]
     pub dev: bool,
+
+    /// The kind of database to store the maps in.
+    #[structopt(
+        long,
+        default_value = "structsy",
+        possible_values = &["structsy", "sqlite"]
+    )]
+    pub database_backend: Backend,
+
+    /// The database file. Defaults to `maps.persydb` for structsy and
+    /// `maps.sqlite` for SQLite.
+    #[structopt(long, name = "database file")]
+    pub database: Option<PathBuf>,
+
+    /// The folder to write backups to. Scheduled backups are disabled without it.
+    #[structopt(long, name = "backup directory")]
+    pub backup_dir: Option<PathBuf>,
+
+    /// The number of hours between two scheduled backups.
+    #[structopt(long, name = "hours", default_value = "24")]
+    pub backup_interval: u64,
+
+    /// The number of days for which the newest backup is kept.
+    #[structopt(long, name = "days", default_value = "7")]
+    pub keep_daily: usize,
+
+    /// The number of weeks for which the newest backup is kept.
+    #[structopt(long, name = "weeks", default_value = "4")]
+    pub keep_weekly: usize,
+
+    /// The free space needed in the map folders for the server to be ready.
+    #[structopt(long, name = "MiB", default_value = "100")]
+    pub min_free_space: u64,
+
+    /// The largest map file create downloads.
+    #[structopt(long, name = "size in MiB", default_value = "16")]
+    pub max_map_size: u64,
+
+    /// The most an archive uploaded with create may unpack to.
+    #[structopt(long, name = "unpacked MiB", default_value = "64")]
+    pub max_archive_size: u64,
+
+    /// How long create waits to connect to the server of a map file.
+    #[structopt(long, name = "connect seconds", default_value = "10")]
+    pub download_connect_timeout: u64,
+
+    /// How long create waits for the next part of a map file.
+    #[structopt(long, name = "read seconds", default_value = "30")]
+    pub download_read_timeout: u64,
+
+    /// How long create waits for the whole map file.
+    #[structopt(long, name = "total seconds", default_value = "120")]
+    pub download_timeout: u64,
+
+    /// A host create may download from, with its subdomains. Can be given
+    /// more than once. Without it, all hosts that aren't denied are allowed.
+    #[structopt(
+        long = "allow-host",
+        name = "allowed host",
+        number_of_values = 1
+    )]
+    pub allow_hosts: Vec<String>,
+
+    /// A host create may not download from, with its subdomains. Can be given
+    /// more than once.
+    #[structopt(
+        long = "deny-host",
+        name = "denied host",
+        number_of_values = 1
+    )]
+    pub deny_hosts: Vec<String>,
+
+    /// Lets create download from private, loopback and link local addresses.
+    #[structopt(long)]
+    pub allow_private_downloads: bool,
+
+    /// The number of redirects create follows.
+    #[structopt(long, name = "redirects", default_value = "5")]
+    pub max_redirects: usize,
+
+    /// A command that map configs may use. Can be given more than once.
+    /// Without it, tunings and the score and time limits are allowed.
+    #[structopt(
+        long = "config-command",
+        name = "command",
+        number_of_values = 1
+    )]
+    pub config_commands: Vec<String>,
+
+    /// The folder the map previews are cached in.
+    #[structopt(
+        long,
+        name = "preview directory",
+        default_value = "./previews"
+    )]
+    pub preview_dir: PathBuf,
+
+    /// The folder every uploaded map file is kept in, to compare revisions.
+    #[structopt(
+        long,
+        name = "revision directory",
+        default_value = "./revisions"
+    )]
+    pub revision_dir: PathBuf,
+
+    /// Changes the severity of a lint rule, e.g. `no_spawn=error`. Rules are
+    /// warnings by default. Uploads with findings of severity `error` fail,
+    /// `off` turns a rule off. Can be given more than once.
+    #[structopt(long = "lint", name = "rule=severity", number_of_values = 1)]
+    pub lint_levels: Vec<LintLevel>,
+
+    /// The mapres the servers have, as a folder with the images and sounds or
+    /// a JSON manifest. Maps using other external mapres can't be published.
+    #[structopt(long, name = "mapres folder or manifest")]
+    pub mapres: Option<PathBuf>,
+
+    /// Gives the servers of a difficulty, e.g. `easy=0.7`, which limits it to
+    /// the maps they run. The servers are `0.6`, `0.7` and `ddnet`. Can be
+    /// given more than once.
+    #[structopt(
+        long = "compatibility",
+        name = "difficulty=servers",
+        number_of_values = 1
+    )]
+    pub compatibility: Vec<Acceptance>,
+
+    /// The number of map uploads that run at the same time.
+    #[structopt(long, name = "workers", default_value = "2")]
+    pub upload_workers: usize,
+
+    /// The format of the log lines written to stderr.
+    #[structopt(
+        long,
+        default_value = "text",
+        possible_values = &["text", "json"]
+    )]
+    pub log_format: LogFormat,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Runs a maintenance task instead of starting the server.
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Compares the database with the map folders and reports differences.
+    Reconcile {
+        /// Fixes the differences, either by changing the files to match the
+        /// database or the database to match the files.
+        #[structopt(long, possible_values = &["trust-database", "trust-files"])]
+        fix: Option<FixPolicy>,
+    },
+
+    /// Creates records for all maps in an existing folder tree. Maps below a
+    /// `test` folder are imported as test maps, maps below a folder named
+    /// after a difficulty as published maps of that difficulty.
+    ImportMaps {
+        /// The folder to import, e.g. the published maps folder.
+        #[structopt(parse(from_os_str))]
+        folder: PathBuf,
+
+        /// Where the creation time of the maps comes from.
+        #[structopt(
+            long,
+            default_value = "votes",
+            possible_values = &["mtime", "votes"]
+        )]
+        created_at: CreatedAtSource,
+
+        /// The difficulty of test maps that aren't listed in a votes.cfg.
+        #[structopt(long)]
+        default_difficulty: Option<Difficulty>,
+
+        /// Only report what would be imported.
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Writes everything in the database to a JSON document.
+    Export {
+        /// The file to write to instead of stdout.
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+
+    /// Restores a JSON document written by `export` into an empty database.
+    Import {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+
+    /// Brings the database to the current version. This also happens on
+    /// every start.
+    Migrate {
+        /// Only report which migrations would run.
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Copies all maps into a new database, e.g. to switch to another
+    /// backend.
+    CopyDatabase {
+        /// The kind of database to create.
+        #[structopt(long, possible_values = &["structsy", "sqlite"])]
+        to: Backend,
+
+        /// The file of the new database, which must not exist yet.
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    /// Writes a backup of the database and the published maps to the backup
+    /// directory.
+    Backup,
+
+    /// Replaces the database and the published maps with the contents of a
+    /// backup. Mapmaster must not be running while doing this.
+    Restore {
+        #[structopt(parse(from_os_str))]
+        archive: PathBuf,
+    },
 }
@@ -0,0 +1,197 @@
+//! PNG overviews of maps, drawn from their game tiles.
+//!
+//! Every tile is a square in the color of its type, so no textures are
+//! needed. Previews are cached in `--preview-dir` by the hash of the map
+//! file, so a map that didn't change is drawn once.
+
+use std::{error::Error, path::PathBuf};
+
+use crate::{
+    datafile::Datafile,
+    diff::Region,
+    reconcile,
+    twmap::{self, Tiles},
+    CONFIG,
+};
+
+/// The longest side of a preview, unless a tile would get smaller than a
+/// pixel.
+const MAX_SIZE: usize = 1024;
+
+/// The largest a tile is drawn.
+const MAX_TILE_SIZE: usize = 8;
+
+const BACKGROUND: [u8; 3] = [34, 34, 40];
+
+/// The color of changed tiles in diffs, which no game tile has.
+const HIGHLIGHT: [u8; 3] = [255, 0, 255];
+
+/// The color of a game tile, or `None` for air and tiles that aren't shown.
+fn color(index: u8) -> Option<[u8; 3]> {
+    match index {
+        // Solid, death and unhookable.
+        1 => Some([150, 150, 150]),
+        2 => Some([200, 40, 40]),
+        3 => Some([80, 90, 140]),
+        // Freeze and deep freeze.
+        9 | 12 => Some([110, 190, 255]),
+        // Start and finish.
+        33 => Some([60, 200, 80]),
+        34 => Some([240, 200, 40]),
+        _ => None,
+    }
+}
+
+/// The pixels of a drawing, with the size of a tile in them.
+struct Canvas {
+    tile: usize,
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn fill(&mut self, x: usize, y: usize, color: [u8; 3]) {
+        for py in y * self.tile..(y + 1) * self.tile {
+            let row = py * self.width;
+            for px in x * self.tile..(x + 1) * self.tile {
+                let i = (row + px) * 3;
+                self.pixels[i..i + 3].copy_from_slice(&color);
+            }
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut png = Vec::new();
+        let mut encoder =
+            png::Encoder::new(&mut png, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        Ok(png)
+    }
+}
+
+/// Draws the layers on a grid of `width` times `height` tiles.
+fn draw(layers: &[Tiles], width: usize, height: usize) -> Canvas {
+    let tile = (MAX_SIZE / width.max(height)).clamp(1, MAX_TILE_SIZE);
+    let (w, h) = (width * tile, height * tile);
+    let mut canvas = Canvas {
+        tile,
+        width: w,
+        height: h,
+        pixels: BACKGROUND.repeat(w * h),
+    };
+    for y in 0..height {
+        for x in 0..width {
+            // Later layers are drawn in front of the earlier ones.
+            let color = layers
+                .iter()
+                .rev()
+                .filter(|l| x < l.width && y < l.height)
+                .find_map(|l| color(l.get(x, y)));
+            if let Some(color) = color {
+                canvas.fill(x, y, color);
+            }
+        }
+    }
+    canvas
+}
+
+/// Draws the game and front layers of a map into a PNG.
+pub fn render(file: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let layers = twmap::game_tiles(&Datafile::parse(file)?)?;
+    draw(&layers, layers[0].width, layers[0].height).encode()
+}
+
+/// Draws the layers dimmed, with the changed tiles of a diff and the
+/// outlines of their regions in a color of their own. `changed` has a value
+/// for every tile of the `size` grid, row by row.
+pub fn highlight(
+    layers: &[Tiles],
+    size: (usize, usize),
+    changed: &[bool],
+    regions: &[Region],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut canvas = draw(layers, size.0, size.1);
+    for value in &mut canvas.pixels {
+        *value /= 3;
+    }
+    for (i, _) in changed.iter().enumerate().filter(|(_, c)| **c) {
+        canvas.fill(i % size.0, i / size.0, HIGHLIGHT);
+    }
+    // The outlines keep single tiles visible in large maps.
+    for region in regions {
+        let x = region.x as usize * canvas.tile;
+        let y = region.y as usize * canvas.tile;
+        let w = region.width as usize * canvas.tile;
+        let h = region.height as usize * canvas.tile;
+        let (x0, y0) = (x.saturating_sub(1), y.saturating_sub(1));
+        let x1 = (x + w).min(canvas.width - 1);
+        let y1 = (y + h).min(canvas.height - 1);
+        for py in y0..=y1 {
+            for px in x0..=x1 {
+                if py == y0 || py == y1 || px == x0 || px == x1 {
+                    let i = (py * canvas.width + px) * 3;
+                    canvas.pixels[i..i + 3].copy_from_slice(&HIGHLIGHT);
+                }
+            }
+        }
+    }
+    canvas.encode()
+}
+
+/// The preview of a map file, drawn if it isn't cached yet. The file is
+/// hashed again instead of trusting the hash in the database, which is also
+/// restored from exports.
+pub fn cached(file: &[u8]) -> Result<PathBuf, Box<dyn Error>> {
+    let path = CONFIG
+        .preview_dir
+        .join(format!("{}.png", reconcile::sha256(file)));
+    if !path.exists() {
+        let png = render(file)?;
+        std::fs::create_dir_all(&CONFIG.preview_dir)?;
+        // Written next to the cache entry first, so a request never sees a
+        // half written preview.
+        let partial = path.with_extension("png.partial");
+        std::fs::write(&partial, png)?;
+        std::fs::rename(partial, &path)?;
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The size and RGB pixels of a PNG.
+    fn decode(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        let mut reader = png::Decoder::new(png).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut pixels).unwrap();
+        (frame.width, frame.height, pixels)
+    }
+
+    #[test]
+    fn draws_the_game_tiles() {
+        let png = render(include_bytes!("../tests/fixtures/race.map")).unwrap();
+        let (width, height, pixels) = decode(&png);
+        // 44 by 20 tiles of 8 pixels.
+        assert_eq!((width, height), (352, 160));
+        let pixel = |x: usize, y: usize| {
+            let i = (y * width as usize + x) * 3;
+            [pixels[i], pixels[i + 1], pixels[i + 2]]
+        };
+        assert_eq!(pixel(3, 3), color(1).unwrap());
+        assert_eq!(pixel(12, 12), BACKGROUND);
+        assert_eq!(pixel(2 * 8 + 4, 18 * 8 + 4), color(33).unwrap());
+        assert_eq!(pixel(10 * 8, 3 * 8), color(9).unwrap());
+    }
+
+    #[test]
+    fn refuses_files_that_are_not_maps() {
+        assert!(render(b"not a map").is_err());
+    }
+}
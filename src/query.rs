@@ -0,0 +1,192 @@
+//! Query parameters and paged answers of list routes.
+//!
+//! Rocket drops query parameters it can't parse, as if they weren't given,
+//! so a typo in a filter would list every map. The parameters here keep
+//! invalid values, which the routes answer with `INVALID_FILTER`.
+
+use rocket::{
+    form::{self, FromFormField, ValueField},
+    response::{self, Responder},
+    serde::{json::Json, Serialize},
+    Request, Response,
+};
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    okapi::openapi3::{Header, ParameterValue, RefOr, Responses},
+    response::OpenApiResponderInner,
+    OpenApiError,
+};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+
+use crate::error::{ApiError, ErrorCode};
+
+/// The header with the number of items of all pages.
+pub const TOTAL_COUNT: &str = "x-total-count";
+
+fn invalid(name: &str, value: &str) -> ApiError {
+    ApiError::new(
+        ErrorCode::InvalidFilter,
+        format!("\"{}\" is not a valid {}!", value, name),
+    )
+}
+
+/// A query parameter with one value.
+pub struct Checked<T>(Result<T, String>);
+
+impl<T> Checked<T> {
+    /// The value, or an error naming the parameter.
+    pub fn get(self, name: &str) -> Result<T, ApiError> {
+        self.0.map_err(|value| invalid(name, &value))
+    }
+}
+
+#[rocket::async_trait]
+impl<'v, T: FromFormField<'v>> FromFormField<'v> for Checked<T> {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        let value = field.value;
+        Ok(Checked(T::from_value(field).map_err(|_| value.to_string())))
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Checked<T> {
+    fn schema_name() -> String {
+        T::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        T::json_schema(gen)
+    }
+
+    fn is_referenceable() -> bool {
+        T::is_referenceable()
+    }
+}
+
+/// A query parameter with values separated by commas, like
+/// `map_state=new,approved`.
+pub struct List<T>(Result<Vec<T>, String>);
+
+impl<T> List<T> {
+    /// The values, or an error naming the parameter and the first invalid
+    /// value.
+    pub fn get(self, name: &str) -> Result<Vec<T>, ApiError> {
+        self.0.map_err(|value| invalid(name, &value))
+    }
+}
+
+#[rocket::async_trait]
+impl<'v, T: FromFormField<'v>> FromFormField<'v> for List<T> {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        Ok(List(
+            field
+                .value
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(|v| {
+                    T::from_value(ValueField::from_value(v))
+                        .map_err(|_| v.to_string())
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for List<T> {
+    fn schema_name() -> String {
+        format!("List_of_{}", T::schema_name())
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        // Shown as one value, the commas are up to the description.
+        T::json_schema(gen)
+    }
+
+    fn is_referenceable() -> bool {
+        false
+    }
+}
+
+/// A page of items, with the number of items of all pages in the
+/// `x-total-count` header.
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: usize,
+}
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Page<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        Response::build_from(Json(self.items).respond_to(request)?)
+            .raw_header(TOTAL_COUNT, self.total.to_string())
+            .ok()
+    }
+}
+
+impl<T: Serialize + JsonSchema + Send> OpenApiResponderInner for Page<T> {
+    fn responses(
+        gen: &mut OpenApiGenerator,
+    ) -> Result<Responses, OpenApiError> {
+        let mut responses = Json::<Vec<T>>::responses(gen)?;
+        let header = Header {
+            description: Some(
+                "The number of items of all pages, which is more than the \
+                 items of the answer when `offset` or `limit` are given."
+                    .to_string(),
+            ),
+            required: true,
+            deprecated: false,
+            allow_empty_value: false,
+            value: ParameterValue::Schema {
+                style: None,
+                explode: None,
+                allow_reserved: false,
+                schema: gen.json_schema::<usize>(),
+                example: None,
+                examples: None,
+            },
+            extensions: Default::default(),
+        };
+        for response in responses.responses.values_mut() {
+            if let RefOr::Object(response) = response {
+                response.headers.insert(
+                    TOTAL_COUNT.to_string(),
+                    RefOr::Object(header.clone()),
+                );
+            }
+        }
+        Ok(responses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Difficulty, MapState};
+
+    #[test]
+    fn reads_lists_of_values() {
+        let field = ValueField::from_value("new, Approved,,");
+        let states = List::<MapState>::from_value(field).unwrap();
+        assert_eq!(
+            states.get("map_state").unwrap(),
+            [MapState::New, MapState::Approved]
+        );
+
+        let field = ValueField::from_value("easy,medium");
+        let difficulties = List::<Difficulty>::from_value(field).unwrap();
+        let e = difficulties.get("difficulty").unwrap_err();
+        assert_eq!(e.code, ErrorCode::InvalidFilter);
+        assert!(e.msg.contains("\"medium\""));
+    }
+
+    #[test]
+    fn keeps_invalid_values() {
+        let field = ValueField::from_value("hard");
+        let checked = Checked::<Difficulty>::from_value(field).unwrap();
+        assert_eq!(checked.get("difficulty").unwrap(), Difficulty::Hard);
+
+        let field = ValueField::from_value("harder");
+        let checked = Checked::<Difficulty>::from_value(field).unwrap();
+        assert!(checked.get("difficulty").is_err());
+    }
+}
@@ -0,0 +1,360 @@
+//! Finds and fixes differences between the database and the map folders.
+
+use rocket::serde::Serialize;
+use schemars::JsonSchema;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+use strum::{EnumString, IntoEnumIterator};
+
+use crate::{
+    compat, get_current_time, journal, lint, map_file_path,
+    repository::{Change, MapFilter, MapRepository},
+    revisions::{self, Revision},
+    stats, Difficulty, Map, MapState, CONFIG,
+};
+
+/// Which side is considered correct when fixing differences.
+#[derive(
+    Serialize, JsonSchema, FromFormField, EnumString, Debug, Clone, Copy,
+)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum FixPolicy {
+    /// Move files to where the database expects them. Files without a record
+    /// are moved out of the way.
+    #[field(value = "trust-database")]
+    TrustDatabase,
+    /// Update the database to match the files. Files without a record get
+    /// one, records without a file are deleted.
+    #[field(value = "trust-files")]
+    TrustFiles,
+}
+
+pub fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    /// A map file without a database record.
+    OrphanFile { path: String },
+    /// A record whose file can't be found in any of the map folders.
+    MissingFile { name: String, expected: String },
+    /// A map file in a different folder than its state and difficulty say.
+    WrongFolder {
+        name: String,
+        expected: String,
+        found: String,
+    },
+    /// Another copy of a map file that is already in the right folder.
+    DuplicateFile { name: String, path: String },
+    /// A map file that changed since it was uploaded.
+    HashMismatch {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Issue::*;
+        match self {
+            OrphanFile { path } => write!(f, "orphan file {}", path),
+            MissingFile { name, expected } => {
+                write!(f, "missing file for {}, expected {}", name, expected)
+            }
+            WrongFolder {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} is in the wrong folder, expected {}, found {}",
+                name, expected, found
+            ),
+            DuplicateFile { name, path } => {
+                write!(f, "duplicate file for {} at {}", name, path)
+            }
+            HashMismatch {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} changed since upload, expected hash {}, found {}",
+                name, expected, actual
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Finding {
+    #[serde(flatten)]
+    pub issue: Issue,
+    pub fixed: bool,
+}
+
+#[derive(Serialize, JsonSchema, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Report {
+    pub policy: Option<FixPolicy>,
+    pub findings: Vec<Finding>,
+}
+
+/// The folder a map file was found in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    Test,
+    Published(Difficulty),
+}
+
+impl Location {
+    fn folder(&self) -> PathBuf {
+        match self {
+            Location::Test => CONFIG.test_map_folder.clone(),
+            Location::Published(difficulty) => {
+                CONFIG.public_map_folder.join(difficulty)
+            }
+        }
+    }
+
+    /// The map with its state and difficulty changed to match this location.
+    fn apply(&self, map: &Map) -> Map {
+        match *self {
+            Location::Test if map.state == MapState::Published => Map {
+                state: MapState::New,
+                ..map.clone()
+            },
+            Location::Test => map.clone(),
+            Location::Published(difficulty) => Map {
+                state: MapState::Published,
+                difficulty,
+                ..map.clone()
+            },
+        }
+    }
+}
+
+struct MapFile {
+    location: Location,
+    path: PathBuf,
+}
+
+fn list_map_files(
+    location: Location,
+    files: &mut HashMap<String, Vec<MapFile>>,
+) -> Result<(), std::io::Error> {
+    let folder = location.folder();
+    if !folder.exists() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(folder)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension() != Some("map".as_ref()) {
+            continue;
+        }
+        if let Some(name) = path.file_stem() {
+            files
+                .entry(name.to_string_lossy().into_owned())
+                .or_default()
+                .push(MapFile { location, path });
+        }
+    }
+    Ok(())
+}
+
+fn display(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn quarantine_path(name: &str, now: u64, n: usize) -> PathBuf {
+    CONFIG
+        .test_map_folder
+        .join("orphaned")
+        .join(format!("{}-{}-{}.map", name, now, n))
+}
+
+/// Compares every map record with the files in the map folders and, if a
+/// policy is given, fixes what it can.
+pub fn reconcile(
+    db: &dyn MapRepository,
+    policy: Option<FixPolicy>,
+) -> Result<Report, Box<dyn std::error::Error>> {
+    use FixPolicy::*;
+
+    let now = get_current_time().unwrap_or_default();
+    let mut files = HashMap::new();
+    list_map_files(Location::Test, &mut files)?;
+    for difficulty in Difficulty::iter() {
+        list_map_files(Location::Published(difficulty), &mut files)?;
+    }
+
+    let mut findings = Vec::new();
+    let mut changes = Vec::new();
+    let mut moves = Vec::new();
+
+    for map in db.list(&MapFilter::default())? {
+        let expected = map_file_path(&map);
+        let (at_expected, mut elsewhere): (Vec<_>, Vec<_>) = files
+            .remove(&map.name)
+            .unwrap_or_default()
+            .into_iter()
+            .partition(|f| f.path == expected);
+
+        // The fixed record, when the files are trusted.
+        let mut updated = None;
+        let current = if !at_expected.is_empty() {
+            Some(expected.clone())
+        } else if !elsewhere.is_empty() {
+            let found = elsewhere.remove(0);
+            findings.push(Finding {
+                issue: Issue::WrongFolder {
+                    name: map.name.clone(),
+                    expected: display(&expected),
+                    found: display(&found.path),
+                },
+                fixed: policy.is_some(),
+            });
+            match policy {
+                Some(TrustDatabase) => {
+                    moves.push(journal::FileMove::new(&found.path, &expected))
+                }
+                Some(TrustFiles) => {
+                    updated = Some(Map {
+                        last_changed: now,
+                        ..found.location.apply(&map)
+                    })
+                }
+                None => {}
+            }
+            Some(found.path)
+        } else {
+            findings.push(Finding {
+                issue: Issue::MissingFile {
+                    name: map.name.clone(),
+                    expected: display(&expected),
+                },
+                fixed: matches!(policy, Some(TrustFiles)),
+            });
+            if let Some(TrustFiles) = policy {
+                changes.push(Change::Delete(map.name.clone()));
+            }
+            None
+        };
+
+        for duplicate in elsewhere {
+            findings.push(Finding {
+                issue: Issue::DuplicateFile {
+                    name: map.name.clone(),
+                    path: display(&duplicate.path),
+                },
+                fixed: policy.is_some(),
+            });
+            if policy.is_some() {
+                let target = quarantine_path(&map.name, now, moves.len());
+                moves.push(journal::FileMove::new(duplicate.path, target));
+            }
+        }
+
+        if let (Some(path), Some(expected)) = (current, &map.sha256) {
+            let data = std::fs::read(&path)?;
+            let actual = sha256(&data);
+            if actual != *expected {
+                findings.push(Finding {
+                    issue: Issue::HashMismatch {
+                        name: map.name.clone(),
+                        expected: expected.clone(),
+                        actual: actual.clone(),
+                    },
+                    fixed: matches!(policy, Some(TrustFiles)),
+                });
+                if let Some(TrustFiles) = policy {
+                    let mut map = updated.unwrap_or_else(|| map.clone());
+                    // The file can be replaced by the next upload, so its
+                    // revision needs a copy.
+                    revisions::store(&data)?;
+                    revisions::add(&mut map.revisions, &actual, now);
+                    // The findings of the old file don't apply anymore.
+                    updated = Some(Map {
+                        sha256: Some(actual),
+                        lint: lint::lint(&data),
+                        stats: stats::read(&data).ok(),
+                        compatibility: compat::read(&data),
+                        ..map
+                    });
+                }
+            }
+        }
+
+        if let Some(updated) = updated {
+            changes.push(Change::Update {
+                name: map.name.clone(),
+                map: updated,
+            });
+        }
+    }
+
+    let mut orphans = files.into_iter().collect::<Vec<_>>();
+    orphans.sort_by(|a, b| a.0.cmp(&b.0));
+    for (name, found) in orphans {
+        for (i, file) in found.into_iter().enumerate() {
+            findings.push(Finding {
+                issue: Issue::OrphanFile {
+                    path: display(&file.path),
+                },
+                fixed: policy.is_some(),
+            });
+            match policy {
+                // Only the first copy can be registered, the others are
+                // duplicates of it.
+                Some(TrustFiles) if i == 0 => {
+                    let data = std::fs::read(&file.path)?;
+                    let sha256 = sha256(&data);
+                    revisions::store(&data)?;
+                    let map = Map {
+                        name: name.clone(),
+                        difficulty: Difficulty::Main,
+                        state: MapState::New,
+                        created_at: now,
+                        last_changed: now,
+                        sha256: Some(sha256.clone()),
+                        config: None,
+                        lint: lint::lint(&data),
+                        stats: stats::read(&data).ok(),
+                        compatibility: compat::read(&data),
+                        revisions: vec![Revision {
+                            sha256,
+                            uploaded_at: now,
+                        }],
+                    };
+                    changes.push(Change::Insert(file.location.apply(&map)));
+                }
+                Some(_) => {
+                    let target = quarantine_path(&name, now, moves.len());
+                    moves.push(journal::FileMove::new(file.path, target));
+                }
+                None => {}
+            }
+        }
+    }
+
+    if policy.is_some() {
+        journal::commit(db, changes, moves)?;
+        crate::update_votes(db)?;
+    }
+
+    Ok(Report { policy, findings })
+}
@@ -0,0 +1,266 @@
+//! Storage of the map records, independent of the database behind it.
+
+use fs2::FileExt;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use strum::EnumString;
+
+use crate::{
+    journal::JournalEntry, sqlite_repository::SqliteRepository,
+    structsy_repository::StructsyRepository, Difficulty, Map, MapState,
+    SortKey, SortOrder,
+};
+
+/// The database the maps are stored in.
+#[derive(EnumString, Debug, Clone, Copy, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum Backend {
+    Structsy,
+    Sqlite,
+}
+
+impl Backend {
+    pub fn default_path(&self) -> &'static str {
+        match self {
+            Backend::Structsy => "maps.persydb",
+            Backend::Sqlite => "maps.sqlite",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RepositoryError(String);
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RepositoryError {}
+
+impl From<structsy::StructsyError> for RepositoryError {
+    fn from(e: structsy::StructsyError) -> Self {
+        RepositoryError(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for RepositoryError {
+    fn from(e: rusqlite::Error) -> Self {
+        RepositoryError(e.to_string())
+    }
+}
+
+impl From<String> for RepositoryError {
+    fn from(msg: String) -> Self {
+        RepositoryError(msg)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, RepositoryError>;
+
+/// Which maps to list and in which order. Empty lists and missing bounds
+/// don't filter anything.
+#[derive(Default)]
+pub struct MapFilter {
+    pub name: Option<String>,
+    pub states: Vec<MapState>,
+    pub difficulties: Vec<Difficulty>,
+    pub created_after: Option<u64>,
+    pub created_before: Option<u64>,
+    pub changed_after: Option<u64>,
+    pub changed_before: Option<u64>,
+    /// Sorts by name if not set.
+    pub sort: Option<SortKey>,
+    /// Sorts ascending if not set.
+    pub order: Option<SortOrder>,
+    pub stats: StatsFilter,
+    /// The number of matching maps to skip.
+    pub offset: usize,
+    /// Lists all matching maps if not set.
+    pub limit: Option<usize>,
+}
+
+/// Bounds on the statistics of the map files. Maps without statistics only
+/// match when no bound is set.
+#[derive(Default)]
+pub struct StatsFilter {
+    pub min_file_size: Option<u64>,
+    pub max_file_size: Option<u64>,
+    pub min_embedded_image_size: Option<u64>,
+    pub max_embedded_image_size: Option<u64>,
+}
+
+fn within(value: u64, min: Option<u64>, max: Option<u64>) -> bool {
+    min.map(|min| value >= min).unwrap_or(true)
+        && max.map(|max| value <= max).unwrap_or(true)
+}
+
+impl StatsFilter {
+    pub fn matches(&self, map: &Map) -> bool {
+        match &map.stats {
+            Some(stats) => {
+                within(stats.file_size, self.min_file_size, self.max_file_size)
+                    && within(
+                        stats.embedded_image_size,
+                        self.min_embedded_image_size,
+                        self.max_embedded_image_size,
+                    )
+            }
+            None => [
+                self.min_file_size,
+                self.max_file_size,
+                self.min_embedded_image_size,
+                self.max_embedded_image_size,
+            ]
+            .iter()
+            .all(Option::is_none),
+        }
+    }
+}
+
+/// A change to the map records, applied together with others by
+/// `MapRepository::apply`.
+#[derive(Debug, Clone)]
+pub enum Change {
+    Insert(Map),
+    /// Replaces the map called `name`, which allows renaming it.
+    Update {
+        name: String,
+        map: Map,
+    },
+    Delete(String),
+}
+
+/// Identifies a journal entry within its repository.
+pub type JournalId = String;
+
+pub trait MapRepository: Send + Sync {
+    /// Finds a map by its exact name.
+    fn find(&self, name: &str) -> Result<Option<Map>>;
+
+    fn list(&self, filter: &MapFilter) -> Result<Vec<Map>>;
+
+    /// The number of maps matching the filter, regardless of its offset and
+    /// limit.
+    fn count(&self, filter: &MapFilter) -> Result<usize>;
+
+    /// Applies all changes or none of them. The journal entry of the changes
+    /// is removed in the same transaction, if there is one.
+    fn apply(
+        &self,
+        changes: &[Change],
+        journal_entry: Option<&JournalId>,
+    ) -> Result<()>;
+
+    fn add_journal_entry(&self, entry: &JournalEntry) -> Result<JournalId>;
+
+    fn journal_entries(&self) -> Result<Vec<(JournalId, JournalEntry)>>;
+
+    fn remove_journal_entry(&self, id: &JournalId) -> Result<()>;
+}
+
+/// Takes the lock of the database at `path`, which the server holds while it
+/// runs, so that commands replacing the database can tell it is in use. The
+/// lock is released when the file is closed.
+pub fn lock(path: &Path) -> std::io::Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(PathBuf::from(format!("{}.lock", path.display())))?;
+    file.try_lock_exclusive()?;
+    Ok(file)
+}
+
+/// Opens the repository at `path`, creating it if needed. Structsy databases
+/// have to be migrated to the current schema version before.
+pub fn open(backend: Backend, path: &Path) -> Result<Arc<dyn MapRepository>> {
+    Ok(match backend {
+        Backend::Structsy => Arc::new(StructsyRepository::open(path)?),
+        Backend::Sqlite => Arc::new(SqliteRepository::open(path)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::MapStats;
+
+    fn map(stats: Option<MapStats>) -> Map {
+        Map {
+            name: "alpha".to_string(),
+            difficulty: Difficulty::Easy,
+            state: MapState::New,
+            created_at: 1,
+            last_changed: 1,
+            sha256: None,
+            config: None,
+            lint: Vec::new(),
+            stats,
+            revisions: Vec::new(),
+            compatibility: None,
+        }
+    }
+
+    fn stats(file_size: u64, embedded_image_size: u64) -> Option<MapStats> {
+        Some(MapStats {
+            file_size,
+            width: 1,
+            height: 1,
+            groups: 1,
+            layers: 1,
+            embedded_images: 1,
+            external_images: 0,
+            embedded_image_size,
+            sounds: 0,
+            envelopes: 0,
+            tiles: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn locks_databases_once() {
+        let path = std::env::temp_dir()
+            .join(format!("mapmaster-lock-{}.sqlite", std::process::id()));
+        let held = lock(&path).unwrap();
+        assert!(lock(&path).is_err());
+        drop(held);
+        assert!(lock(&path).is_ok());
+        std::fs::remove_file(format!("{}.lock", path.display())).unwrap();
+    }
+
+    #[test]
+    fn filters_by_statistics() {
+        const MIB: u64 = 1024 * 1024;
+        let big_images = map(stats(3 * MIB, 2 * MIB));
+        let small = map(stats(MIB / 2, 0));
+        let unknown = map(None);
+
+        let any = StatsFilter::default();
+        assert!(any.matches(&big_images));
+        assert!(any.matches(&unknown));
+
+        let images_over_1_mib = StatsFilter {
+            min_embedded_image_size: Some(MIB),
+            ..StatsFilter::default()
+        };
+        assert!(images_over_1_mib.matches(&big_images));
+        assert!(!images_over_1_mib.matches(&small));
+        assert!(!images_over_1_mib.matches(&unknown));
+
+        // Both bounds are inclusive.
+        let exactly = StatsFilter {
+            min_file_size: Some(MIB / 2),
+            max_file_size: Some(MIB / 2),
+            ..StatsFilter::default()
+        };
+        assert!(exactly.matches(&small));
+        assert!(!exactly.matches(&big_images));
+        assert!(!exactly.matches(&unknown));
+    }
+}
@@ -0,0 +1,95 @@
+//! The files a map had over time, so a new upload can be compared with the
+//! ones before it.
+//!
+//! Every uploaded file is kept in `--revision-dir` under its hash, and the
+//! map lists the hashes in the order they were uploaded. Revisions are
+//! numbered from 1. Files are never deleted, as maps may share them.
+
+use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::{io, path::PathBuf};
+use structsy_derive::PersistentEmbedded;
+
+use crate::{
+    error::{ApiError, ErrorCode},
+    map_file_path, reconcile, Map, CONFIG,
+};
+
+#[derive(
+    Serialize, Deserialize, JsonSchema, PersistentEmbedded, Debug, Clone,
+)]
+#[serde(crate = "rocket::serde")]
+pub struct Revision {
+    pub sha256: String,
+    pub uploaded_at: u64,
+}
+
+/// Adds a revision, unless the file is the same as the latest one.
+pub fn add(revisions: &mut Vec<Revision>, sha256: &str, now: u64) {
+    if revisions.last().map(|r| r.sha256 != sha256).unwrap_or(true) {
+        revisions.push(Revision {
+            sha256: sha256.to_string(),
+            uploaded_at: now,
+        });
+    }
+}
+
+fn path(sha256: &str) -> PathBuf {
+    CONFIG.revision_dir.join(format!("{}.map", sha256))
+}
+
+/// Keeps an uploaded map file, if it isn't kept yet.
+pub fn store(file: &[u8]) -> io::Result<()> {
+    let path = path(&reconcile::sha256(file));
+    if !path.exists() {
+        std::fs::create_dir_all(&CONFIG.revision_dir)?;
+        let partial = path.with_extension("map.partial");
+        std::fs::write(&partial, file)?;
+        std::fs::rename(partial, &path)?;
+    }
+    Ok(())
+}
+
+/// A revision of a map with its file.
+pub struct RevisionFile {
+    pub number: usize,
+    pub data: Vec<u8>,
+}
+
+/// Reads the file of a revision. Maps uploaded before revisions were kept
+/// only have the map file itself, if it didn't change since.
+pub fn read(map: &Map, number: usize) -> Result<RevisionFile, ApiError> {
+    let revision = number
+        .checked_sub(1)
+        .and_then(|i| map.revisions.get(i))
+        .ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::RevisionNotFound,
+                format!(
+                    "{} has no revision {}, it has {}.",
+                    map.name,
+                    number,
+                    map.revisions.len()
+                ),
+            )
+        })?;
+    let data = match std::fs::read(path(&revision.sha256)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            match std::fs::read(map_file_path(map)) {
+                Ok(data) if reconcile::sha256(&data) == revision.sha256 => data,
+                _ => {
+                    return Err(ApiError::new(
+                        ErrorCode::RevisionNotFound,
+                        format!(
+                            "The file of revision {} of {} isn't kept.",
+                            number, map.name
+                        ),
+                    ))
+                }
+            }
+        }
+        Err(e) => return Err(ApiError::internal(e)),
+    };
+    Ok(RevisionFile { number, data })
+}
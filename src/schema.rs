@@ -0,0 +1,887 @@
+//! Versions of the database layout and the migrations between them.
+//!
+//! Structsy identifies a record type by its name and refuses to read records
+//! whose stored description doesn't match the type, so any change to `Map`
+//! makes existing databases unreadable. The layout version is stored in the
+//! database, and every older version has a migration that brings the records
+//! one version forward. The old record types live in the `v<n>` modules and
+//! must never change, as they describe databases that are already out there.
+
+use std::{error::Error, fmt, path::Path};
+use structsy::{Persistent, SRes, Structsy, StructsyTx};
+use structsy_derive::Persistent;
+
+use crate::{get_current_time, Map};
+
+/// The version of the layout this build of mapmaster writes.
+pub const CURRENT_VERSION: u32 = 7;
+
+#[derive(Persistent, Debug)]
+struct SchemaVersion {
+    version: u32,
+    migrated_at: u64,
+}
+
+/// The layout before versions were stored in the database.
+pub mod v0 {
+    use structsy_derive::Persistent;
+
+    use crate::{Difficulty, MapState};
+
+    #[derive(Persistent, Debug)]
+    pub struct Map {
+        #[index]
+        pub name: String,
+        pub difficulty: Difficulty,
+        pub state: MapState,
+        pub created_at: u64,
+        pub last_changed: u64,
+    }
+
+    /// The hash of a map file, which was kept apart from the map so the map
+    /// layout didn't have to change.
+    #[derive(Persistent, Debug)]
+    pub struct MapChecksum {
+        #[index]
+        pub name: String,
+        pub sha256: String,
+    }
+
+    impl From<Map> for super::v1::Map {
+        fn from(map: Map) -> Self {
+            super::v1::Map {
+                name: map.name,
+                difficulty: map.difficulty,
+                state: map.state,
+                created_at: map.created_at,
+                last_changed: map.last_changed,
+                sha256: None,
+            }
+        }
+    }
+}
+
+/// The layout before maps had configs.
+pub mod v1 {
+    use structsy_derive::Persistent;
+
+    use crate::{Difficulty, MapState};
+
+    #[derive(Persistent, Debug)]
+    pub struct Map {
+        #[index]
+        pub name: String,
+        pub difficulty: Difficulty,
+        pub state: MapState,
+        pub created_at: u64,
+        pub last_changed: u64,
+        pub sha256: Option<String>,
+    }
+
+    impl From<Map> for super::v2::Map {
+        fn from(map: Map) -> Self {
+            super::v2::Map {
+                name: map.name,
+                difficulty: map.difficulty,
+                state: map.state,
+                created_at: map.created_at,
+                last_changed: map.last_changed,
+                sha256: map.sha256,
+                config: None,
+            }
+        }
+    }
+}
+
+/// The layout before maps kept their lint findings.
+pub mod v2 {
+    use structsy_derive::Persistent;
+
+    use crate::{Difficulty, MapState};
+
+    #[derive(Persistent, Debug)]
+    pub struct Map {
+        #[index]
+        pub name: String,
+        pub difficulty: Difficulty,
+        pub state: MapState,
+        pub created_at: u64,
+        pub last_changed: u64,
+        pub sha256: Option<String>,
+        pub config: Option<String>,
+    }
+
+    impl From<Map> for super::v3::Map {
+        fn from(map: Map) -> Self {
+            super::v3::Map {
+                name: map.name,
+                difficulty: map.difficulty,
+                state: map.state,
+                created_at: map.created_at,
+                last_changed: map.last_changed,
+                sha256: map.sha256,
+                config: map.config,
+                lint: Vec::new(),
+            }
+        }
+    }
+}
+
+/// The layout before maps had statistics.
+pub mod v3 {
+    use structsy_derive::{Persistent, PersistentEmbedded};
+
+    use crate::{lint, Difficulty, MapState};
+
+    /// The rules as they were, which are part of the layout.
+    #[derive(PersistentEmbedded, Debug, Clone, Copy)]
+    pub enum Rule {
+        Unreadable,
+        NoSpawn,
+        NoStart,
+        NoFinish,
+        TeleWithoutTarget,
+        UnreachableFinish,
+    }
+
+    #[derive(PersistentEmbedded, Debug, Clone, Copy)]
+    pub enum Severity {
+        Off,
+        Warning,
+        Error,
+    }
+
+    #[derive(PersistentEmbedded, Debug, Clone)]
+    pub struct Finding {
+        pub rule: Rule,
+        pub severity: Severity,
+        pub message: String,
+        pub x: Option<u32>,
+        pub y: Option<u32>,
+    }
+
+    impl From<Finding> for lint::Finding {
+        fn from(finding: Finding) -> Self {
+            lint::Finding {
+                rule: match finding.rule {
+                    Rule::Unreadable => lint::Rule::Unreadable,
+                    Rule::NoSpawn => lint::Rule::NoSpawn,
+                    Rule::NoStart => lint::Rule::NoStart,
+                    Rule::NoFinish => lint::Rule::NoFinish,
+                    Rule::TeleWithoutTarget => lint::Rule::TeleWithoutTarget,
+                    Rule::UnreachableFinish => lint::Rule::UnreachableFinish,
+                },
+                severity: match finding.severity {
+                    Severity::Off => lint::Severity::Off,
+                    Severity::Warning => lint::Severity::Warning,
+                    Severity::Error => lint::Severity::Error,
+                },
+                message: finding.message,
+                x: finding.x,
+                y: finding.y,
+            }
+        }
+    }
+
+    #[derive(Persistent, Debug)]
+    pub struct Map {
+        #[index]
+        pub name: String,
+        pub difficulty: Difficulty,
+        pub state: MapState,
+        pub created_at: u64,
+        pub last_changed: u64,
+        pub sha256: Option<String>,
+        pub config: Option<String>,
+        pub lint: Vec<Finding>,
+    }
+
+    impl From<Map> for super::v4::Map {
+        fn from(map: Map) -> Self {
+            super::v4::Map {
+                name: map.name,
+                difficulty: map.difficulty,
+                state: map.state,
+                created_at: map.created_at,
+                last_changed: map.last_changed,
+                sha256: map.sha256,
+                config: map.config,
+                lint: map.lint,
+                stats: None,
+            }
+        }
+    }
+}
+
+/// The layout before the `missing_mapres` lint rule, as the rules are part
+/// of the layout.
+pub mod v4 {
+    use structsy_derive::Persistent;
+
+    use super::v3::Finding;
+    use crate::{stats::MapStats, Difficulty, MapState};
+
+    #[derive(Persistent, Debug)]
+    pub struct Map {
+        #[index]
+        pub name: String,
+        pub difficulty: Difficulty,
+        pub state: MapState,
+        pub created_at: u64,
+        pub last_changed: u64,
+        pub sha256: Option<String>,
+        pub config: Option<String>,
+        pub lint: Vec<Finding>,
+        pub stats: Option<MapStats>,
+    }
+
+    impl From<Map> for super::v5::Map {
+        fn from(map: Map) -> Self {
+            super::v5::Map {
+                name: map.name,
+                difficulty: map.difficulty,
+                state: map.state,
+                created_at: map.created_at,
+                last_changed: map.last_changed,
+                sha256: map.sha256,
+                config: map.config,
+                lint: map.lint.into_iter().map(Into::into).collect(),
+                stats: map.stats,
+            }
+        }
+    }
+}
+
+/// The layout before maps kept their revisions.
+pub mod v5 {
+    use structsy_derive::Persistent;
+
+    use crate::{
+        lint::Finding, revisions::Revision, stats::MapStats, Difficulty,
+        MapState,
+    };
+
+    #[derive(Persistent, Debug)]
+    pub struct Map {
+        #[index]
+        pub name: String,
+        pub difficulty: Difficulty,
+        pub state: MapState,
+        pub created_at: u64,
+        pub last_changed: u64,
+        pub sha256: Option<String>,
+        pub config: Option<String>,
+        pub lint: Vec<Finding>,
+        pub stats: Option<MapStats>,
+    }
+
+    impl From<Map> for super::v6::Map {
+        fn from(map: Map) -> Self {
+            // The file a map has is its only known revision.
+            let revisions = map
+                .sha256
+                .iter()
+                .map(|sha256| Revision {
+                    sha256: sha256.clone(),
+                    uploaded_at: map.last_changed,
+                })
+                .collect();
+            super::v6::Map {
+                name: map.name,
+                difficulty: map.difficulty,
+                state: map.state,
+                created_at: map.created_at,
+                last_changed: map.last_changed,
+                sha256: map.sha256,
+                config: map.config,
+                lint: map.lint,
+                stats: map.stats,
+                revisions,
+            }
+        }
+    }
+}
+
+/// The layout before maps knew which servers they run on.
+pub mod v6 {
+    use structsy_derive::Persistent;
+
+    use crate::{
+        lint::Finding, revisions::Revision, stats::MapStats, Difficulty,
+        MapState,
+    };
+
+    #[derive(Persistent, Debug)]
+    pub struct Map {
+        #[index]
+        pub name: String,
+        pub difficulty: Difficulty,
+        pub state: MapState,
+        pub created_at: u64,
+        pub last_changed: u64,
+        pub sha256: Option<String>,
+        pub config: Option<String>,
+        pub lint: Vec<Finding>,
+        pub stats: Option<MapStats>,
+        pub revisions: Vec<Revision>,
+    }
+
+    impl From<Map> for crate::Map {
+        fn from(map: Map) -> Self {
+            crate::Map {
+                name: map.name,
+                difficulty: map.difficulty,
+                state: map.state,
+                created_at: map.created_at,
+                last_changed: map.last_changed,
+                sha256: map.sha256,
+                config: map.config,
+                lint: map.lint,
+                stats: map.stats,
+                revisions: map.revisions,
+                compatibility: None,
+            }
+        }
+    }
+}
+
+struct Migration {
+    /// The version this migration starts from.
+    from: u32,
+    /// What the migration is going to change, for the report.
+    describe: fn(&Structsy) -> SRes<String>,
+    /// Converts the records of the database at the path. Must be safe to run
+    /// again after it was interrupted, as the new version is only stored
+    /// once it finished.
+    run: fn(&Path) -> SRes<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        describe: describe_v1,
+        run: migrate_v1,
+    },
+    Migration {
+        from: 1,
+        describe: describe_v2,
+        run: migrate_v2,
+    },
+    Migration {
+        from: 2,
+        describe: describe_v3,
+        run: migrate_v3,
+    },
+    Migration {
+        from: 3,
+        describe: describe_v4,
+        run: migrate_v4,
+    },
+    Migration {
+        from: 4,
+        describe: describe_v5,
+        run: migrate_v5,
+    },
+    Migration {
+        from: 5,
+        describe: describe_v6,
+        run: migrate_v6,
+    },
+    Migration {
+        from: 6,
+        describe: describe_v7,
+        run: migrate_v7,
+    },
+];
+
+/// Whether `T` is defined in the database with exactly its current layout.
+fn has_layout<T: Persistent>(db: &Structsy) -> SRes<bool> {
+    let description = T::get_description();
+    Ok(db.list_defined()?.any(|d| d == description))
+}
+
+/// The number of records of `T`, if it is defined with its current layout.
+fn count<T: Persistent + 'static>(db: &Structsy) -> SRes<usize> {
+    if !has_layout::<T>(db)? {
+        return Ok(0);
+    }
+    db.define::<T>()?;
+    Ok(db.query::<T>().fetch().count())
+}
+
+fn describe_v1(db: &Structsy) -> SRes<String> {
+    Ok(format!(
+        "convert {} maps and move {} checksums into them",
+        count::<v0::Map>(db)?,
+        count::<v0::MapChecksum>(db)?
+    ))
+}
+
+fn migrate_v1(path: &Path) -> SRes<()> {
+    let db = Structsy::open(path)?;
+    let converted = !has_layout::<v0::Map>(&db)?;
+    drop(db);
+
+    let prepare = Structsy::prepare_open(path)?;
+    if !converted {
+        prepare.migrate::<v0::Map, v1::Map>()?;
+    }
+    let db = prepare.open()?;
+    db.define::<v1::Map>()?;
+
+    if has_layout::<v0::MapChecksum>(&db)? {
+        db.define::<v0::MapChecksum>()?;
+        let maps = db.query::<v1::Map>().fetch().collect::<Vec<_>>();
+        let mut tx = db.begin()?;
+        for (_id, checksum) in db.query::<v0::MapChecksum>().fetch() {
+            let found = maps.iter().find(|(_, map)| map.name == checksum.name);
+            if let Some((id, map)) = found {
+                let map = v1::Map {
+                    sha256: Some(checksum.sha256),
+                    name: map.name.clone(),
+                    ..*map
+                };
+                tx.update(id, &map)?;
+            }
+        }
+        tx.commit()?;
+        db.undefine::<v0::MapChecksum>()?;
+    }
+    Ok(())
+}
+
+fn describe_v2(db: &Structsy) -> SRes<String> {
+    let count = count::<v1::Map>(db)?;
+    Ok(if count > 0 {
+        format!("add an empty config to {} maps", count)
+    } else {
+        "add an empty config to the maps".to_string()
+    })
+}
+
+fn migrate_v2(path: &Path) -> SRes<()> {
+    let db = Structsy::open(path)?;
+    let converted = !has_layout::<v1::Map>(&db)?;
+    drop(db);
+
+    let prepare = Structsy::prepare_open(path)?;
+    if !converted {
+        prepare.migrate::<v1::Map, v2::Map>()?;
+    }
+    prepare.open()?.define::<v2::Map>()?;
+    Ok(())
+}
+
+fn describe_v3(db: &Structsy) -> SRes<String> {
+    let count = count::<v2::Map>(db)?;
+    Ok(if count > 0 {
+        format!("add empty lint findings to {} maps", count)
+    } else {
+        "add empty lint findings to the maps".to_string()
+    })
+}
+
+fn migrate_v3(path: &Path) -> SRes<()> {
+    let db = Structsy::open(path)?;
+    let converted = !has_layout::<v2::Map>(&db)?;
+    drop(db);
+
+    let prepare = Structsy::prepare_open(path)?;
+    if !converted {
+        prepare.migrate::<v2::Map, v3::Map>()?;
+    }
+    prepare.open()?.define::<v3::Map>()?;
+    Ok(())
+}
+
+fn describe_v4(db: &Structsy) -> SRes<String> {
+    let count = count::<v3::Map>(db)?;
+    Ok(if count > 0 {
+        format!("add empty statistics to {} maps", count)
+    } else {
+        "add empty statistics to the maps".to_string()
+    })
+}
+
+fn migrate_v4(path: &Path) -> SRes<()> {
+    let db = Structsy::open(path)?;
+    let converted = !has_layout::<v3::Map>(&db)?;
+    drop(db);
+
+    let prepare = Structsy::prepare_open(path)?;
+    if !converted {
+        prepare.migrate::<v3::Map, v4::Map>()?;
+    }
+    prepare.open()?.define::<v4::Map>()?;
+    Ok(())
+}
+
+fn describe_v5(db: &Structsy) -> SRes<String> {
+    let count = count::<v4::Map>(db)?;
+    Ok(if count > 0 {
+        format!("add the missing_mapres lint rule to {} maps", count)
+    } else {
+        "add the missing_mapres lint rule to the maps".to_string()
+    })
+}
+
+fn migrate_v5(path: &Path) -> SRes<()> {
+    let db = Structsy::open(path)?;
+    let converted = !has_layout::<v4::Map>(&db)?;
+    drop(db);
+
+    let prepare = Structsy::prepare_open(path)?;
+    if !converted {
+        prepare.migrate::<v4::Map, v5::Map>()?;
+    }
+    prepare.open()?.define::<v5::Map>()?;
+    Ok(())
+}
+
+fn describe_v6(db: &Structsy) -> SRes<String> {
+    let count = count::<v5::Map>(db)?;
+    Ok(if count > 0 {
+        format!("add the current file of {} maps as their revision", count)
+    } else {
+        "add the current files of the maps as their revisions".to_string()
+    })
+}
+
+fn migrate_v6(path: &Path) -> SRes<()> {
+    let db = Structsy::open(path)?;
+    let converted = !has_layout::<v5::Map>(&db)?;
+    drop(db);
+
+    let prepare = Structsy::prepare_open(path)?;
+    if !converted {
+        prepare.migrate::<v5::Map, v6::Map>()?;
+    }
+    prepare.open()?.define::<v6::Map>()?;
+    Ok(())
+}
+
+fn describe_v7(db: &Structsy) -> SRes<String> {
+    let count = count::<v6::Map>(db)?;
+    Ok(if count > 0 {
+        format!("add an unknown compatibility to {} maps", count)
+    } else {
+        "add an unknown compatibility to the maps".to_string()
+    })
+}
+
+fn migrate_v7(path: &Path) -> SRes<()> {
+    let db = Structsy::open(path)?;
+    let converted = !has_layout::<v6::Map>(&db)?;
+    drop(db);
+
+    let prepare = Structsy::prepare_open(path)?;
+    if !converted {
+        prepare.migrate::<v6::Map, Map>()?;
+    }
+    prepare.open()?.define::<Map>()?;
+    Ok(())
+}
+
+fn read_version(db: &Structsy) -> SRes<Option<u32>> {
+    if !db.list_defined()?.any(|d| d.get_name() == "SchemaVersion") {
+        return Ok(None);
+    }
+    db.define::<SchemaVersion>()?;
+    Ok(db
+        .query::<SchemaVersion>()
+        .fetch()
+        .next()
+        .map(|(_id, v)| v.version))
+}
+
+fn write_version(db: &Structsy, version: u32) -> SRes<()> {
+    db.define::<SchemaVersion>()?;
+    let record = SchemaVersion {
+        version,
+        migrated_at: get_current_time().unwrap_or_default(),
+    };
+    let mut tx = db.begin()?;
+    match db.query::<SchemaVersion>().fetch().next() {
+        Some((id, _)) => tx.update(&id, &record)?,
+        None => {
+            tx.insert(&record)?;
+        }
+    }
+    tx.commit()
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub from: u32,
+    pub to: u32,
+    /// The migrations that ran, or would run on a dry run.
+    pub steps: Vec<String>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            writeln!(f, "{}", step)?;
+        }
+        if self.from == self.to {
+            write!(f, "The database is at version {}.", self.to)
+        } else {
+            write!(
+                f,
+                "Migrated the database from version {} to {}.",
+                self.from, self.to
+            )
+        }
+    }
+}
+
+/// Brings the database at `path` to the current version. With `dry_run`,
+/// only reports what would be done.
+pub fn migrate(path: &Path, dry_run: bool) -> Result<Report, Box<dyn Error>> {
+    let db = Structsy::open(path)?;
+    let version = match read_version(&db)? {
+        Some(version) => version,
+        // Everything written before versions were stored has a `Map`.
+        None if db.list_defined()?.any(|d| d.get_name() == "Map") => 0,
+        None => {
+            if !dry_run {
+                write_version(&db, CURRENT_VERSION)?;
+            }
+            CURRENT_VERSION
+        }
+    };
+    if version > CURRENT_VERSION {
+        return Err(format!(
+            "The database is at version {}, which is newer than the supported version {}!",
+            version, CURRENT_VERSION
+        )
+        .into());
+    }
+
+    let pending = MIGRATIONS
+        .iter()
+        .filter(|m| m.from >= version)
+        .collect::<Vec<_>>();
+    let mut report = Report {
+        from: version,
+        to: version,
+        steps: Vec::new(),
+    };
+    for migration in &pending {
+        report.steps.push(format!(
+            "{} -> {}: {}",
+            migration.from,
+            migration.from + 1,
+            (migration.describe)(&db)?
+        ));
+    }
+    drop(db);
+
+    if dry_run {
+        return Ok(report);
+    }
+    for migration in pending {
+        (migration.run)(path)?;
+        report.to = migration.from + 1;
+        write_version(&Structsy::open(path)?, report.to)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lint, structsy_repository::find_map};
+    use std::{
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    static COPIES: AtomicUsize = AtomicUsize::new(0);
+
+    /// Copies a fixture database, so the migration can change it.
+    fn fixture(name: &str) -> PathBuf {
+        let source = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name);
+        let target = std::env::temp_dir().join(format!(
+            "mapmaster-{}-{}-{}",
+            std::process::id(),
+            COPIES.fetch_add(1, Ordering::SeqCst),
+            name
+        ));
+        std::fs::copy(source, &target).unwrap();
+        target
+    }
+
+    fn open(path: &Path) -> Structsy {
+        let db = Structsy::open(path).unwrap();
+        db.define::<Map>().unwrap();
+        db
+    }
+
+    fn sha256(db: &Structsy, name: &str) -> Option<String> {
+        find_map(db, name).unwrap().1.sha256
+    }
+
+    #[test]
+    fn migrates_v0() {
+        let path = fixture("v0.persydb");
+        let report = migrate(&path, false).unwrap();
+        assert_eq!((report.from, report.to), (0, CURRENT_VERSION));
+
+        let db = open(&path);
+        assert_eq!(db.query::<Map>().fetch().count(), 3);
+        assert_eq!(sha256(&db, "alpha"), None);
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migrates_v0_with_checksums() {
+        let path = fixture("v0-checksums.persydb");
+        let report = migrate(&path, false).unwrap();
+        assert_eq!((report.from, report.to), (0, CURRENT_VERSION));
+
+        let db = open(&path);
+        assert_eq!(sha256(&db, "alpha").as_deref(), Some("aaaa"));
+        assert_eq!(sha256(&db, "bravo").as_deref(), Some("bbbb"));
+        assert_eq!(sha256(&db, "charlie"), None);
+        assert!(!db
+            .list_defined()
+            .unwrap()
+            .any(|d| d.get_name() == "MapChecksum"));
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migrates_v1() {
+        let path = fixture("v1.persydb");
+        let report = migrate(&path, false).unwrap();
+        assert_eq!((report.from, report.to), (1, CURRENT_VERSION));
+
+        let db = open(&path);
+        assert_eq!(db.query::<Map>().fetch().count(), 3);
+        assert_eq!(sha256(&db, "alpha").as_deref(), Some("aaaa"));
+        assert_eq!(find_map(&db, "alpha").unwrap().1.config, None);
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migrates_v2() {
+        let path = fixture("v2.persydb");
+        let report = migrate(&path, false).unwrap();
+        assert_eq!((report.from, report.to), (2, CURRENT_VERSION));
+
+        let (_, alpha) = find_map(&open(&path), "alpha").unwrap();
+        assert_eq!(alpha.config.as_deref(), Some("tune gravity 0.25"));
+        assert!(alpha.lint.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migrates_v3() {
+        let path = fixture("v3.persydb");
+        let report = migrate(&path, false).unwrap();
+        assert_eq!((report.from, report.to), (3, CURRENT_VERSION));
+
+        let (_, alpha) = find_map(&open(&path), "alpha").unwrap();
+        assert_eq!(alpha.lint[0].rule, lint::Rule::UnreachableFinish);
+        assert!(alpha.stats.is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migrates_v4() {
+        let path = fixture("v4.persydb");
+        let report = migrate(&path, false).unwrap();
+        assert_eq!((report.from, report.to), (4, CURRENT_VERSION));
+
+        let (_, alpha) = find_map(&open(&path), "alpha").unwrap();
+        assert_eq!(alpha.lint[0].rule, lint::Rule::UnreachableFinish);
+        assert_eq!(alpha.stats.unwrap().file_size, 1467);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migrates_v5() {
+        let path = fixture("v5.persydb");
+        let report = migrate(&path, false).unwrap();
+        assert_eq!((report.from, report.to), (5, CURRENT_VERSION));
+
+        let db = open(&path);
+        let (_, alpha) = find_map(&db, "alpha").unwrap();
+        assert_eq!(alpha.revisions.len(), 1);
+        assert_eq!(alpha.revisions[0].sha256, "aaaa");
+        assert_eq!(alpha.revisions[0].uploaded_at, alpha.last_changed);
+        assert!(find_map(&db, "charlie").unwrap().1.revisions.is_empty());
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migrates_v6() {
+        let path = fixture("v6.persydb");
+        let report = migrate(&path, false).unwrap();
+        assert_eq!((report.from, report.to), (6, CURRENT_VERSION));
+
+        let (_, alpha) = find_map(&open(&path), "alpha").unwrap();
+        assert_eq!(alpha.revisions[0].sha256, "aaaa");
+        assert_eq!(alpha.compatibility, None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let path = fixture("v0-checksums.persydb");
+        let report = migrate(&path, true).unwrap();
+        assert_eq!((report.from, report.to), (0, 0));
+        assert_eq!(
+            report.steps,
+            vec![
+                "0 -> 1: convert 3 maps and move 2 checksums into them",
+                "1 -> 2: add an empty config to the maps",
+                "2 -> 3: add empty lint findings to the maps",
+                "3 -> 4: add empty statistics to the maps",
+                "4 -> 5: add the missing_mapres lint rule to the maps",
+                "5 -> 6: add the current files of the maps as their revisions",
+                "6 -> 7: add an unknown compatibility to the maps",
+            ]
+        );
+
+        let db = Structsy::open(&path).unwrap();
+        assert!(has_layout::<v0::Map>(&db).unwrap());
+        assert!(has_layout::<v0::MapChecksum>(&db).unwrap());
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migrating_twice_does_nothing() {
+        let path = fixture("v0-checksums.persydb");
+        migrate(&path, false).unwrap();
+        let report = migrate(&path, false).unwrap();
+        assert_eq!(
+            (report.from, report.to),
+            (CURRENT_VERSION, CURRENT_VERSION)
+        );
+        assert!(report.steps.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resumes_interrupted_migration() {
+        let path = fixture("v0-checksums.persydb");
+        // The records were converted, but the version wasn't stored yet.
+        migrate_v1(&path).unwrap();
+        let report = migrate(&path, false).unwrap();
+        assert_eq!((report.from, report.to), (0, CURRENT_VERSION));
+
+        let db = open(&path);
+        assert_eq!(sha256(&db, "alpha").as_deref(), Some("aaaa"));
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+}
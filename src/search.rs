@@ -0,0 +1,146 @@
+//! Finds maps by the edit distance of their names and suggests close names
+//! when a map isn't found.
+
+use rocket::serde::Serialize;
+use schemars::JsonSchema;
+
+/// How a search term matched a value. Variants are ordered from best to worst.
+#[derive(
+    Serialize, JsonSchema, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy,
+)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum MatchKind {
+    Exact,
+    Prefix,
+    Substring,
+    Fuzzy,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct Score {
+    pub kind: MatchKind,
+    pub distance: usize,
+}
+
+/// The number of edits we tolerate before a value stops being a fuzzy match.
+fn max_distance(query: &str) -> usize {
+    (query.chars().count() / 3).max(1)
+}
+
+/// Levenshtein distance between two strings, counted in chars.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] =
+                substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
+
+/// Scores how well `value` matches `query`, or `None` if it doesn't match.
+///
+/// Both are compared case-insensitively. The distance of prefix and substring
+/// matches is the number of characters the value has on top of the query, so
+/// shorter values rank first.
+pub fn score(query: &str, value: &str) -> Option<Score> {
+    let query = query.trim().to_lowercase();
+    let value = value.to_lowercase();
+    if query.is_empty() {
+        return None;
+    }
+
+    let extra = value.chars().count().saturating_sub(query.chars().count());
+    if value == query {
+        Some(Score {
+            kind: MatchKind::Exact,
+            distance: 0,
+        })
+    } else if value.starts_with(&query) {
+        Some(Score {
+            kind: MatchKind::Prefix,
+            distance: extra,
+        })
+    } else if value.contains(&query) {
+        Some(Score {
+            kind: MatchKind::Substring,
+            distance: extra,
+        })
+    } else {
+        let distance = edit_distance(&query, &value);
+        if distance <= max_distance(&query) {
+            Some(Score {
+                kind: MatchKind::Fuzzy,
+                distance,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Scores every item by the best match of any of its values and returns the
+/// matching items, best first.
+pub fn rank<T, F, V>(query: &str, items: Vec<T>, values: F) -> Vec<(Score, T)>
+where
+    F: Fn(&T) -> V,
+    V: IntoIterator<Item = String>,
+{
+    let mut ranked = items
+        .into_iter()
+        .filter_map(|item| {
+            values(&item)
+                .into_iter()
+                .filter_map(|value| score(query, &value))
+                .min()
+                .map(|score| (score, item))
+        })
+        .collect::<Vec<_>>();
+    ranked.sort_by_key(|(score, _)| *score);
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_edits() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("flaw", "lawn"), 2);
+        assert_eq!(edit_distance("lawn", "flaw"), 2);
+        // Chars, not bytes.
+        assert_eq!(edit_distance("über", "uber"), 1);
+    }
+
+    #[test]
+    fn scores_matches() {
+        let score = |query, value| score(query, value).map(|s| s.kind);
+        assert_eq!(score("Jungle", "jungle"), Some(MatchKind::Exact));
+        assert_eq!(score("jun", "jungle"), Some(MatchKind::Prefix));
+        assert_eq!(score("ngl", "jungle"), Some(MatchKind::Substring));
+        assert_eq!(score("jungel", "jungle"), Some(MatchKind::Fuzzy));
+        assert_eq!(score("desert", "jungle"), None);
+        assert_eq!(score("  ", "jungle"), None);
+    }
+
+    #[test]
+    fn ranks_best_matches_first() {
+        let maps = vec!["jungle_2", "jungle", "the_jungle", "junlge", "desert"];
+        let ranked = rank("jungle", maps, |m| vec![m.to_string()]);
+        let names = ranked.iter().map(|(_, m)| *m).collect::<Vec<_>>();
+        assert_eq!(names, ["jungle", "jungle_2", "the_jungle", "junlge"]);
+    }
+}
@@ -0,0 +1,567 @@
+//! Stores the maps in an SQLite database, which can be inspected and queried
+//! with the usual SQL tools.
+
+use rocket::serde::DeserializeOwned;
+use rusqlite::{params, Connection, OptionalExtension, Row, ToSql};
+use std::{convert::TryFrom, path::Path, str::FromStr, sync::Mutex};
+
+use crate::{
+    journal::JournalEntry,
+    repository::{Change, JournalId, MapFilter, MapRepository, Result},
+    Map, SortKey, SortOrder,
+};
+
+/// The version of the tables, stored in the `user_version` of the database.
+const SCHEMA_VERSION: i64 = 6;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS maps (
+        name TEXT PRIMARY KEY NOT NULL,
+        difficulty TEXT NOT NULL,
+        state TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        last_changed INTEGER NOT NULL,
+        sha256 TEXT,
+        config TEXT,
+        lint TEXT NOT NULL DEFAULT '[]',
+        stats TEXT,
+        revisions TEXT NOT NULL DEFAULT '[]',
+        compatibility TEXT
+    );
+    CREATE TABLE IF NOT EXISTS journal (
+        id INTEGER PRIMARY KEY,
+        moves TEXT NOT NULL,
+        started_at INTEGER NOT NULL
+    );
+";
+
+/// The statements that bring the tables of version 1 up to the next version
+/// each. Versions 1 to 5 lacked configs, lint findings, statistics, revisions
+/// and the compatibility.
+const UPGRADES: &[&str] = &[
+    "ALTER TABLE maps ADD COLUMN config TEXT",
+    "ALTER TABLE maps ADD COLUMN lint TEXT NOT NULL DEFAULT '[]'",
+    "ALTER TABLE maps ADD COLUMN stats TEXT",
+    // The file a map has is its only known revision.
+    "ALTER TABLE maps ADD COLUMN revisions TEXT NOT NULL DEFAULT '[]';
+     UPDATE maps SET revisions = json_array(json_object(
+         'sha256', sha256, 'uploaded_at', last_changed))
+     WHERE sha256 IS NOT NULL",
+    "ALTER TABLE maps ADD COLUMN compatibility TEXT",
+];
+
+/// The sizes the maps can be filtered by, from their statistics.
+const FILE_SIZE: &str = "json_extract(stats, '$.file_size')";
+const EMBEDDED_IMAGE_SIZE: &str =
+    "json_extract(stats, '$.embedded_image_size')";
+
+const COLUMNS: &str =
+    "name, difficulty, state, created_at, last_changed, sha256, config, lint, \
+     stats, revisions, compatibility";
+
+pub struct SqliteRepository {
+    // A connection can't be shared between threads, so requests take turns.
+    connection: Mutex<Connection>,
+}
+
+impl SqliteRepository {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut connection = Connection::open(path)?;
+        let version: i64 =
+            connection
+                .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(format!(
+                "The database is at version {}, which is newer than the supported version {}!",
+                version, SCHEMA_VERSION
+            )
+            .into());
+        }
+        // A new database is at version 0 and gets the current tables below.
+        if version > 0 {
+            let pending = &UPGRADES[version as usize - 1..];
+            for (from, upgrade) in (version..).zip(pending) {
+                // Each upgrade is stored together with its version, so an
+                // interrupted one runs again as a whole.
+                let tx = connection.transaction()?;
+                tx.execute_batch(upgrade)?;
+                tx.pragma_update(None, "user_version", from + 1)?;
+                tx.commit()?;
+            }
+        }
+        let tx = connection.transaction()?;
+        tx.execute_batch(SCHEMA)?;
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        tx.commit()?;
+        Ok(SqliteRepository {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // A panic while holding the lock can't leave a transaction open, as
+        // it is rolled back when dropped.
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn parse<T: FromStr>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let value: String = row.get(index)?;
+    value.parse().map_err(|_| {
+        rusqlite::Error::FromSqlConversionFailure(
+            index,
+            rusqlite::types::Type::Text,
+            format!("\"{}\" is not a valid value", value).into(),
+        )
+    })
+}
+
+fn json<T: DeserializeOwned>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let value: String = row.get(index)?;
+    serde_json::from_str(&value).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(
+            index,
+            rusqlite::types::Type::Text,
+            e.into(),
+        )
+    })
+}
+
+fn to_map(row: &Row) -> rusqlite::Result<Map> {
+    Ok(Map {
+        name: row.get(0)?,
+        difficulty: parse(row, 1)?,
+        state: parse(row, 2)?,
+        created_at: row.get::<_, i64>(3)? as u64,
+        last_changed: row.get::<_, i64>(4)? as u64,
+        sha256: row.get(5)?,
+        config: row.get(6)?,
+        lint: json(row, 7)?,
+        stats: match row.get::<_, Option<String>>(8)? {
+            Some(_) => json(row, 8)?,
+            None => None,
+        },
+        revisions: json(row, 9)?,
+        compatibility: match row.get::<_, Option<String>>(10)? {
+            Some(_) => Some(parse(row, 10)?),
+            None => None,
+        },
+    })
+}
+
+/// The values of the columns in the order of `COLUMNS`.
+fn values(map: &Map) -> Vec<Box<dyn ToSql>> {
+    let difficulty: &'static str = map.difficulty.into();
+    let state: &'static str = map.state.into();
+    vec![
+        Box::new(map.name.clone()),
+        Box::new(difficulty),
+        Box::new(state),
+        Box::new(map.created_at as i64),
+        Box::new(map.last_changed as i64),
+        Box::new(map.sha256.clone()),
+        Box::new(map.config.clone()),
+        // Findings, statistics and revisions always serialize.
+        Box::new(
+            serde_json::to_string(&map.lint).unwrap_or_else(|_| "[]".into()),
+        ),
+        Box::new(
+            map.stats
+                .as_ref()
+                .and_then(|stats| serde_json::to_string(stats).ok()),
+        ),
+        Box::new(
+            serde_json::to_string(&map.revisions)
+                .unwrap_or_else(|_| "[]".into()),
+        ),
+        Box::new(map.compatibility.map(<&'static str>::from)),
+    ]
+}
+
+/// The `WHERE` clause of the maps matching the filter, and its values.
+fn conditions(filter: &MapFilter) -> (String, Vec<Box<dyn ToSql>>) {
+    let mut conditions = Vec::new();
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+
+    if let Some(name) = &filter.name {
+        values.push(Box::new(name.clone()));
+        conditions.push(format!("name = ?{}", values.len()));
+    }
+    for (column, list) in [
+        (
+            "state",
+            filter
+                .states
+                .iter()
+                .map(|s| <&str>::from(*s))
+                .collect::<Vec<_>>(),
+        ),
+        (
+            "difficulty",
+            filter
+                .difficulties
+                .iter()
+                .map(|d| <&str>::from(*d))
+                .collect(),
+        ),
+    ] {
+        if list.is_empty() {
+            continue;
+        }
+        let mut placeholders = Vec::new();
+        for value in list {
+            values.push(Box::new(value));
+            placeholders.push(format!("?{}", values.len()));
+        }
+        conditions.push(format!("{} IN ({})", column, placeholders.join(", ")));
+    }
+    for (column, operator, bound) in [
+        ("created_at", ">=", filter.created_after),
+        ("created_at", "<=", filter.created_before),
+        ("last_changed", ">=", filter.changed_after),
+        ("last_changed", "<=", filter.changed_before),
+        // Maps without statistics have no sizes, so they don't match any
+        // bound on them.
+        (FILE_SIZE, ">=", filter.stats.min_file_size),
+        (FILE_SIZE, "<=", filter.stats.max_file_size),
+        (
+            EMBEDDED_IMAGE_SIZE,
+            ">=",
+            filter.stats.min_embedded_image_size,
+        ),
+        (
+            EMBEDDED_IMAGE_SIZE,
+            "<=",
+            filter.stats.max_embedded_image_size,
+        ),
+    ] {
+        if let Some(bound) = bound {
+            values.push(Box::new(bound as i64));
+            conditions.push(format!(
+                "{} {} ?{}",
+                column,
+                operator,
+                values.len()
+            ));
+        }
+    }
+
+    if conditions.is_empty() {
+        (String::new(), values)
+    } else {
+        (format!(" WHERE {}", conditions.join(" AND ")), values)
+    }
+}
+
+impl MapRepository for SqliteRepository {
+    fn find(&self, name: &str) -> Result<Option<Map>> {
+        let sql = format!("SELECT {} FROM maps WHERE name = ?1", COLUMNS);
+        Ok(self
+            .connection()
+            .query_row(&sql, [name.to_lowercase()], to_map)
+            .optional()?)
+    }
+
+    fn list(&self, filter: &MapFilter) -> Result<Vec<Map>> {
+        let (conditions, mut values) = conditions(filter);
+        let column = match filter.sort.unwrap_or(SortKey::Name) {
+            SortKey::Name => "name",
+            SortKey::CreatedAt => "created_at",
+            SortKey::LastChanged => "last_changed",
+        };
+        let order = match filter.order.unwrap_or(SortOrder::Asc) {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+        // A negative limit is no limit.
+        let limit = filter
+            .limit
+            .map_or(-1, |l| i64::try_from(l).unwrap_or(i64::MAX));
+        values.push(Box::new(limit));
+        values.push(Box::new(filter.offset as i64));
+        let sql = format!(
+            "SELECT {} FROM maps{} ORDER BY {} {}, name LIMIT ?{} OFFSET ?{}",
+            COLUMNS,
+            conditions,
+            column,
+            order,
+            values.len() - 1,
+            values.len()
+        );
+
+        let connection = self.connection();
+        let mut statement = connection.prepare(&sql)?;
+        let params = values.iter().map(|v| v.as_ref()).collect::<Vec<_>>();
+        let maps = statement
+            .query_map(params.as_slice(), to_map)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(maps)
+    }
+
+    fn count(&self, filter: &MapFilter) -> Result<usize> {
+        let (conditions, values) = conditions(filter);
+        let sql = format!("SELECT COUNT(*) FROM maps{}", conditions);
+        let params = values.iter().map(|v| v.as_ref()).collect::<Vec<_>>();
+        let count: i64 =
+            self.connection()
+                .query_row(&sql, params.as_slice(), |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    fn apply(
+        &self,
+        changes: &[Change],
+        journal_entry: Option<&JournalId>,
+    ) -> Result<()> {
+        let mut connection = self.connection();
+        let tx = connection.transaction()?;
+        for change in changes {
+            match change {
+                Change::Insert(map) => {
+                    let values = values(map);
+                    tx.execute(
+                        &format!(
+                            "INSERT INTO maps ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                            COLUMNS
+                        ),
+                        rusqlite::params_from_iter(values.iter()),
+                    )?;
+                }
+                Change::Update { name, map } => {
+                    let mut values = values(map);
+                    values.push(Box::new(name.clone()));
+                    let updated = tx.execute(
+                        "UPDATE maps SET name = ?1, difficulty = ?2, state = ?3, \
+                         created_at = ?4, last_changed = ?5, sha256 = ?6, \
+                         config = ?7, lint = ?8, stats = ?9, revisions = ?10, \
+                         compatibility = ?11 WHERE name = ?12",
+                        rusqlite::params_from_iter(values.iter()),
+                    )?;
+                    if updated == 0 {
+                        return Err(
+                            format!("Map \"{}\" not found!", name).into()
+                        );
+                    }
+                }
+                Change::Delete(name) => {
+                    tx.execute("DELETE FROM maps WHERE name = ?1", [name])?;
+                }
+            }
+        }
+        if let Some(id) = journal_entry {
+            tx.execute("DELETE FROM journal WHERE id = ?1", [parse_id(id)?])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn add_journal_entry(&self, entry: &JournalEntry) -> Result<JournalId> {
+        let moves =
+            serde_json::to_string(&entry.moves).map_err(|e| e.to_string())?;
+        let connection = self.connection();
+        connection.execute(
+            "INSERT INTO journal (moves, started_at) VALUES (?1, ?2)",
+            params![moves, entry.started_at as i64],
+        )?;
+        Ok(connection.last_insert_rowid().to_string())
+    }
+
+    fn journal_entries(&self) -> Result<Vec<(JournalId, JournalEntry)>> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare("SELECT id, moves, started_at FROM journal ORDER BY id")?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter()
+            .map(|(id, moves, started_at)| {
+                let moves =
+                    serde_json::from_str(&moves).map_err(|e| e.to_string())?;
+                Ok((
+                    id.to_string(),
+                    JournalEntry {
+                        moves,
+                        started_at: started_at as u64,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    fn remove_journal_entry(&self, id: &JournalId) -> Result<()> {
+        self.connection()
+            .execute("DELETE FROM journal WHERE id = ?1", [parse_id(id)?])?;
+        Ok(())
+    }
+}
+
+fn parse_id(id: &str) -> Result<i64> {
+    id.parse()
+        .map_err(|_| format!("\"{}\" is not a journal entry!", id).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A database with the given tables, at version `version`.
+    fn old_database(name: &str, version: i64, tables: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "mapmaster-{}-{}.sqlite",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        let connection = Connection::open(&path).unwrap();
+        connection.execute_batch(tables).unwrap();
+        connection
+            .pragma_update(None, "user_version", version)
+            .unwrap();
+        path
+    }
+
+    fn version(path: &Path) -> i64 {
+        Connection::open(path)
+            .unwrap()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    const V1: &str = "
+        CREATE TABLE maps (
+            name TEXT PRIMARY KEY NOT NULL,
+            difficulty TEXT NOT NULL,
+            state TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            last_changed INTEGER NOT NULL,
+            sha256 TEXT
+        );
+        INSERT INTO maps VALUES ('alpha', 'easy', 'new', 1, 2, 'aaaa');
+    ";
+
+    #[test]
+    fn upgrades_old_tables() {
+        let path = old_database("v1", 1, V1);
+        let db = SqliteRepository::open(&path).unwrap();
+        let alpha = db.find("alpha").unwrap().unwrap();
+        assert_eq!(alpha.revisions[0].sha256, "aaaa");
+        assert_eq!(alpha.revisions[0].uploaded_at, 2);
+        assert!(alpha.lint.is_empty());
+        drop(db);
+        assert_eq!(version(&path), SCHEMA_VERSION);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_version_of_failed_upgrades() {
+        // The compatibility column is already there, so its upgrade fails
+        // after the earlier ones were stored.
+        let tables = format!(
+            "{}
+             ALTER TABLE maps ADD COLUMN config TEXT;
+             ALTER TABLE maps ADD COLUMN lint TEXT NOT NULL DEFAULT '[]';
+             ALTER TABLE maps ADD COLUMN stats TEXT;
+             ALTER TABLE maps ADD COLUMN compatibility TEXT;",
+            V1
+        );
+        let path = old_database("v4", 4, &tables);
+        assert!(SqliteRepository::open(&path).is_err());
+        assert_eq!(version(&path), 5);
+        std::fs::remove_file(path).unwrap();
+
+        // A failed upgrade leaves none of its changes behind.
+        let tables = V1.replace("last_changed INTEGER NOT NULL,", "");
+        let tables = tables.replace("1, 2, 'aaaa'", "1, 'aaaa'");
+        let path = old_database("broken", 1, &tables);
+        assert!(SqliteRepository::open(&path).is_err());
+        assert_eq!(version(&path), 4);
+        let connection = Connection::open(&path).unwrap();
+        let revisions: i64 = connection
+            .query_row(
+                "SELECT count(*) FROM pragma_table_info('maps') \
+                 WHERE name = 'revisions'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(revisions, 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn map(name: &str, file_size: Option<u64>) -> Map {
+        Map {
+            name: name.to_string(),
+            difficulty: crate::Difficulty::Easy,
+            state: crate::MapState::New,
+            created_at: 1,
+            last_changed: 1,
+            sha256: None,
+            config: None,
+            lint: Vec::new(),
+            stats: file_size.map(|file_size| crate::stats::MapStats {
+                file_size,
+                width: 1,
+                height: 1,
+                groups: 1,
+                layers: 1,
+                embedded_images: 0,
+                external_images: 0,
+                embedded_image_size: 0,
+                sounds: 0,
+                envelopes: 0,
+                tiles: Vec::new(),
+            }),
+            revisions: Vec::new(),
+            compatibility: None,
+        }
+    }
+
+    #[test]
+    fn pages_and_counts_in_the_query() {
+        let path = old_database("list", 0, "");
+        let db = SqliteRepository::open(&path).unwrap();
+        let maps = [
+            map("alpha", Some(100)),
+            map("beta", Some(2000)),
+            map("gamma", None),
+            map("delta", Some(3000)),
+        ];
+        let inserts =
+            maps.iter().cloned().map(Change::Insert).collect::<Vec<_>>();
+        db.apply(&inserts, None).unwrap();
+
+        let names = |filter: &MapFilter| {
+            let maps = db.list(filter).unwrap();
+            maps.into_iter().map(|m| m.name).collect::<Vec<_>>()
+        };
+        let mut filter = MapFilter {
+            offset: 1,
+            limit: Some(2),
+            ..MapFilter::default()
+        };
+        assert_eq!(names(&filter), ["beta", "delta"]);
+        assert_eq!(db.count(&filter).unwrap(), 4);
+
+        filter.stats.min_file_size = Some(1000);
+        filter.offset = 0;
+        assert_eq!(names(&filter), ["beta", "delta"]);
+        assert_eq!(db.count(&filter).unwrap(), 2);
+
+        filter.stats.max_file_size = Some(2000);
+        filter.limit = None;
+        assert_eq!(names(&filter), ["beta"]);
+        assert_eq!(db.count(&filter).unwrap(), 1);
+
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+//! Numbers about a map file, taken when it is uploaded, to compare maps by
+//! more than their difficulty.
+
+use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use structsy_derive::PersistentEmbedded;
+
+use crate::{
+    datafile::{self, Datafile},
+    twmap,
+};
+
+/// How often a game tile is used in the game and front layers.
+#[derive(
+    Serialize, Deserialize, JsonSchema, PersistentEmbedded, Debug, Clone,
+)]
+#[serde(crate = "rocket::serde")]
+pub struct TileCount {
+    pub index: u8,
+    pub count: u32,
+}
+
+#[derive(
+    Serialize, Deserialize, JsonSchema, PersistentEmbedded, Debug, Clone,
+)]
+#[serde(crate = "rocket::serde")]
+pub struct MapStats {
+    /// The size of the map file in bytes.
+    pub file_size: u64,
+    /// The size of the game layer in tiles.
+    pub width: u32,
+    pub height: u32,
+    pub groups: u32,
+    pub layers: u32,
+    pub embedded_images: u32,
+    pub external_images: u32,
+    /// The bytes the embedded images take up in the map file.
+    pub embedded_image_size: u64,
+    pub sounds: u32,
+    pub envelopes: u32,
+    /// The game tiles used in the map, without air, by their index.
+    pub tiles: Vec<TileCount>,
+}
+
+/// Reads the statistics of a map file.
+pub fn read(file: &[u8]) -> datafile::Result<MapStats> {
+    let df = Datafile::parse(file)?;
+
+    let layers = twmap::game_tiles(&df)?;
+    let mut counts = [0u32; 256];
+    for layer in &layers {
+        for index in &layer.indices {
+            counts[*index as usize] += 1;
+        }
+    }
+
+    let images = twmap::images(&df)?;
+    let embedded = images.iter().filter_map(|i| i.data).collect::<Vec<_>>();
+    let count = |type_id| df.items(type_id).count() as u32;
+    Ok(MapStats {
+        file_size: file.len() as u64,
+        width: layers[0].width as u32,
+        height: layers[0].height as u32,
+        groups: count(twmap::ITEM_GROUP),
+        layers: count(twmap::ITEM_LAYER),
+        embedded_images: embedded.len() as u32,
+        external_images: images.iter().filter(|i| i.external).count() as u32,
+        embedded_image_size: embedded
+            .iter()
+            .filter_map(|data| df.stored_size(*data))
+            .sum::<usize>() as u64,
+        sounds: count(twmap::ITEM_SOUND),
+        envelopes: count(twmap::ITEM_ENVELOPE),
+        tiles: counts
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, count)| **count > 0)
+            .map(|(index, count)| TileCount {
+                index: index as u8,
+                count: *count,
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{datafile::testing::build, twmap::*};
+
+    fn counts(stats: &MapStats) -> Vec<(u8, u32)> {
+        stats.tiles.iter().map(|t| (t.index, t.count)).collect()
+    }
+
+    #[test]
+    fn reads_the_fixture() {
+        let file = include_bytes!("../tests/fixtures/race.map");
+        let stats = read(file).unwrap();
+        assert_eq!(stats.file_size, file.len() as u64);
+        assert_eq!((stats.width, stats.height), (44, 20));
+        assert_eq!((stats.groups, stats.layers), (0, 1));
+        assert_eq!((stats.embedded_images, stats.external_images), (0, 1));
+        assert_eq!(stats.embedded_image_size, 0);
+        // The walls around the map and in its middle, a freeze tile, the
+        // start, the finish and a spawn.
+        assert_eq!(
+            counts(&stats),
+            [(1, 82 + 8), (9, 1), (33, 1), (34, 1), (192, 1)]
+        );
+    }
+
+    #[test]
+    fn counts_items_and_embedded_images() {
+        let mut layer = vec![0, LAYER_TILES, 0, 3, 2, 2, TILES_GAME];
+        layer.extend([255, 255, 255, 255, -1, 0, -1, 0]);
+        let tiles = [1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0];
+        let pixels = (0..4096).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        let file = build(
+            &[
+                (ITEM_GROUP, vec![]),
+                (ITEM_LAYER, layer),
+                (ITEM_IMAGE, vec![1, 64, 16, 1, 1, -1]),
+                (ITEM_IMAGE, vec![1, 32, 32, 0, 1, 2]),
+                (ITEM_ENVELOPE, vec![]),
+                (ITEM_ENVELOPE, vec![]),
+                (ITEM_SOUND, vec![]),
+            ],
+            &[&tiles, b"grass\0", &pixels],
+        );
+        let stats = read(&file).unwrap();
+        assert_eq!((stats.width, stats.height), (2, 2));
+        assert_eq!((stats.groups, stats.layers), (1, 1));
+        assert_eq!((stats.embedded_images, stats.external_images), (1, 1));
+        assert_eq!((stats.envelopes, stats.sounds), (2, 1));
+        assert_eq!(counts(&stats), [(1, 2), (2, 1)]);
+
+        // The compressed size, not the size of the pixels.
+        let df = Datafile::parse(&file).unwrap();
+        assert_eq!(
+            stats.embedded_image_size,
+            df.stored_size(2).unwrap() as u64
+        );
+        assert!(stats.embedded_image_size < pixels.len() as u64);
+    }
+
+    #[test]
+    fn refuses_maps_without_game_layer() {
+        let file = build(&[(ITEM_IMAGE, vec![1, 64, 16, 1, 0, -1])], &[]);
+        assert!(read(&file).is_err());
+    }
+}
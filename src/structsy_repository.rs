@@ -0,0 +1,197 @@
+//! Stores the maps in a structsy database, which needs no setup.
+
+use std::{
+    ops::{Bound, RangeBounds},
+    path::Path,
+};
+use structsy::{Operators, Order, Ref, Structsy, StructsyTx};
+use structsy_derive::queries;
+
+use crate::{
+    journal::JournalEntry,
+    repository::{Change, JournalId, MapFilter, MapRepository, Result},
+    Difficulty, Map, MapState, SortKey, SortOrder,
+};
+
+#[queries(Map)]
+trait MapByName {
+    fn by_name(self, name: &str) -> Self;
+    fn by_state(self, state: MapState) -> Self;
+    fn by_difficulty(self, difficulty: Difficulty) -> Self;
+    fn created_between<R: RangeBounds<u64>>(self, created_at: R) -> Self;
+    fn changed_between<R: RangeBounds<u64>>(self, last_changed: R) -> Self;
+    fn order_by_name(self, name: Order) -> Self;
+    fn order_by_created_at(self, created_at: Order) -> Self;
+    fn order_by_last_changed(self, last_changed: Order) -> Self;
+}
+
+impl From<SortOrder> for Order {
+    fn from(order: SortOrder) -> Self {
+        match order {
+            SortOrder::Asc => Order::Asc,
+            SortOrder::Desc => Order::Desc,
+        }
+    }
+}
+
+fn to_range(
+    after: Option<u64>,
+    before: Option<u64>,
+) -> (Bound<u64>, Bound<u64>) {
+    (
+        after.map_or(Bound::Unbounded, Bound::Included),
+        before.map_or(Bound::Unbounded, Bound::Included),
+    )
+}
+
+pub fn find_map(db: &Structsy, name: &str) -> Option<(Ref<Map>, Map)> {
+    let query = db.query::<Map>().by_name(&name.to_lowercase());
+    query.fetch().next()
+}
+
+pub struct StructsyRepository {
+    db: Structsy,
+}
+
+impl StructsyRepository {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = Structsy::open(path)?;
+        db.define::<Map>()?;
+        db.define::<JournalEntry>()?;
+        Ok(StructsyRepository { db })
+    }
+
+    /// The maps matching the filter, in its order.
+    fn matching<'a>(
+        &self,
+        filter: &'a MapFilter,
+    ) -> impl Iterator<Item = Map> + 'a {
+        let query = self.db.query::<Map>();
+
+        let query = if let Some(name) = &filter.name {
+            query.by_name(name)
+        } else {
+            query
+        };
+
+        let query = if filter.states.is_empty() {
+            query
+        } else {
+            query
+                .or(|or| filter.states.iter().fold(or, |or, s| or.by_state(*s)))
+        };
+
+        let query = if filter.difficulties.is_empty() {
+            query
+        } else {
+            query.or(|or| {
+                filter
+                    .difficulties
+                    .iter()
+                    .fold(or, |or, d| or.by_difficulty(*d))
+            })
+        };
+
+        let query = query
+            .created_between(to_range(
+                filter.created_after,
+                filter.created_before,
+            ))
+            .changed_between(to_range(
+                filter.changed_after,
+                filter.changed_before,
+            ));
+
+        let order = filter.order.unwrap_or(SortOrder::Asc).into();
+        let query = match filter.sort.unwrap_or(SortKey::Name) {
+            SortKey::Name => query.order_by_name(order),
+            SortKey::CreatedAt => query.order_by_created_at(order),
+            SortKey::LastChanged => query.order_by_last_changed(order),
+        };
+
+        // The statistics are optional, which the queries can't look into.
+        query
+            .fetch()
+            .map(|(_id, map)| map)
+            .filter(move |map| filter.stats.matches(map))
+    }
+}
+
+impl MapRepository for StructsyRepository {
+    fn find(&self, name: &str) -> Result<Option<Map>> {
+        Ok(find_map(&self.db, name).map(|(_id, map)| map))
+    }
+
+    fn list(&self, filter: &MapFilter) -> Result<Vec<Map>> {
+        Ok(self
+            .matching(filter)
+            .skip(filter.offset)
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+
+    fn count(&self, filter: &MapFilter) -> Result<usize> {
+        Ok(self.matching(filter).count())
+    }
+
+    fn apply(
+        &self,
+        changes: &[Change],
+        journal_entry: Option<&JournalId>,
+    ) -> Result<()> {
+        let mut tx = self.db.begin()?;
+        for change in changes {
+            match change {
+                Change::Insert(map) => {
+                    tx.insert(map)?;
+                }
+                Change::Update { name, map } => {
+                    let found = tx.query::<Map>().by_name(name).fetch().next();
+                    let (id, _) = found.ok_or_else(|| {
+                        format!("Map \"{}\" not found!", name)
+                    })?;
+                    tx.update(&id, map)?;
+                }
+                Change::Delete(name) => {
+                    let found = tx.query::<Map>().by_name(name).fetch().next();
+                    if let Some((id, _)) = found {
+                        tx.delete(&id)?;
+                    }
+                }
+            }
+        }
+        if let Some(id) = journal_entry {
+            tx.delete(&parse_journal_id(id)?)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn add_journal_entry(&self, entry: &JournalEntry) -> Result<JournalId> {
+        let mut tx = self.db.begin()?;
+        let id = tx.insert(entry)?;
+        tx.commit()?;
+        Ok(id.to_string())
+    }
+
+    fn journal_entries(&self) -> Result<Vec<(JournalId, JournalEntry)>> {
+        Ok(self
+            .db
+            .query::<JournalEntry>()
+            .fetch()
+            .map(|(id, entry)| (id.to_string(), entry))
+            .collect())
+    }
+
+    fn remove_journal_entry(&self, id: &JournalId) -> Result<()> {
+        let mut tx = self.db.begin()?;
+        tx.delete(&parse_journal_id(id)?)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn parse_journal_id(id: &str) -> Result<Ref<JournalEntry>> {
+    id.parse()
+        .map_err(|_| format!("\"{}\" is not a journal entry!", id).into())
+}
@@ -0,0 +1,392 @@
+//! The map items of a teeworlds datafile: groups, layers and their tiles.
+//!
+//! Only what mapmaster looks at is read. The layouts are the ones of
+//! teeworlds and DDNet, which add fields at the end of an item and raise its
+//! version, so shorter items of older versions are fine.
+
+use std::convert::TryFrom;
+
+use crate::datafile::{Datafile, DatafileError, Result};
+
+pub const ITEM_INFO: u16 = 1;
+pub const ITEM_IMAGE: u16 = 2;
+pub const ITEM_ENVELOPE: u16 = 3;
+pub const ITEM_GROUP: u16 = 4;
+pub const ITEM_LAYER: u16 = 5;
+/// DDNet only.
+pub const ITEM_SOUND: u16 = 7;
+
+pub const LAYER_TILES: i32 = 2;
+
+/// Set on the tile layer that holds the game tiles.
+pub const TILES_GAME: i32 = 1;
+/// Set on the DDNet layer with teleporters.
+pub const TILES_TELE: i32 = 2;
+/// Set on the DDNet layer with game tiles in front of the players.
+pub const TILES_FRONT: i32 = 8;
+
+/// Since this version, tile layers have names.
+const TILEMAP_NAME_VERSION: i32 = 3;
+/// Since this version, runs of equal tiles are stored once with a count.
+const TILEMAP_SKIP_VERSION: i32 = 4;
+
+/// The largest number of tiles a layer may have.
+pub const MAX_TILES: usize = 4096 * 4096;
+
+/// A tile layer, without its tiles.
+pub struct TileLayer {
+    pub version: i32,
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+    pub flags: i32,
+    pub data: i32,
+    /// The data of a DDNet front layer, which isn't in `data`.
+    pub front: Option<i32>,
+    /// The data of a DDNet tele layer.
+    pub tele: Option<i32>,
+}
+
+/// The tile indices of a layer, row by row.
+pub struct Tiles {
+    pub width: usize,
+    pub height: usize,
+    pub indices: Vec<u8>,
+}
+
+impl Tiles {
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.indices[y * self.width + x]
+    }
+}
+
+/// An image of a map, which is either in the map file or one of the mapres
+/// of the client.
+pub struct Image {
+    pub external: bool,
+    /// The data block with the name of the image.
+    pub name: i32,
+    /// The data block with the pixels, if the image is in the map file.
+    pub data: Option<i32>,
+}
+
+fn field(item: &[i32], index: usize) -> Result<i32> {
+    item.get(index)
+        .copied()
+        .ok_or_else(|| "An item is cut off.".into())
+}
+
+pub fn images(df: &Datafile) -> Result<Vec<Image>> {
+    df.items(ITEM_IMAGE)
+        .map(|item| {
+            let external = field(&item.data, 3)? != 0;
+            Ok(Image {
+                external,
+                name: field(&item.data, 4)?,
+                data: Some(field(&item.data, 5)?).filter(|_| !external),
+            })
+        })
+        .collect()
+}
+
+/// Decodes a short string stored in item fields, four bytes per field with
+/// 128 added to each byte. The last byte is always cleared.
+fn ints_to_str(ints: &[i32]) -> String {
+    let bytes = ints
+        .iter()
+        .flat_map(|i| i.to_be_bytes())
+        .take(ints.len() * 4 - 1)
+        .map(|b| b.wrapping_sub(128))
+        .take_while(|b| *b != 0)
+        .collect::<Vec<_>>();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The tile layers of a map, in the order they are drawn.
+pub fn tile_layers(df: &Datafile) -> Result<Vec<TileLayer>> {
+    let mut layers = Vec::new();
+    for item in df.items(ITEM_LAYER) {
+        let item = &item.data;
+        if field(item, 1)? != LAYER_TILES {
+            continue;
+        }
+        let (width, height) = (field(item, 4)?, field(item, 5)?);
+        let size = |v: i32| {
+            usize::try_from(v).ok().filter(|v| *v > 0).ok_or_else(|| {
+                DatafileError(format!("A layer is {} tiles wide.", v))
+            })
+        };
+        let flags = field(item, 6)?;
+        let version = field(item, 3)?;
+        let name = match item.get(15..18) {
+            Some(name) if version >= TILEMAP_NAME_VERSION => ints_to_str(name),
+            _ => String::new(),
+        };
+        layers.push(TileLayer {
+            version,
+            name,
+            width: size(width)?,
+            height: size(height)?,
+            flags,
+            data: field(item, 14)?,
+            front: item.get(20).copied().filter(|_| flags & TILES_FRONT != 0),
+            tele: item.get(18).copied().filter(|_| flags & TILES_TELE != 0),
+        });
+    }
+    Ok(layers)
+}
+
+/// The tile indices of a layer. Tiles are four bytes: the index, flags, the
+/// number of repeats of the tile and one unused byte.
+pub fn tiles(df: &Datafile, layer: &TileLayer) -> Result<Tiles> {
+    let count = layer
+        .width
+        .checked_mul(layer.height)
+        .filter(|c| *c <= MAX_TILES)
+        .ok_or("A layer has too many tiles.")?;
+    let data = df.data(layer.front.unwrap_or(layer.data))?;
+
+    let mut indices = Vec::with_capacity(count);
+    if layer.version >= TILEMAP_SKIP_VERSION {
+        for tile in data.chunks_exact(4) {
+            let repeat = 1 + tile[2] as usize;
+            indices.resize(indices.len() + repeat, tile[0]);
+            if indices.len() > count {
+                break;
+            }
+        }
+    } else {
+        indices.extend(data.chunks_exact(4).map(|tile| tile[0]));
+    }
+    if indices.len() < count {
+        return Err("A layer has fewer tiles than its size.".into());
+    }
+    indices.truncate(count);
+    Ok(Tiles {
+        width: layer.width,
+        height: layer.height,
+        indices,
+    })
+}
+
+/// The information about a map the editor lets mappers fill in.
+#[derive(Default)]
+pub struct Info {
+    pub author: Option<String>,
+    pub version: Option<String>,
+    pub credits: Option<String>,
+    pub license: Option<String>,
+    /// Server commands run when the map is loaded, DDNet only.
+    pub settings: Vec<String>,
+}
+
+/// The info item of a map, with its strings read.
+pub fn info(df: &Datafile) -> Result<Info> {
+    let item = match df.items(ITEM_INFO).next() {
+        Some(item) => &item.data,
+        None => return Ok(Info::default()),
+    };
+    let string = |index: usize| match item.get(index) {
+        Some(data) if *data >= 0 => df.string(*data).map(Some),
+        _ => Ok(None),
+    };
+    let settings = match item.get(5) {
+        Some(data) if *data >= 0 => df
+            .data(*data)?
+            .split(|b| *b == 0)
+            .filter(|s| !s.is_empty())
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .collect(),
+        _ => Vec::new(),
+    };
+    Ok(Info {
+        author: string(1)?,
+        version: string(2)?,
+        credits: string(3)?,
+        license: string(4)?,
+        settings,
+    })
+}
+
+/// A sound of a map, which is either in the map file or one of the mapres of
+/// the client.
+pub struct Sound {
+    pub external: bool,
+    /// The data block with the name of the sound.
+    pub name: i32,
+}
+
+pub fn sounds(df: &Datafile) -> Result<Vec<Sound>> {
+    df.items(ITEM_SOUND)
+        .map(|item| {
+            Ok(Sound {
+                external: field(&item.data, 1)? != 0,
+                name: field(&item.data, 2)?,
+            })
+        })
+        .collect()
+}
+
+/// A teleporter tile: its kind and the number that links it to the others.
+#[derive(Clone, Copy)]
+pub struct Tele {
+    pub number: u8,
+    pub kind: u8,
+}
+
+/// The teleporters of a DDNet tele layer, row by row. They are two bytes and
+/// never stored with repeats.
+pub fn tele_tiles(df: &Datafile, layer: &TileLayer) -> Result<Vec<Tele>> {
+    let count = layer
+        .width
+        .checked_mul(layer.height)
+        .filter(|c| *c <= MAX_TILES)
+        .ok_or("A layer has too many tiles.")?;
+    let data = df.data(layer.tele.ok_or("The layer has no teleporters.")?)?;
+    if data.len() < count * 2 {
+        return Err("A layer has fewer tiles than its size.".into());
+    }
+    Ok(data
+        .chunks_exact(2)
+        .take(count)
+        .map(|t| Tele {
+            number: t[0],
+            kind: t[1],
+        })
+        .collect())
+}
+
+/// The layer with the game tiles.
+pub fn game_layer(df: &Datafile) -> Result<TileLayer> {
+    tile_layers(df)?
+        .into_iter()
+        .find(|l| l.flags & TILES_GAME != 0)
+        .ok_or_else(|| "The map has no game layer.".into())
+}
+
+/// The tiles of the game layer, followed by the ones of the front layers,
+/// which act like game tiles as well.
+pub fn game_tiles(df: &Datafile) -> Result<Vec<Tiles>> {
+    let mut layers = vec![tiles(df, &game_layer(df)?)?];
+    for layer in tile_layers(df)? {
+        if layer.front.is_some() {
+            layers.push(tiles(df, &layer)?);
+        }
+    }
+    Ok(layers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datafile::testing::build;
+
+    /// The fields of a tile layer up to the DDNet front layer.
+    fn layer(version: i32, width: i32, height: i32, flags: i32) -> Vec<i32> {
+        let mut item = vec![0, LAYER_TILES, 0, version, width, height, flags];
+        item.extend([255, 255, 255, 255, -1, 0, -1, 0]);
+        item.extend([-1; 6]);
+        item
+    }
+
+    /// Tiles stored with repeats, as `(index, repeats)`.
+    fn runs(runs: &[(u8, u8)]) -> Vec<u8> {
+        runs.iter().flat_map(|(i, r)| vec![*i, 0, *r, 0]).collect()
+    }
+
+    #[test]
+    fn reads_the_fixture() {
+        let file = include_bytes!("../tests/fixtures/race.map");
+        let df = Datafile::parse(file).unwrap();
+
+        let game = game_layer(&df).unwrap();
+        assert_eq!(game.name, "Game");
+        assert_eq!((game.width, game.height), (44, 20));
+        let tiles = &game_tiles(&df).unwrap()[0];
+        assert_eq!(tiles.get(0, 0), 1);
+        assert_eq!(tiles.get(1, 1), 0);
+        assert_eq!(tiles.get(20, 5), 1);
+        assert_eq!(tiles.get(2, 18), 33);
+        assert_eq!(tiles.get(37, 18), 34);
+
+        let info = info(&df).unwrap();
+        assert_eq!(info.author.as_deref(), Some("alice & bob"));
+        assert_eq!(info.version.as_deref(), Some("2"));
+        assert_eq!(info.credits, None);
+        assert_eq!(info.settings, ["sv_gravity 0.5"]);
+
+        let images = images(&df).unwrap();
+        assert_eq!(images.len(), 1);
+        assert!(images[0].external);
+        assert_eq!(df.string(images[0].name).unwrap(), "desert_main");
+    }
+
+    #[test]
+    fn reads_tiles_with_and_without_repeats() {
+        let plain = [1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0];
+        let file = build(
+            &[
+                (ITEM_LAYER, layer(3, 2, 2, TILES_GAME)),
+                (ITEM_LAYER, layer(4, 3, 2, 0)),
+            ],
+            &[&plain, &runs(&[(1, 2), (0, 1), (9, 0)])],
+        );
+        let df = Datafile::parse(&file).unwrap();
+        let mut layers = tile_layers(&df).unwrap();
+        layers[1].data = 1;
+
+        let plain = tiles(&df, &layers[0]).unwrap();
+        assert_eq!(plain.indices, [1, 2, 0, 1]);
+        let skipped = tiles(&df, &layers[1]).unwrap();
+        assert_eq!(skipped.indices, [1, 1, 1, 0, 0, 9]);
+    }
+
+    #[test]
+    fn refuses_broken_layers() {
+        let file = build(
+            &[
+                (ITEM_LAYER, layer(4, 4097, 4096, TILES_GAME)),
+                (ITEM_LAYER, layer(4, 2, 2, 0)),
+                (ITEM_LAYER, layer(4, 2, 2, TILES_TELE)),
+            ],
+            &[&runs(&[(1, 255)]), &[1, 0]],
+        );
+        let df = Datafile::parse(&file).unwrap();
+        let mut layers = tile_layers(&df).unwrap();
+        let error = |r: Result<Tiles>| r.err().unwrap().0;
+        assert!(error(tiles(&df, &layers[0])).contains("too many"));
+
+        // The repeats are too few for the layer.
+        layers[1].data = 0;
+        layers[1].width = 200;
+        assert!(error(tiles(&df, &layers[1])).contains("fewer tiles"));
+        layers[1].data = 5;
+        assert!(tiles(&df, &layers[1]).is_err());
+
+        layers[2].tele = Some(1);
+        assert!(tele_tiles(&df, &layers[2]).is_err());
+        layers[2].tele = None;
+        assert!(tele_tiles(&df, &layers[2]).is_err());
+
+        for (width, height) in [(0, 2), (2, -1)] {
+            let file = build(&[(ITEM_LAYER, layer(4, width, height, 0))], &[]);
+            let df = Datafile::parse(&file).unwrap();
+            assert!(tile_layers(&df).is_err());
+        }
+
+        let cut = build(&[(ITEM_LAYER, layer(4, 2, 2, 0)[..10].to_vec())], &[]);
+        let df = Datafile::parse(&cut).unwrap();
+        assert!(tile_layers(&df).err().unwrap().0.contains("cut off"));
+
+        let none = build(&[(ITEM_LAYER, layer(4, 2, 2, 0))], &[]);
+        let df = Datafile::parse(&none).unwrap();
+        assert!(game_layer(&df).is_err());
+    }
+
+    #[test]
+    fn refuses_info_with_missing_strings() {
+        let file = build(&[(ITEM_INFO, vec![1, 3, -1, -1, -1, -1])], &[]);
+        let df = Datafile::parse(&file).unwrap();
+        assert!(info(&df).is_err());
+    }
+}